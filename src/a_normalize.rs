@@ -1,6 +1,6 @@
 use crate::ast::{
     ANormalBaseExpr, ANormalExpr, ANormalFunDef, ANormalLet, ANormalProgram, ANormalTopLevel,
-    BaseExpr, Expr, Expr_, FunDef, Ident, Let, Program, TopLevel, Type,
+    BaseExpr, Expr, Expr_, FunDef, Ident, Let, Program, Size, TopLevel, Type,
 };
 use anyhow::Result;
 use std::collections::HashMap;
@@ -8,6 +8,10 @@ use std::collections::HashMap;
 struct NormalizeState {
     temp_counter: usize,
     type_env: HashMap<Ident, Type>,
+    // Return types of the functions normalized so far
+    fun_types: HashMap<Ident, Option<Type>>,
+    // Field names and types of each struct
+    structs: HashMap<Ident, Vec<(Ident, Type)>>,
 }
 
 impl NormalizeState {
@@ -15,6 +19,8 @@ impl NormalizeState {
         Self {
             temp_counter: 0,
             type_env: HashMap::new(),
+            fun_types: HashMap::new(),
+            structs: HashMap::new(),
         }
     }
 
@@ -28,6 +34,18 @@ impl NormalizeState {
         self.type_env.insert(name, ty);
     }
 
+    fn get_field_type(&self, struct_name: &str, field: &str) -> Result<Type> {
+        let fields = self
+            .structs
+            .get(struct_name)
+            .ok_or_else(|| anyhow::anyhow!("Struct '{}' is not declared", struct_name))?;
+        fields
+            .iter()
+            .find(|(name, _)| name == field)
+            .map(|(_, ty)| ty.clone())
+            .ok_or_else(|| anyhow::anyhow!("Struct '{}' has no field '{}'", struct_name, field))
+    }
+
     fn get_type(&self, name: &str) -> Result<Type> {
         self.type_env
             .get(name)
@@ -36,6 +54,14 @@ impl NormalizeState {
     }
 }
 
+// Sizes must be literals once constants are folded and functions are specialized
+fn resolve_size(size: Size) -> Result<usize> {
+    match size {
+        Size::Lit(n) => Ok(n),
+        Size::Var(name) => Err(anyhow::anyhow!("Size '{}' is not a known constant", name)),
+    }
+}
+
 fn infer_anormal_type(expr: &ANormalBaseExpr, state: &NormalizeState) -> Result<Type> {
     match expr {
         ANormalBaseExpr::Int(_) => Ok(Type::I(32)),
@@ -65,8 +91,36 @@ fn infer_anormal_type(expr: &ANormalBaseExpr, state: &NormalizeState) -> Result<
                 )),
             }
         }
+        ANormalBaseExpr::BinOp(op, left, right) => {
+            let left_ty = state.get_type(left)?;
+            let right_ty = state.get_type(right)?;
+            match (&left_ty, &right_ty) {
+                (Type::I(w1), Type::I(w2)) if w1 == w2 => {
+                    if op.is_comparison() {
+                        Ok(Type::bool())
+                    } else {
+                        Ok(Type::I(*w1))
+                    }
+                }
+                _ => Err(anyhow::anyhow!(
+                    "Cannot apply {:?} to types {:?} and {:?}",
+                    op,
+                    left_ty,
+                    right_ty
+                )),
+            }
+        }
         ANormalBaseExpr::NewArray(ty, size) => Ok(Type::Array(ty.clone(), *size)),
-        ANormalBaseExpr::Call(_, _) => Ok(Type::I(32)),
+        ANormalBaseExpr::Range(size) => Ok(Type::array(Type::i32(), *size)),
+        ANormalBaseExpr::Fill(ty, size, _) => Ok(Type::Array(ty.clone(), *size)),
+        ANormalBaseExpr::Call(func_name, _) => match state.fun_types.get(func_name) {
+            Some(Some(ty)) => Ok(ty.clone()),
+            Some(None) => Err(anyhow::anyhow!(
+                "Call: Function {} has no return value",
+                func_name
+            )),
+            None => Ok(Type::I(32)),
+        },
         ANormalBaseExpr::ArraySet(array_name, _, _) => {
             let array_ty = state.get_type(array_name)?;
             match array_ty {
@@ -77,13 +131,47 @@ fn infer_anormal_type(expr: &ANormalBaseExpr, state: &NormalizeState) -> Result<
                 )),
             }
         }
-        ANormalBaseExpr::Map(arrays, _, _) => {
-            if let Some(array) = arrays.first() {
-                state.get_type(array)
-            } else {
-                Err(anyhow::anyhow!("Map: Empty array list"))
+        ANormalBaseExpr::Map(arrays, _, _, body) => {
+            let Some(array) = arrays.first() else {
+                return Err(anyhow::anyhow!("Map: Empty array list"));
+            };
+            let array_ty = state.get_type(array)?;
+            // A lambda returning a tuple produces one array per component
+            match &body.1 {
+                ANormalBaseExpr::Tuple(items) => Ok(Type::Tuple(vec![array_ty; items.len()])),
+                _ => Ok(array_ty),
+            }
+        }
+        ANormalBaseExpr::ArrayGet(array_name, _) => {
+            let array_ty = state.get_type(array_name)?;
+            match array_ty {
+                Type::Array(element_ty, _) => Ok((*element_ty).clone()),
+                _ => Err(anyhow::anyhow!(
+                    "ArrayGet: Variable '{}' is not an array type",
+                    array_name
+                )),
             }
         }
+        ANormalBaseExpr::For(..) => Err(anyhow::anyhow!("For: A loop has no value")),
+        ANormalBaseExpr::While(..) => Err(anyhow::anyhow!("While: A loop has no value")),
+        ANormalBaseExpr::Assign(name, _) => state.get_type(name),
+        ANormalBaseExpr::Field(value, field) => match state.get_type(value)? {
+            Type::Struct(struct_name) => state.get_field_type(&struct_name, field),
+            ty => Err(anyhow::anyhow!(
+                "Field: Cannot access field '{}' of type {:?}",
+                field,
+                ty
+            )),
+        },
+        ANormalBaseExpr::Struct(name, _) => Ok(Type::Struct(name.clone())),
+        ANormalBaseExpr::If(_, then_branch, _) => infer_anormal_type(&then_branch.1, state),
+        ANormalBaseExpr::Tuple(items) => Ok(Type::Tuple(
+            items
+                .iter()
+                .map(|item| state.get_type(item))
+                .collect::<Result<_>>()?,
+        )),
+        ANormalBaseExpr::Scan(array, _, _, _, _) => state.get_type(array),
         ANormalBaseExpr::Reduce(array, _, _, _, _) => {
             let array_ty = state.get_type(array)?;
             match array_ty {
@@ -97,6 +185,27 @@ fn infer_anormal_type(expr: &ANormalBaseExpr, state: &NormalizeState) -> Result<
     }
 }
 
+fn bind_to_ident(
+    result: ANormalBaseExpr,
+    bindings: &mut Vec<ANormalLet>,
+    state: &mut NormalizeState,
+) -> Result<Ident> {
+    match result {
+        ANormalBaseExpr::Var(name) => Ok(name),
+        other => {
+            let temp_name = state.fresh_temp();
+            let inferred_ty = infer_anormal_type(&other, state)?;
+            state.insert_type(temp_name.clone(), inferred_ty.clone());
+            bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
+                name: temp_name.clone(),
+                ty: inferred_ty,
+                value: other,
+            }));
+            Ok(temp_name)
+        }
+    }
+}
+
 fn normalize_base_expr(
     expr: BaseExpr,
     state: &mut NormalizeState,
@@ -112,35 +221,8 @@ fn normalize_base_expr(
 
             bindings.append(&mut right_bindings);
 
-            let left_ident = match left_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
-                }
-            };
-
-            let right_ident = match right_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
-                }
-            };
+            let left_ident = bind_to_ident(left_result, &mut bindings, state)?;
+            let right_ident = bind_to_ident(right_result, &mut bindings, state)?;
 
             Ok((bindings, ANormalBaseExpr::Add(left_ident, right_ident)))
         }
@@ -151,40 +233,48 @@ fn normalize_base_expr(
 
             bindings.append(&mut right_bindings);
 
-            let left_ident = match left_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
-                }
-            };
-
-            let right_ident = match right_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
-                }
-            };
+            let left_ident = bind_to_ident(left_result, &mut bindings, state)?;
+            let right_ident = bind_to_ident(right_result, &mut bindings, state)?;
 
             Ok((bindings, ANormalBaseExpr::Mul(left_ident, right_ident)))
         }
 
-        BaseExpr::NewArray(ty, size) => Ok((vec![], ANormalBaseExpr::NewArray(ty, size))),
+        BaseExpr::BinOp(op, left, right) => {
+            let (mut bindings, left_result) = normalize_base_expr(*left, state)?;
+            let (mut right_bindings, right_result) = normalize_base_expr(*right, state)?;
+
+            bindings.append(&mut right_bindings);
+
+            let left_ident = bind_to_ident(left_result, &mut bindings, state)?;
+            let right_ident = bind_to_ident(right_result, &mut bindings, state)?;
+
+            Ok((
+                bindings,
+                ANormalBaseExpr::BinOp(op, left_ident, right_ident),
+            ))
+        }
+
+        BaseExpr::NewArray(ty, size) => {
+            Ok((vec![], ANormalBaseExpr::NewArray(ty, resolve_size(size)?)))
+        }
+        BaseExpr::Range(size) => Ok((vec![], ANormalBaseExpr::Range(resolve_size(size)?))),
+
+        BaseExpr::Fill(ty, size, value) => {
+            let (mut bindings, value_result) = normalize_base_expr(*value, state)?;
+            let value_ident = bind_to_ident(value_result, &mut bindings, state)?;
+            let value_ty = state.get_type(&value_ident)?;
+            if value_ty != *ty {
+                return Err(anyhow::anyhow!(
+                    "Fill: Expected a value of type {:?}, got {:?}",
+                    ty,
+                    value_ty
+                ));
+            }
+            Ok((
+                bindings,
+                ANormalBaseExpr::Fill(ty, resolve_size(size)?, value_ident),
+            ))
+        }
 
         BaseExpr::Call(func_name, args) => {
             let mut bindings = vec![];
@@ -194,21 +284,7 @@ fn normalize_base_expr(
                 let (mut arg_bindings, arg_result) = normalize_base_expr(arg, state)?;
                 bindings.append(&mut arg_bindings);
 
-                let arg_ident = match arg_result {
-                    ANormalBaseExpr::Var(name) => name,
-                    other => {
-                        let temp_name = state.fresh_temp();
-                        let inferred_ty = infer_anormal_type(&other, state)?;
-                        state.insert_type(temp_name.clone(), inferred_ty.clone());
-                        bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                            name: temp_name.clone(),
-                            ty: inferred_ty,
-                            value: other,
-                        }));
-                        temp_name
-                    }
-                };
-
+                let arg_ident = bind_to_ident(arg_result, &mut bindings, state)?;
                 normalized_args.push(arg_ident);
             }
 
@@ -221,43 +297,140 @@ fn normalize_base_expr(
 
             bindings.append(&mut value_bindings);
 
-            let index_ident = match index_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
+            let index_ident = bind_to_ident(index_result, &mut bindings, state)?;
+            let value_ident = bind_to_ident(value_result, &mut bindings, state)?;
+
+            Ok((
+                bindings,
+                ANormalBaseExpr::ArraySet(array_name, Box::new(index_ident), Box::new(value_ident)),
+            ))
+        }
+
+        BaseExpr::ArrayGet(array_name, index) => {
+            let (mut bindings, index_result) = normalize_base_expr(*index, state)?;
+            let index_ident = bind_to_ident(index_result, &mut bindings, state)?;
+            Ok((bindings, ANormalBaseExpr::ArrayGet(array_name, index_ident)))
+        }
+
+        BaseExpr::For(counter, start, end, body) => {
+            let saved_env = state.type_env.clone();
+            state.insert_type(counter.clone(), Type::i32());
+            let normalized_body = normalize_expr_with_state(*body, state)?;
+            state.type_env = saved_env;
+
+            Ok((
+                vec![],
+                ANormalBaseExpr::For(
+                    counter,
+                    resolve_size(start)?,
+                    resolve_size(end)?,
+                    Box::new(normalized_body),
+                ),
+            ))
+        }
+
+        BaseExpr::Assign(name, value) => {
+            let (mut bindings, value_result) = normalize_base_expr(*value, state)?;
+            let value_ident = bind_to_ident(value_result, &mut bindings, state)?;
+            Ok((bindings, ANormalBaseExpr::Assign(name, value_ident)))
+        }
+
+        BaseExpr::Field(value, field) => {
+            let (mut bindings, value_result) = normalize_base_expr(*value, state)?;
+            let value_ident = bind_to_ident(value_result, &mut bindings, state)?;
+            Ok((bindings, ANormalBaseExpr::Field(value_ident, field)))
+        }
+
+        BaseExpr::Struct(name, fields) => {
+            let declared = state
+                .structs
+                .get(&name)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Struct '{}' is not declared", name))?;
+            for (field, _) in &declared {
+                if !fields.iter().any(|(given, _)| given == field) {
+                    return Err(anyhow::anyhow!(
+                        "Struct '{}' is missing field '{}'",
+                        name,
+                        field
+                    ));
                 }
-            };
+            }
 
-            let value_ident = match value_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
+            let mut bindings = vec![];
+            let mut normalized_fields = vec![];
+            for (field, value) in fields {
+                let field_ty = state.get_field_type(&name, &field)?;
+                let (mut value_bindings, value_result) = normalize_base_expr(value, state)?;
+                bindings.append(&mut value_bindings);
+                let value_ident = bind_to_ident(value_result, &mut bindings, state)?;
+                let value_ty = state.get_type(&value_ident)?;
+                if value_ty != field_ty {
+                    return Err(anyhow::anyhow!(
+                        "Field '{}' of struct '{}' has type {:?}, but got {:?}",
+                        field,
+                        name,
+                        field_ty,
+                        value_ty
+                    ));
                 }
-            };
+                normalized_fields.push((field, value_ident));
+            }
+
+            Ok((bindings, ANormalBaseExpr::Struct(name, normalized_fields)))
+        }
+
+        BaseExpr::Block(body) => {
+            let Expr_(lets, base) = *body;
+            let mut bindings = vec![];
+            for let_binding in lets {
+                bindings.append(&mut normalize_let(let_binding, state)?);
+            }
+            let (mut base_bindings, result) = normalize_base_expr(base, state)?;
+            bindings.append(&mut base_bindings);
+            Ok((bindings, result))
+        }
 
+        BaseExpr::While(cond, body) => {
+            // The condition keeps its bindings so that they are evaluated on every iteration
+            let normalized_cond = normalize_expr_with_state(Expr_(vec![], *cond), state)?;
+            let normalized_body = normalize_expr_with_state(*body, state)?;
+            Ok((
+                vec![],
+                ANormalBaseExpr::While(Box::new(normalized_cond), Box::new(normalized_body)),
+            ))
+        }
+
+        BaseExpr::If(cond, then_branch, else_branch) => {
+            let (mut bindings, cond_result) = normalize_base_expr(*cond, state)?;
+            let cond_ident = bind_to_ident(cond_result, &mut bindings, state)?;
+            let normalized_then = normalize_expr_with_state(*then_branch, state)?;
+            let normalized_else = normalize_expr_with_state(*else_branch, state)?;
             Ok((
                 bindings,
-                ANormalBaseExpr::ArraySet(array_name, Box::new(index_ident), Box::new(value_ident)),
+                ANormalBaseExpr::If(
+                    cond_ident,
+                    Box::new(normalized_then),
+                    Box::new(normalized_else),
+                ),
             ))
         }
 
-        BaseExpr::Map(arrays, params, body) => {
+        BaseExpr::Tuple(items) => {
+            let mut bindings = vec![];
+            let mut normalized_items = vec![];
+
+            for item in items {
+                let (mut item_bindings, item_result) = normalize_base_expr(item, state)?;
+                bindings.append(&mut item_bindings);
+                let item_ident = bind_to_ident(item_result, &mut bindings, state)?;
+                normalized_items.push(item_ident);
+            }
+
+            Ok((bindings, ANormalBaseExpr::Tuple(normalized_items)))
+        }
+
+        BaseExpr::Map(arrays, index, params, body) => {
             let mut bindings = vec![];
             let mut normalized_arrays = vec![];
 
@@ -265,21 +438,7 @@ fn normalize_base_expr(
                 let (mut array_bindings, array_result) = normalize_base_expr(array, state)?;
                 bindings.append(&mut array_bindings);
 
-                let array_ident = match array_result {
-                    ANormalBaseExpr::Var(name) => name,
-                    other => {
-                        let temp_name = state.fresh_temp();
-                        let inferred_ty = infer_anormal_type(&other, state)?;
-                        state.insert_type(temp_name.clone(), inferred_ty.clone());
-                        bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                            name: temp_name.clone(),
-                            ty: inferred_ty,
-                            value: other,
-                        }));
-                        temp_name
-                    }
-                };
-
+                let array_ident = bind_to_ident(array_result, &mut bindings, state)?;
                 normalized_arrays.push(array_ident);
             }
 
@@ -295,7 +454,7 @@ fn normalize_base_expr(
                         return Err(anyhow::anyhow!(
                             "Map: Expected array type, got {:?}",
                             array_type
-                        ))
+                        ));
                     }
                 };
 
@@ -305,6 +464,11 @@ fn normalize_base_expr(
                 }
             }
 
+            // The index parameter is the loop counter
+            if let Some(index) = &index {
+                state.insert_type(index.clone(), Type::i32());
+            }
+
             let normalized_body = normalize_expr_with_state(*body, state)?;
 
             // Restore type environment
@@ -312,69 +476,20 @@ fn normalize_base_expr(
 
             Ok((
                 bindings,
-                ANormalBaseExpr::Map(normalized_arrays, params, Box::new(normalized_body)),
+                ANormalBaseExpr::Map(normalized_arrays, index, params, Box::new(normalized_body)),
             ))
         }
 
         BaseExpr::Reduce(array, init_value, param1, param2, body) => {
-            let (mut bindings, array_result) = normalize_base_expr(*array, state)?;
-            let (mut init_bindings, init_result) = normalize_base_expr(*init_value, state)?;
-            bindings.append(&mut init_bindings);
-
-            let array_ident = match array_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
-                }
-            };
-
-            let init_ident = match init_result {
-                ANormalBaseExpr::Var(name) => name,
-                other => {
-                    let temp_name = state.fresh_temp();
-                    let inferred_ty = infer_anormal_type(&other, state)?;
-                    state.insert_type(temp_name.clone(), inferred_ty.clone());
-                    bindings.push(ANormalLet::BindLet(crate::ast::BindLet_ {
-                        name: temp_name.clone(),
-                        ty: inferred_ty,
-                        value: other,
-                    }));
-                    temp_name
-                }
-            };
-
-            // Add lambda parameters to type environment
-            let saved_env = state.type_env.clone();
-
-            // Infer the element type from the array
-            let array_type = state.get_type(&array_ident)?;
-            let element_type = match array_type {
-                Type::Array(element_ty, _) => (*element_ty).clone(),
-                _ => {
-                    return Err(anyhow::anyhow!(
-                        "Reduce: Expected array type, got {:?}",
-                        array_type
-                    ))
-                }
-            };
-
-            // Add lambda parameters with element type
-            state.insert_type(param1.clone(), element_type.clone());
-            state.insert_type(param2.clone(), element_type);
-
-            let normalized_body = normalize_expr_with_state(*body, state)?;
-
-            // Restore type environment
-            state.type_env = saved_env;
-
+            let (bindings, array_ident, init_ident, normalized_body) = normalize_fold(
+                "Reduce",
+                *array,
+                *init_value,
+                &param1,
+                &param2,
+                *body,
+                state,
+            )?;
             Ok((
                 bindings,
                 ANormalBaseExpr::Reduce(
@@ -386,9 +501,69 @@ fn normalize_base_expr(
                 ),
             ))
         }
+
+        BaseExpr::Scan(array, init_value, param1, param2, body) => {
+            let (bindings, array_ident, init_ident, normalized_body) =
+                normalize_fold("Scan", *array, *init_value, &param1, &param2, *body, state)?;
+            Ok((
+                bindings,
+                ANormalBaseExpr::Scan(
+                    array_ident,
+                    init_ident,
+                    param1,
+                    param2,
+                    Box::new(normalized_body),
+                ),
+            ))
+        }
     }
 }
 
+// Shared by reduce and scan: normalizes the array and the initial value and the lambda body
+fn normalize_fold(
+    kind: &str,
+    array: BaseExpr,
+    init_value: BaseExpr,
+    param1: &Ident,
+    param2: &Ident,
+    body: Expr,
+    state: &mut NormalizeState,
+) -> Result<(Vec<ANormalLet>, Ident, Ident, ANormalExpr)> {
+    let (mut bindings, array_result) = normalize_base_expr(array, state)?;
+    let (mut init_bindings, init_result) = normalize_base_expr(init_value, state)?;
+    bindings.append(&mut init_bindings);
+
+    let array_ident = bind_to_ident(array_result, &mut bindings, state)?;
+    let init_ident = bind_to_ident(init_result, &mut bindings, state)?;
+
+    // Add lambda parameters to type environment
+    let saved_env = state.type_env.clone();
+
+    // Infer the element type from the array
+    let array_type = state.get_type(&array_ident)?;
+    let element_type = match array_type {
+        Type::Array(element_ty, _) => (*element_ty).clone(),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "{}: Expected array type, got {:?}",
+                kind,
+                array_type
+            ));
+        }
+    };
+
+    // Add lambda parameters with element type
+    state.insert_type(param1.clone(), element_type.clone());
+    state.insert_type(param2.clone(), element_type);
+
+    let normalized_body = normalize_expr_with_state(body, state)?;
+
+    // Restore type environment
+    state.type_env = saved_env;
+
+    Ok((bindings, array_ident, init_ident, normalized_body))
+}
+
 fn normalize_let(let_binding: Let, state: &mut NormalizeState) -> Result<Vec<ANormalLet>> {
     match let_binding {
         Let::BindLet(bind_let) => {
@@ -408,6 +583,46 @@ fn normalize_let(let_binding: Let, state: &mut NormalizeState) -> Result<Vec<ANo
             }));
             Ok(bindings)
         }
+        Let::VarLet(var_let) => {
+            let (mut bindings, result) = normalize_base_expr(var_let.value, state)?;
+            state.insert_type(var_let.name.clone(), var_let.ty.clone());
+            bindings.push(ANormalLet::VarLet(crate::ast::BindLet_ {
+                name: var_let.name,
+                ty: var_let.ty,
+                value: result,
+            }));
+            Ok(bindings)
+        }
+        Let::TupleLet(tuple_let) => {
+            let (mut bindings, result) = normalize_base_expr(tuple_let.value, state)?;
+            let ty = match tuple_let.ty {
+                Some(ty) => ty,
+                None => infer_anormal_type(&result, state)?,
+            };
+            let Type::Tuple(tys) = &ty else {
+                return Err(anyhow::anyhow!(
+                    "Expected a tuple type for {:?}, got {:?}",
+                    tuple_let.names,
+                    ty
+                ));
+            };
+            if tys.len() != tuple_let.names.len() {
+                return Err(anyhow::anyhow!(
+                    "Cannot bind {} names to a tuple of type {:?}",
+                    tuple_let.names.len(),
+                    ty
+                ));
+            }
+            for (name, ty) in tuple_let.names.iter().zip(tys) {
+                state.insert_type(name.clone(), ty.clone());
+            }
+            bindings.push(ANormalLet::TupleLet(crate::ast::TupleLet_ {
+                names: tuple_let.names,
+                ty: Some(ty),
+                value: result,
+            }));
+            Ok(bindings)
+        }
     }
 }
 
@@ -438,7 +653,20 @@ pub fn normalize_base_expr_public(expr: BaseExpr) -> Result<ANormalExpr> {
     Ok(Expr_(bindings, result))
 }
 
+fn check_monomorphic(fundef: &FunDef) -> Result<()> {
+    if fundef.size_params.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Function {} still has size parameters {:?}; it must be monomorphized first",
+            fundef.name,
+            fundef.size_params
+        ))
+    }
+}
+
 pub fn normalize_fundef(fundef: FunDef) -> Result<ANormalFunDef> {
+    check_monomorphic(&fundef)?;
     let mut state = NormalizeState::new();
 
     for (param_name, param_type) in &fundef.params {
@@ -449,6 +677,8 @@ pub fn normalize_fundef(fundef: FunDef) -> Result<ANormalFunDef> {
 
     Ok(crate::ast::FunDef_ {
         name: fundef.name,
+        inline: fundef.inline,
+        size_params: vec![],
         params: fundef.params,
         return_type: fundef.return_type,
         body: normalized_body,
@@ -459,12 +689,32 @@ fn normalize_fundef_with_externals(
     fundef: FunDef,
     external_context: &[ANormalTopLevel],
 ) -> Result<ANormalFunDef> {
+    check_monomorphic(&fundef)?;
     let mut state = NormalizeState::new();
 
-    // Add external declarations to type environment
+    // Add external declarations to type environment, and the return types of the
+    // functions defined before this one
     for top_level in external_context {
-        if let ANormalTopLevel::ExternalDecl(external_decl) = top_level {
-            state.insert_type(external_decl.name.clone(), external_decl.ty.clone());
+        match top_level {
+            ANormalTopLevel::ExternalDecl(external_decl) => {
+                state.insert_type(external_decl.name.clone(), external_decl.ty.clone());
+            }
+            ANormalTopLevel::StructDecl(struct_decl) => {
+                state
+                    .structs
+                    .insert(struct_decl.name.clone(), struct_decl.fields.clone());
+            }
+            ANormalTopLevel::ExternComponent(decl) => {
+                state
+                    .fun_types
+                    .insert(decl.name.clone(), decl.return_type());
+            }
+            ANormalTopLevel::Import(_) | ANormalTopLevel::ConstDecl(_) => {}
+            ANormalTopLevel::FunDef(fundef) => {
+                state
+                    .fun_types
+                    .insert(fundef.name.clone(), fundef.return_type.clone());
+            }
         }
     }
 
@@ -477,6 +727,8 @@ fn normalize_fundef_with_externals(
 
     Ok(crate::ast::FunDef_ {
         name: fundef.name,
+        inline: fundef.inline,
+        size_params: vec![],
         params: fundef.params,
         return_type: fundef.return_type,
         body: normalized_body,
@@ -486,13 +738,25 @@ fn normalize_fundef_with_externals(
 pub fn normalize_top_level(top_level: TopLevel) -> Result<ANormalTopLevel> {
     match top_level {
         TopLevel::ExternalDecl(external_decl) => Ok(ANormalTopLevel::ExternalDecl(external_decl)),
+        TopLevel::ExternComponent(decl) => Ok(ANormalTopLevel::ExternComponent(decl)),
+        TopLevel::StructDecl(struct_decl) => Ok(ANormalTopLevel::StructDecl(struct_decl)),
+        TopLevel::Import(path) => Err(anyhow::anyhow!(
+            "Import of {} must be resolved before normalization",
+            path
+        )),
+        TopLevel::ConstDecl(const_decl) => Err(anyhow::anyhow!(
+            "Constant {} must be evaluated before normalization",
+            const_decl.name
+        )),
         TopLevel::FunDef(fundef) => Ok(ANormalTopLevel::FunDef(normalize_fundef(fundef)?)),
     }
 }
 
 pub fn normalize_program(program: Program) -> Result<ANormalProgram> {
-    // First, collect all external declarations
+    // First, collect all struct and external declarations
+    let mut struct_decls = Vec::new();
     let mut external_decls = Vec::new();
+    let mut extern_components = Vec::new();
     let mut function_defs = Vec::new();
 
     for top_level in program {
@@ -500,6 +764,24 @@ pub fn normalize_program(program: Program) -> Result<ANormalProgram> {
             TopLevel::ExternalDecl(external_decl) => {
                 external_decls.push(external_decl.clone());
             }
+            TopLevel::ExternComponent(decl) => {
+                extern_components.push(decl);
+            }
+            TopLevel::StructDecl(struct_decl) => {
+                struct_decls.push(struct_decl);
+            }
+            TopLevel::Import(path) => {
+                return Err(anyhow::anyhow!(
+                    "Import of {} must be resolved before normalization",
+                    path
+                ));
+            }
+            TopLevel::ConstDecl(const_decl) => {
+                return Err(anyhow::anyhow!(
+                    "Constant {} must be evaluated before normalization",
+                    const_decl.name
+                ));
+            }
             TopLevel::FunDef(fundef) => {
                 function_defs.push(fundef);
             }
@@ -509,10 +791,16 @@ pub fn normalize_program(program: Program) -> Result<ANormalProgram> {
     // Normalize all items with access to external declarations
     let mut result = Vec::new();
 
-    // Add external declarations first
+    // Add struct and external declarations first
+    for struct_decl in struct_decls {
+        result.push(ANormalTopLevel::StructDecl(struct_decl));
+    }
     for external_decl in external_decls {
         result.push(ANormalTopLevel::ExternalDecl(external_decl));
     }
+    for decl in extern_components {
+        result.push(ANormalTopLevel::ExternComponent(decl));
+    }
 
     // Normalize functions with external declarations in scope
     for fundef in function_defs {
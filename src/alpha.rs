@@ -5,11 +5,34 @@ use std::collections::HashMap;
 pub struct AlphaContext {
     env: HashMap<String, String>,
     counter: usize,
+    // Put before the counter in fresh names, so that passes renaming again after
+    // alpha conversion cannot clash with its names
+    tag: &'static str,
+    // The parameters of the top function are ports and memories seen from outside, so
+    // they keep their names
+    top: String,
 }
 
 impl AlphaContext {
+    pub fn tagged(tag: &'static str) -> Self {
+        AlphaContext {
+            tag,
+            ..Default::default()
+        }
+    }
+
+    // Renames the parameters and the local variables of a function body apart from
+    // every other copy, e.g. before inlining it
+    pub fn rename_body(&mut self, params: &[(Ident, Type)], body: &Expr) -> (Vec<Ident>, Expr) {
+        let saved_env = self.env.clone();
+        let new_params = params.iter().map(|(name, _)| self.bind(name)).collect();
+        let new_body = self.alpha_convert_expr(body);
+        self.env = saved_env;
+        (new_params, new_body)
+    }
+
     fn fresh_name(&mut self, original: &str) -> String {
-        let fresh = format!("{}_{}", original, self.counter);
+        let fresh = format!("{}_{}{}", original, self.tag, self.counter);
         self.counter += 1;
         fresh
     }
@@ -32,6 +55,10 @@ impl AlphaContext {
             TopLevel::ExternalDecl(decl) => {
                 TopLevel::ExternalDecl(self.alpha_convert_external_decl(decl))
             }
+            TopLevel::Import(path) => TopLevel::Import(path.clone()),
+            TopLevel::ExternComponent(decl) => TopLevel::ExternComponent(decl.clone()),
+            TopLevel::StructDecl(decl) => TopLevel::StructDecl(decl.clone()),
+            TopLevel::ConstDecl(decl) => TopLevel::ConstDecl(decl.clone()),
             TopLevel::FunDef(fundef) => TopLevel::FunDef(self.alpha_convert_fundef(fundef)),
         }
     }
@@ -44,11 +71,8 @@ impl AlphaContext {
     }
 
     fn alpha_convert_fundef(&mut self, fundef: &FunDef) -> FunDef {
-        let new_name = if fundef.name == "main" {
-            fundef.name.clone()
-        } else {
-            self.bind(&fundef.name)
-        };
+        // Function names are bound up front, so that calls may come before definitions
+        let new_name = self.lookup(&fundef.name);
 
         let saved_env = self.env.clone();
 
@@ -56,7 +80,12 @@ impl AlphaContext {
             .params
             .iter()
             .map(|(name, ty)| {
-                let new_name = self.bind(name);
+                let new_name = if fundef.name == self.top {
+                    self.env.insert(name.clone(), name.clone());
+                    name.clone()
+                } else {
+                    self.bind(name)
+                };
                 (new_name, ty.clone())
             })
             .collect();
@@ -64,12 +93,11 @@ impl AlphaContext {
         let new_body = self.alpha_convert_expr(&fundef.body);
 
         self.env = saved_env;
-        if fundef.name != "main" {
-            self.env.insert(fundef.name.clone(), new_name.clone());
-        }
 
         FunDef {
             name: new_name,
+            inline: fundef.inline,
+            size_params: fundef.size_params.clone(),
             params: new_params,
             return_type: fundef.return_type.clone(),
             body: new_body,
@@ -108,6 +136,26 @@ impl AlphaContext {
 
                 Let::NoBindLet(NoBindLet { value: new_value })
             }
+            Let::VarLet(var_let) => {
+                let new_value = self.alpha_convert_base_expr(&var_let.value);
+                let new_name = self.bind(&var_let.name);
+
+                Let::VarLet(BindLet {
+                    name: new_name,
+                    ty: var_let.ty.clone(),
+                    value: new_value,
+                })
+            }
+            Let::TupleLet(tuple_let) => {
+                let new_value = self.alpha_convert_base_expr(&tuple_let.value);
+                let new_names = tuple_let.names.iter().map(|name| self.bind(name)).collect();
+
+                Let::TupleLet(TupleLet {
+                    names: new_names,
+                    ty: tuple_let.ty.clone(),
+                    value: new_value,
+                })
+            }
         }
     }
 
@@ -129,20 +177,32 @@ impl AlphaContext {
                 BaseExpr::Mul(Box::new(new_left), Box::new(new_right))
             }
 
-            BaseExpr::NewArray(ty, size) => BaseExpr::NewArray(ty.clone(), *size),
+            BaseExpr::BinOp(op, left, right) => {
+                let new_left = self.alpha_convert_base_expr(left);
+                let new_right = self.alpha_convert_base_expr(right);
+                BaseExpr::BinOp(*op, Box::new(new_left), Box::new(new_right))
+            }
 
-            BaseExpr::Map(arrays, params, body) => {
+            BaseExpr::NewArray(ty, size) => BaseExpr::NewArray(ty.clone(), size.clone()),
+            BaseExpr::Range(size) => BaseExpr::Range(size.clone()),
+            BaseExpr::Fill(ty, size, value) => {
+                let new_value = self.alpha_convert_base_expr(value);
+                BaseExpr::Fill(ty.clone(), size.clone(), Box::new(new_value))
+            }
+
+            BaseExpr::Map(arrays, index, params, body) => {
                 let new_arrays: Vec<BaseExpr> = arrays
                     .iter()
                     .map(|array| self.alpha_convert_base_expr(array))
                     .collect();
 
                 let saved_env = self.env.clone();
+                let new_index = index.as_ref().map(|index| self.bind(index));
                 let new_params: Vec<Ident> = params.iter().map(|param| self.bind(param)).collect();
                 let new_body = self.alpha_convert_expr(body);
                 self.env = saved_env;
 
-                BaseExpr::Map(new_arrays, new_params, Box::new(new_body))
+                BaseExpr::Map(new_arrays, new_index, new_params, Box::new(new_body))
             }
 
             BaseExpr::Reduce(array, init_value, param1, param2, body) => {
@@ -164,6 +224,25 @@ impl AlphaContext {
                 )
             }
 
+            BaseExpr::Scan(array, init_value, param1, param2, body) => {
+                let new_array = self.alpha_convert_base_expr(array);
+                let new_init_value = self.alpha_convert_base_expr(init_value);
+
+                let saved_env = self.env.clone();
+                let new_param1 = self.bind(param1);
+                let new_param2 = self.bind(param2);
+                let new_body = self.alpha_convert_expr(body);
+                self.env = saved_env;
+
+                BaseExpr::Scan(
+                    Box::new(new_array),
+                    Box::new(new_init_value),
+                    new_param1,
+                    new_param2,
+                    Box::new(new_body),
+                )
+            }
+
             BaseExpr::Call(name, args) => {
                 let new_name = self.lookup(name);
                 let new_args: Vec<BaseExpr> = args
@@ -179,18 +258,96 @@ impl AlphaContext {
                 let new_value = self.alpha_convert_base_expr(value);
                 BaseExpr::ArraySet(new_name, Box::new(new_index), Box::new(new_value))
             }
+
+            BaseExpr::ArrayGet(name, index) => {
+                let new_name = self.lookup(name);
+                let new_index = self.alpha_convert_base_expr(index);
+                BaseExpr::ArrayGet(new_name, Box::new(new_index))
+            }
+
+            BaseExpr::For(counter, start, end, body) => {
+                let saved_env = self.env.clone();
+                let new_counter = self.bind(counter);
+                let new_body = self.alpha_convert_expr(body);
+                self.env = saved_env;
+
+                BaseExpr::For(new_counter, start.clone(), end.clone(), Box::new(new_body))
+            }
+
+            BaseExpr::Assign(name, value) => {
+                let new_name = self.lookup(name);
+                let new_value = self.alpha_convert_base_expr(value);
+                BaseExpr::Assign(new_name, Box::new(new_value))
+            }
+
+            BaseExpr::Field(value, field) => {
+                let new_value = self.alpha_convert_base_expr(value);
+                BaseExpr::Field(Box::new(new_value), field.clone())
+            }
+
+            BaseExpr::Struct(name, fields) => BaseExpr::Struct(
+                name.clone(),
+                fields
+                    .iter()
+                    .map(|(field, value)| (field.clone(), self.alpha_convert_base_expr(value)))
+                    .collect(),
+            ),
+
+            BaseExpr::While(cond, body) => {
+                let new_cond = self.alpha_convert_base_expr(cond);
+                let saved_env = self.env.clone();
+                let new_body = self.alpha_convert_expr(body);
+                self.env = saved_env;
+                BaseExpr::While(Box::new(new_cond), Box::new(new_body))
+            }
+
+            BaseExpr::If(cond, then_branch, else_branch) => {
+                let new_cond = self.alpha_convert_base_expr(cond);
+                let saved_env = self.env.clone();
+                let new_then = self.alpha_convert_expr(then_branch);
+                self.env = saved_env.clone();
+                let new_else = self.alpha_convert_expr(else_branch);
+                self.env = saved_env;
+                BaseExpr::If(Box::new(new_cond), Box::new(new_then), Box::new(new_else))
+            }
+
+            BaseExpr::Block(body) => {
+                let saved_env = self.env.clone();
+                let new_body = self.alpha_convert_expr(body);
+                self.env = saved_env;
+                BaseExpr::Block(Box::new(new_body))
+            }
+
+            BaseExpr::Tuple(items) => BaseExpr::Tuple(
+                items
+                    .iter()
+                    .map(|item| self.alpha_convert_base_expr(item))
+                    .collect(),
+            ),
         }
     }
 }
 
-pub fn alpha_convert_program(program: &Program) -> Program {
-    let mut ctx = AlphaContext::default();
+// `main` and the top function keep their names, which are visible outside the program
+pub fn alpha_convert_program(program: &Program, top: &str) -> Program {
+    let mut ctx = AlphaContext {
+        top: top.to_string(),
+        ..Default::default()
+    };
 
     for item in program {
         if let TopLevel::ExternalDecl(decl) = item {
             ctx.env.insert(decl.name.clone(), decl.name.clone());
         }
     }
+    for item in program {
+        if let TopLevel::FunDef(fundef) = item
+            && fundef.name != "main"
+            && fundef.name != top
+        {
+            ctx.bind(&fundef.name);
+        }
+    }
 
     program
         .iter()
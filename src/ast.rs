@@ -1,4 +1,4 @@
-use std::ops::{Add, Mul};
+use std::ops::{Add, Mul, Sub};
 
 pub type Ident = String;
 
@@ -9,7 +9,12 @@ pub type ANormalProgram = Program_<ANormalBaseExpr>;
 
 #[derive(Debug, Clone)]
 pub enum TopLevel_<BaseExpr> {
+    // Resolved by the loader, relative to the importing file
+    Import(String),
     ExternalDecl(ExternalDecl),
+    ExternComponent(ExternComponent),
+    StructDecl(StructDecl),
+    ConstDecl(ConstDecl),
     FunDef(FunDef_<BaseExpr>),
 }
 
@@ -22,9 +27,49 @@ pub struct ExternalDecl {
     pub ty: Type,
 }
 
+// extern component name(in: i32, ...) -> (out: i32, ...) from "file.futil";
+// A hand-written component, called like a function with one argument per input port
+#[derive(Debug, Clone)]
+pub struct ExternComponent {
+    pub name: Ident,
+    pub inputs: Vec<(Ident, Type)>,
+    pub outputs: Vec<(Ident, Type)>,
+    pub path: String,
+}
+
+impl ExternComponent {
+    // The value of a call: nothing, the only output, or a tuple of the outputs
+    pub fn return_type(&self) -> Option<Type> {
+        match self.outputs.as_slice() {
+            [] => None,
+            [(_, ty)] => Some(ty.clone()),
+            outputs => Some(Type::Tuple(
+                outputs.iter().map(|(_, ty)| ty.clone()).collect(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StructDecl {
+    pub name: Ident,
+    pub fields: Vec<(Ident, Type)>,
+}
+
+// Folded away by const_eval before alpha conversion
+#[derive(Debug, Clone)]
+pub struct ConstDecl {
+    pub name: Ident,
+    pub ty: Type,
+    pub value: BaseExpr,
+}
+
 #[derive(Debug, Clone)]
 pub struct FunDef_<BaseExpr> {
     pub name: Ident,
+    pub inline: Inline,
+    // Generic array sizes, `fn f<N>(a: i32[N])`; empty once monomorphized
+    pub size_params: Vec<Ident>,
     pub params: Vec<(Ident, Type)>,
     pub return_type: Option<Type>,
     pub body: Expr_<BaseExpr>,
@@ -33,10 +78,30 @@ pub struct FunDef_<BaseExpr> {
 pub type FunDef = FunDef_<BaseExpr>;
 pub type ANormalFunDef = FunDef_<ANormalBaseExpr>;
 
+// `inline fn` / `noinline fn`; small functions are inlined by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Inline {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     I(usize),
     Array(Box<Type>, usize),
+    Tuple(Vec<Type>),
+    Struct(Ident),
+    // An array whose size is a constant not yet evaluated
+    SymbolicArray(Box<Type>, Ident),
+}
+
+// An array size or loop bound as written in the source
+#[derive(Debug, Clone, PartialEq)]
+pub enum Size {
+    Lit(usize),
+    Var(Ident),
 }
 
 impl Type {
@@ -53,6 +118,31 @@ impl Type {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Sub,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Neq,
+    And,
+    Or,
+    Xor,
+    // Only introduced by strength reduction, after A-normalization
+    Shl,
+}
+
+impl BinOp {
+    pub fn is_comparison(&self) -> bool {
+        !matches!(
+            self,
+            BinOp::Sub | BinOp::And | BinOp::Or | BinOp::Xor | BinOp::Shl
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum BaseExpr {
     Int(i32),
@@ -60,11 +150,29 @@ pub enum BaseExpr {
     Var(Ident),
     Add(Box<BaseExpr>, Box<BaseExpr>),
     Mul(Box<BaseExpr>, Box<BaseExpr>),
-    NewArray(Box<Type>, usize),
-    Map(Vec<BaseExpr>, Vec<Ident>, Box<Expr>),
+    BinOp(BinOp, Box<BaseExpr>, Box<BaseExpr>),
+    NewArray(Box<Type>, Size),
+    Range(Size),
+    Fill(Box<Type>, Size, Box<BaseExpr>),
+    // Map(arrays, index param, element params, body)
+    Map(Vec<BaseExpr>, Option<Ident>, Vec<Ident>, Box<Expr>),
     Reduce(Box<BaseExpr>, Box<BaseExpr>, Ident, Ident, Box<Expr>),
+    Scan(Box<BaseExpr>, Box<BaseExpr>, Ident, Ident, Box<Expr>),
     Call(Ident, Vec<BaseExpr>),
     ArraySet(Ident, Box<BaseExpr>, Box<BaseExpr>),
+    ArrayGet(Ident, Box<BaseExpr>),
+    Tuple(Vec<BaseExpr>),
+    // for counter in start..end do body
+    For(Ident, Size, Size, Box<Expr>),
+    // name := value, where name is declared with `var`
+    Assign(Ident, Box<BaseExpr>),
+    Field(Box<BaseExpr>, Ident),
+    // Name { field: value, ... }
+    Struct(Ident, Vec<(Ident, BaseExpr)>),
+    While(Box<BaseExpr>, Box<Expr>),
+    If(Box<BaseExpr>, Box<Expr>, Box<Expr>),
+    // Lets evaluated in place, produced by the inliner
+    Block(Box<Expr>),
 }
 
 #[derive(Debug, Clone)]
@@ -74,11 +182,24 @@ pub enum ANormalBaseExpr {
     Var(Ident),
     Add(Ident, Ident),
     Mul(Ident, Ident),
+    BinOp(BinOp, Ident, Ident),
     NewArray(Box<Type>, usize),
-    Map(Vec<Ident>, Vec<Ident>, Box<ANormalExpr>),
+    Range(usize),
+    Fill(Box<Type>, usize, Ident),
+    Map(Vec<Ident>, Option<Ident>, Vec<Ident>, Box<ANormalExpr>),
     Reduce(Ident, Ident, Ident, Ident, Box<ANormalExpr>),
+    Scan(Ident, Ident, Ident, Ident, Box<ANormalExpr>),
     Call(Ident, Vec<Ident>),
     ArraySet(Ident, Box<Ident>, Box<Ident>),
+    ArrayGet(Ident, Ident),
+    Tuple(Vec<Ident>),
+    For(Ident, usize, usize, Box<ANormalExpr>),
+    Assign(Ident, Ident),
+    Field(Ident, Ident),
+    Struct(Ident, Vec<(Ident, Ident)>),
+    // The condition is re-evaluated before every iteration
+    While(Box<ANormalExpr>, Box<ANormalExpr>),
+    If(Ident, Box<ANormalExpr>, Box<ANormalExpr>),
 }
 
 #[derive(Debug, Clone)]
@@ -99,10 +220,25 @@ pub struct NoBindLet_<BaseExpr> {
 pub type NoBindLet = NoBindLet_<BaseExpr>;
 pub type ANormalNoBindLet = NoBindLet_<ANormalBaseExpr>;
 
+// let (name1, name2, ...): (ty1, ty2, ...) = value
+#[derive(Debug, Clone)]
+pub struct TupleLet_<BaseExpr> {
+    pub names: Vec<Ident>,
+    // Inferred from the value during normalization when omitted
+    pub ty: Option<Type>,
+    pub value: BaseExpr,
+}
+
+pub type TupleLet = TupleLet_<BaseExpr>;
+pub type ANormalTupleLet = TupleLet_<ANormalBaseExpr>;
+
 #[derive(Debug, Clone)]
 pub enum Let_<BaseExpr> {
     BindLet(BindLet_<BaseExpr>),
     NoBindLet(NoBindLet_<BaseExpr>),
+    TupleLet(TupleLet_<BaseExpr>),
+    // var name: ty = value
+    VarLet(BindLet_<BaseExpr>),
 }
 
 pub type Let = Let_<BaseExpr>;
@@ -128,15 +264,35 @@ impl BaseExpr {
     }
 
     pub fn new_array(ty: Type, size: usize) -> Self {
-        BaseExpr::NewArray(Box::new(ty), size)
+        BaseExpr::NewArray(Box::new(ty), Size::Lit(size))
+    }
+
+    pub fn fill(ty: Type, size: usize, value: BaseExpr) -> Self {
+        BaseExpr::Fill(Box::new(ty), Size::Lit(size), Box::new(value))
     }
 
     pub fn map(arrays: Vec<BaseExpr>, params: Vec<&str>, body: Expr) -> Self {
         let param_strings: Vec<String> = params.iter().map(|p| p.to_string()).collect();
-        BaseExpr::Map(arrays, param_strings, Box::new(body))
+        BaseExpr::Map(arrays, None, param_strings, Box::new(body))
+    }
+
+    pub fn mapi(arrays: Vec<BaseExpr>, index: &str, params: Vec<&str>, body: Expr) -> Self {
+        let param_strings: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+        BaseExpr::Map(
+            arrays,
+            Some(index.to_string()),
+            param_strings,
+            Box::new(body),
+        )
     }
 
-    pub fn reduce(array: BaseExpr, init_value: BaseExpr, param1: &str, param2: &str, body: Expr) -> Self {
+    pub fn reduce(
+        array: BaseExpr,
+        init_value: BaseExpr,
+        param1: &str,
+        param2: &str,
+        body: Expr,
+    ) -> Self {
         BaseExpr::Reduce(
             array.into(),
             init_value.into(),
@@ -146,6 +302,22 @@ impl BaseExpr {
         )
     }
 
+    pub fn scan(
+        array: BaseExpr,
+        init_value: BaseExpr,
+        param1: &str,
+        param2: &str,
+        body: Expr,
+    ) -> Self {
+        BaseExpr::Scan(
+            array.into(),
+            init_value.into(),
+            param1.to_string(),
+            param2.to_string(),
+            body.into(),
+        )
+    }
+
     pub fn call(name: &str, args: Vec<BaseExpr>) -> Self {
         BaseExpr::Call(name.to_string(), args)
     }
@@ -159,6 +331,14 @@ impl Add for BaseExpr {
     }
 }
 
+impl Sub for BaseExpr {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self::Output {
+        BaseExpr::BinOp(BinOp::Sub, Box::new(self), Box::new(other))
+    }
+}
+
 impl Mul for BaseExpr {
     type Output = Self;
 
@@ -0,0 +1,153 @@
+use crate::ast::*;
+use anyhow::Result;
+use std::collections::HashMap;
+
+// Functions may call functions defined anywhere in the program. Orders the function
+// definitions so that every function comes after the functions it calls, which later
+// passes rely on to know the callee's type, and rejects recursion: a component cannot
+// contain an instance of itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+#[derive(Debug, Default)]
+struct CallGraph {
+    // Function -> functions it calls, in the order of the calls
+    callees: HashMap<Ident, Vec<Ident>>,
+    visits: HashMap<Ident, Visit>,
+    // Functions being visited, to report the cycle
+    path: Vec<Ident>,
+    order: Vec<Ident>,
+}
+
+impl CallGraph {
+    fn visit(&mut self, name: &str) -> Result<()> {
+        match self.visits.get(name) {
+            Some(Visit::Done) => return Ok(()),
+            Some(Visit::InProgress) => {
+                let start = self.path.iter().position(|f| f == name).unwrap_or(0);
+                let mut cycle = self.path[start..].to_vec();
+                cycle.push(name.to_string());
+                return Err(anyhow::anyhow!(
+                    "Recursive call {}; functions cannot be recursive in hardware",
+                    cycle.join(" -> ")
+                ));
+            }
+            None => {}
+        }
+        self.visits.insert(name.to_string(), Visit::InProgress);
+        self.path.push(name.to_string());
+        for callee in self.callees[name].clone() {
+            // Unknown functions are reported by normalization
+            if self.callees.contains_key(&callee) {
+                self.visit(&callee)?;
+            }
+        }
+        self.path.pop();
+        self.visits.insert(name.to_string(), Visit::Done);
+        self.order.push(name.to_string());
+        Ok(())
+    }
+}
+
+fn expr_calls(expr: &Expr, calls: &mut Vec<Ident>) {
+    let Expr_(lets, base) = expr;
+    for let_binding in lets {
+        match let_binding {
+            Let::BindLet(bind_let) | Let::VarLet(bind_let) => {
+                base_expr_calls(&bind_let.value, calls)
+            }
+            Let::NoBindLet(no_bind_let) => base_expr_calls(&no_bind_let.value, calls),
+            Let::TupleLet(tuple_let) => base_expr_calls(&tuple_let.value, calls),
+        }
+    }
+    base_expr_calls(base, calls);
+}
+
+fn base_expr_calls(expr: &BaseExpr, calls: &mut Vec<Ident>) {
+    match expr {
+        BaseExpr::Int(_) | BaseExpr::Bool(_) | BaseExpr::Var(_) => {}
+        BaseExpr::NewArray(..) | BaseExpr::Range(_) => {}
+        BaseExpr::Add(left, right)
+        | BaseExpr::Mul(left, right)
+        | BaseExpr::BinOp(_, left, right)
+        | BaseExpr::ArraySet(_, left, right) => {
+            base_expr_calls(left, calls);
+            base_expr_calls(right, calls);
+        }
+        BaseExpr::Fill(_, _, value)
+        | BaseExpr::ArrayGet(_, value)
+        | BaseExpr::Assign(_, value)
+        | BaseExpr::Field(value, _) => base_expr_calls(value, calls),
+        BaseExpr::Map(arrays, _, _, body) => {
+            for array in arrays {
+                base_expr_calls(array, calls);
+            }
+            expr_calls(body, calls);
+        }
+        BaseExpr::Reduce(array, init, _, _, body) | BaseExpr::Scan(array, init, _, _, body) => {
+            base_expr_calls(array, calls);
+            base_expr_calls(init, calls);
+            expr_calls(body, calls);
+        }
+        BaseExpr::Call(name, args) => {
+            for arg in args {
+                base_expr_calls(arg, calls);
+            }
+            calls.push(name.clone());
+        }
+        BaseExpr::Tuple(items) => {
+            for item in items {
+                base_expr_calls(item, calls);
+            }
+        }
+        BaseExpr::Struct(_, fields) => {
+            for (_, value) in fields {
+                base_expr_calls(value, calls);
+            }
+        }
+        BaseExpr::For(_, _, _, body) | BaseExpr::Block(body) => expr_calls(body, calls),
+        BaseExpr::While(cond, body) => {
+            base_expr_calls(cond, calls);
+            expr_calls(body, calls);
+        }
+        BaseExpr::If(cond, then_branch, else_branch) => {
+            base_expr_calls(cond, calls);
+            expr_calls(then_branch, calls);
+            expr_calls(else_branch, calls);
+        }
+    }
+}
+
+// Other items keep their place before the functions
+pub fn order_functions(program: Program) -> Result<Program> {
+    let mut graph = CallGraph::default();
+    let mut fundefs = HashMap::new();
+    let mut names = vec![];
+    let mut items = vec![];
+    for item in program {
+        match item {
+            TopLevel::FunDef(fundef) => {
+                let mut calls = vec![];
+                expr_calls(&fundef.body, &mut calls);
+                graph.callees.insert(fundef.name.clone(), calls);
+                names.push(fundef.name.clone());
+                fundefs.insert(fundef.name.clone(), fundef);
+            }
+            item => items.push(item),
+        }
+    }
+
+    for name in &names {
+        graph.visit(name)?;
+    }
+    items.extend(
+        graph
+            .order
+            .iter()
+            .map(|name| TopLevel::FunDef(fundefs.remove(name).unwrap())),
+    );
+    Ok(items)
+}
@@ -40,6 +40,8 @@ pub struct Component {
     pub cells: Vec<Cell>,
     pub wires: Wires,
     pub control: Vec<Control>,
+    // Marks the entry point when it is not named main
+    pub is_toplevel: bool,
 }
 
 impl Component {
@@ -49,12 +51,21 @@ impl Component {
         }
     }
 
-    fn find_cell(&self, name: &str) -> Option<&Cell> {
+    pub fn find_cell(&self, name: &str) -> Option<&Cell> {
         self.cells.iter().find(|cell| cell.name == name)
     }
 
-    pub fn get_mult_cell(&mut self, width: usize) -> Cell {
-        let name = format!("_mult_{}", width);
+    // The lanes of an unrolled loop run in par, so each lane gets its own shared cells
+    fn shared_cell_name(kind: &str, width: usize, lane: usize) -> String {
+        if lane == 0 {
+            format!("_{}_{}", kind, width)
+        } else {
+            format!("_{}_{}_lane{}", kind, width, lane)
+        }
+    }
+
+    pub fn get_mult_cell(&mut self, width: usize, lane: usize) -> Cell {
+        let name = Component::shared_cell_name("mult", width, lane);
         if let Some(cell) = self.find_cell(&name) {
             cell.clone()
         } else {
@@ -69,8 +80,8 @@ impl Component {
         }
     }
 
-    pub fn get_add_cell(&mut self, width: usize) -> Cell {
-        let name = format!("_add_{}", width);
+    pub fn get_add_cell(&mut self, width: usize, lane: usize) -> Cell {
+        let name = Component::shared_cell_name("add", width, lane);
         if let Some(cell) = self.find_cell(&name) {
             cell.clone()
         } else {
@@ -100,6 +111,7 @@ impl Component {
                 groups: vec![],
             },
             control: vec![],
+            is_toplevel: false,
         }
     }
 }
@@ -108,6 +120,9 @@ impl Display for Component {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         // Format component declaration
         write!(f, "component {}", self.name)?;
+        if self.is_toplevel {
+            write!(f, "<\"toplevel\"=1>")?;
+        }
 
         write!(f, "(")?;
         for (i, (name, ty)) in self.params.iter().enumerate() {
@@ -165,6 +180,8 @@ impl Display for Cell {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         if self.is_external {
             write!(f, "@external(1) {} = {};", self.name, self.circuit)
+        } else if self.is_ref {
+            write!(f, "ref {} = {};", self.name, self.circuit)
         } else {
             write!(f, "{} = {};", self.name, self.circuit)
         }
@@ -190,6 +207,39 @@ pub enum Circuit {
     StdLt {
         width: usize,
     },
+    StdSub {
+        width: usize,
+    },
+    StdGt {
+        width: usize,
+    },
+    StdLe {
+        width: usize,
+    },
+    StdGe {
+        width: usize,
+    },
+    StdEq {
+        width: usize,
+    },
+    StdNeq {
+        width: usize,
+    },
+    StdAnd {
+        width: usize,
+    },
+    StdOr {
+        width: usize,
+    },
+    StdXor {
+        width: usize,
+    },
+    StdLsh {
+        width: usize,
+    },
+    StdWire {
+        width: usize,
+    },
     FunInstance {
         name: String,
     },
@@ -215,6 +265,17 @@ impl Display for Circuit {
             Circuit::StdAdd { width } => write!(f, "std_add({})", width),
             Circuit::StdMultPipe { width } => write!(f, "std_mult_pipe({})", width),
             Circuit::StdLt { width } => write!(f, "std_lt({})", width),
+            Circuit::StdSub { width } => write!(f, "std_sub({})", width),
+            Circuit::StdGt { width } => write!(f, "std_gt({})", width),
+            Circuit::StdLe { width } => write!(f, "std_le({})", width),
+            Circuit::StdGe { width } => write!(f, "std_ge({})", width),
+            Circuit::StdEq { width } => write!(f, "std_eq({})", width),
+            Circuit::StdNeq { width } => write!(f, "std_neq({})", width),
+            Circuit::StdAnd { width } => write!(f, "std_and({})", width),
+            Circuit::StdOr { width } => write!(f, "std_or({})", width),
+            Circuit::StdXor { width } => write!(f, "std_xor({})", width),
+            Circuit::StdLsh { width } => write!(f, "std_lsh({})", width),
+            Circuit::StdWire { width } => write!(f, "std_wire({})", width),
             Circuit::FunInstance { name } => {
                 write!(f, "{}()", name)
             }
@@ -333,6 +394,18 @@ pub enum Control {
         with: Option<String>,
         body: Vec<Control>,
     },
+    // invoke cell[ref_cell = memory, ...](port = src, ...)();
+    Invoke {
+        cell: String,
+        refs: Vec<(String, String)>,
+        inputs: Vec<(String, Src)>,
+    },
+    If {
+        condition: Port,
+        with: Option<String>,
+        then_branch: Vec<Control>,
+        else_branch: Vec<Control>,
+    },
 }
 
 impl Control {
@@ -390,6 +463,47 @@ impl Display for Control {
                 }
                 write!(f, "}}")
             }
+            Control::Invoke { cell, refs, inputs } => {
+                write!(f, "invoke {}", cell)?;
+                if !refs.is_empty() {
+                    let refs: Vec<String> = refs
+                        .iter()
+                        .map(|(name, memory)| format!("{}={}", name, memory))
+                        .collect();
+                    write!(f, "[{}]", refs.join(", "))?;
+                }
+                let inputs: Vec<String> = inputs
+                    .iter()
+                    .map(|(port, src)| format!("{}={}", port, src))
+                    .collect();
+                write!(f, "({})();", inputs.join(", "))
+            }
+            Control::If {
+                condition,
+                with,
+                then_branch,
+                else_branch,
+            } => {
+                if let Some(with_group) = with {
+                    writeln!(f, "if {} with {} {{", condition, with_group)?;
+                } else {
+                    writeln!(f, "if {} {{", condition)?;
+                }
+                for control in then_branch {
+                    let control_str = format!("{}", control);
+                    for line in control_str.lines() {
+                        writeln!(f, "  {}", line)?;
+                    }
+                }
+                writeln!(f, "}} else {{")?;
+                for control in else_branch {
+                    let control_str = format!("{}", control);
+                    for line in control_str.lines() {
+                        writeln!(f, "  {}", line)?;
+                    }
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
@@ -0,0 +1,380 @@
+use crate::ast::*;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+// Replaces names by compile-time values (an Int or a Bool) in types, sizes and expressions.
+// Used for top-level constants, and by monomorphization for size parameters.
+#[derive(Debug, Clone, Default)]
+pub struct Substitution {
+    values: HashMap<Ident, BaseExpr>,
+    // Local bindings that shadow a substituted name
+    shadowed: HashSet<Ident>,
+}
+
+impl Substitution {
+    pub fn insert(&mut self, name: Ident, value: BaseExpr) {
+        self.values.insert(name, value);
+    }
+
+    fn lookup(&self, name: &str) -> Option<&BaseExpr> {
+        if self.shadowed.contains(name) {
+            return None;
+        }
+        self.values.get(name)
+    }
+
+    fn shadow(&mut self, name: &str) {
+        if self.values.contains_key(name) {
+            self.shadowed.insert(name.to_string());
+        }
+    }
+
+    fn size_value(&self, name: &str) -> Result<Option<usize>> {
+        match self.lookup(name) {
+            Some(BaseExpr::Int(n)) if *n >= 0 => Ok(Some(*n as usize)),
+            Some(value) => Err(anyhow::anyhow!(
+                "{} = {:?} cannot be used as a size",
+                name,
+                value
+            )),
+            None => Ok(None),
+        }
+    }
+
+    // Names without a value are left as they are
+    pub fn size(&self, size: &Size) -> Result<Size> {
+        match size {
+            Size::Lit(n) => Ok(Size::Lit(*n)),
+            Size::Var(name) => Ok(match self.size_value(name)? {
+                Some(n) => Size::Lit(n),
+                None => Size::Var(name.clone()),
+            }),
+        }
+    }
+
+    pub fn ty(&self, ty: &Type) -> Result<Type> {
+        match ty {
+            Type::I(_) | Type::Struct(_) => Ok(ty.clone()),
+            Type::Array(inner, size) => Ok(Type::Array(Box::new(self.ty(inner)?), *size)),
+            Type::Tuple(tys) => Ok(Type::Tuple(
+                tys.iter().map(|ty| self.ty(ty)).collect::<Result<_>>()?,
+            )),
+            Type::SymbolicArray(inner, name) => {
+                let inner = Box::new(self.ty(inner)?);
+                Ok(match self.size_value(name)? {
+                    Some(n) => Type::Array(inner, n),
+                    None => Type::SymbolicArray(inner, name.clone()),
+                })
+            }
+        }
+    }
+
+    pub fn fundef(&mut self, fundef: &FunDef) -> Result<FunDef> {
+        let saved = self.shadowed.clone();
+        for size_param in &fundef.size_params {
+            self.shadow(size_param);
+        }
+        let params = fundef
+            .params
+            .iter()
+            .map(|(name, ty)| Ok((name.clone(), self.ty(ty)?)))
+            .collect::<Result<Vec<_>>>()?;
+        for (name, _) in &params {
+            self.shadow(name);
+        }
+        let return_type = fundef
+            .return_type
+            .as_ref()
+            .map(|ty| self.ty(ty))
+            .transpose()?;
+        let body = self.expr(&fundef.body)?;
+        self.shadowed = saved;
+
+        Ok(FunDef {
+            name: fundef.name.clone(),
+            inline: fundef.inline,
+            size_params: fundef.size_params.clone(),
+            params,
+            return_type,
+            body,
+        })
+    }
+
+    pub fn expr(&mut self, expr: &Expr) -> Result<Expr> {
+        let Expr_(lets, base) = expr;
+        let saved = self.shadowed.clone();
+
+        let mut new_lets = vec![];
+        for let_binding in lets {
+            new_lets.push(self.let_binding(let_binding)?);
+        }
+        let new_base = self.base_expr(base)?;
+
+        self.shadowed = saved;
+        Ok(Expr_(new_lets, new_base))
+    }
+
+    fn let_binding(&mut self, let_binding: &Let) -> Result<Let> {
+        match let_binding {
+            Let::BindLet(bind_let) => {
+                let value = self.base_expr(&bind_let.value)?;
+                let ty = self.ty(&bind_let.ty)?;
+                self.shadow(&bind_let.name);
+                Ok(Let::BindLet(BindLet {
+                    name: bind_let.name.clone(),
+                    ty,
+                    value,
+                }))
+            }
+            Let::VarLet(var_let) => {
+                let value = self.base_expr(&var_let.value)?;
+                let ty = self.ty(&var_let.ty)?;
+                self.shadow(&var_let.name);
+                Ok(Let::VarLet(BindLet {
+                    name: var_let.name.clone(),
+                    ty,
+                    value,
+                }))
+            }
+            Let::NoBindLet(no_bind_let) => Ok(Let::NoBindLet(NoBindLet {
+                value: self.base_expr(&no_bind_let.value)?,
+            })),
+            Let::TupleLet(tuple_let) => {
+                let value = self.base_expr(&tuple_let.value)?;
+                let ty = tuple_let.ty.as_ref().map(|ty| self.ty(ty)).transpose()?;
+                for name in &tuple_let.names {
+                    self.shadow(name);
+                }
+                Ok(Let::TupleLet(TupleLet {
+                    names: tuple_let.names.clone(),
+                    ty,
+                    value,
+                }))
+            }
+        }
+    }
+
+    // Substitutes in a lambda or loop body where `params` are bound
+    fn body(&mut self, params: &[&Ident], body: &Expr) -> Result<Box<Expr>> {
+        let saved = self.shadowed.clone();
+        for param in params {
+            self.shadow(param);
+        }
+        let body = self.expr(body)?;
+        self.shadowed = saved;
+        Ok(Box::new(body))
+    }
+
+    fn boxed(&mut self, expr: &BaseExpr) -> Result<Box<BaseExpr>> {
+        Ok(Box::new(self.base_expr(expr)?))
+    }
+
+    pub fn base_expr(&mut self, expr: &BaseExpr) -> Result<BaseExpr> {
+        Ok(match expr {
+            BaseExpr::Int(n) => BaseExpr::Int(*n),
+            BaseExpr::Bool(b) => BaseExpr::Bool(*b),
+            BaseExpr::Var(name) => match self.lookup(name) {
+                Some(value) => value.clone(),
+                None => BaseExpr::Var(name.clone()),
+            },
+            BaseExpr::Add(left, right) => BaseExpr::Add(self.boxed(left)?, self.boxed(right)?),
+            BaseExpr::Mul(left, right) => BaseExpr::Mul(self.boxed(left)?, self.boxed(right)?),
+            BaseExpr::BinOp(op, left, right) => {
+                BaseExpr::BinOp(*op, self.boxed(left)?, self.boxed(right)?)
+            }
+            BaseExpr::NewArray(ty, size) => {
+                BaseExpr::NewArray(Box::new(self.ty(ty)?), self.size(size)?)
+            }
+            BaseExpr::Range(size) => BaseExpr::Range(self.size(size)?),
+            BaseExpr::Fill(ty, size, value) => {
+                BaseExpr::Fill(Box::new(self.ty(ty)?), self.size(size)?, self.boxed(value)?)
+            }
+            BaseExpr::Map(arrays, index, params, body) => {
+                let arrays = arrays
+                    .iter()
+                    .map(|array| self.base_expr(array))
+                    .collect::<Result<_>>()?;
+                let bound: Vec<&Ident> = index.iter().chain(params).collect();
+                let body = self.body(&bound, body)?;
+                BaseExpr::Map(arrays, index.clone(), params.clone(), body)
+            }
+            BaseExpr::Reduce(array, init, param1, param2, body) => BaseExpr::Reduce(
+                self.boxed(array)?,
+                self.boxed(init)?,
+                param1.clone(),
+                param2.clone(),
+                self.body(&[param1, param2], body)?,
+            ),
+            BaseExpr::Scan(array, init, param1, param2, body) => BaseExpr::Scan(
+                self.boxed(array)?,
+                self.boxed(init)?,
+                param1.clone(),
+                param2.clone(),
+                self.body(&[param1, param2], body)?,
+            ),
+            BaseExpr::Call(name, args) => BaseExpr::Call(
+                name.clone(),
+                args.iter()
+                    .map(|arg| self.base_expr(arg))
+                    .collect::<Result<_>>()?,
+            ),
+            BaseExpr::ArraySet(name, index, value) => {
+                BaseExpr::ArraySet(name.clone(), self.boxed(index)?, self.boxed(value)?)
+            }
+            BaseExpr::ArrayGet(name, index) => BaseExpr::ArrayGet(name.clone(), self.boxed(index)?),
+            BaseExpr::Tuple(items) => BaseExpr::Tuple(
+                items
+                    .iter()
+                    .map(|item| self.base_expr(item))
+                    .collect::<Result<_>>()?,
+            ),
+            BaseExpr::For(counter, start, end, body) => BaseExpr::For(
+                counter.clone(),
+                self.size(start)?,
+                self.size(end)?,
+                self.body(&[counter], body)?,
+            ),
+            BaseExpr::Assign(name, value) => BaseExpr::Assign(name.clone(), self.boxed(value)?),
+            BaseExpr::Field(value, field) => BaseExpr::Field(self.boxed(value)?, field.clone()),
+            BaseExpr::Struct(name, fields) => BaseExpr::Struct(
+                name.clone(),
+                fields
+                    .iter()
+                    .map(|(field, value)| Ok((field.clone(), self.base_expr(value)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            BaseExpr::Block(body) => BaseExpr::Block(self.body(&[], body)?),
+            BaseExpr::While(cond, body) => {
+                BaseExpr::While(self.boxed(cond)?, self.body(&[], body)?)
+            }
+            BaseExpr::If(cond, then_branch, else_branch) => BaseExpr::If(
+                self.boxed(cond)?,
+                self.body(&[], then_branch)?,
+                self.body(&[], else_branch)?,
+            ),
+        })
+    }
+
+    // Evaluates a constant expression to an Int or a Bool
+    fn eval(&self, expr: &BaseExpr) -> Result<BaseExpr> {
+        match expr {
+            BaseExpr::Int(_) | BaseExpr::Bool(_) => Ok(expr.clone()),
+            BaseExpr::Var(name) => self
+                .lookup(name)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} is not a constant", name)),
+            BaseExpr::Add(left, right) => {
+                let (left, right) = self.eval_ints(left, right)?;
+                Ok(BaseExpr::Int(left.wrapping_add(right)))
+            }
+            BaseExpr::Mul(left, right) => {
+                let (left, right) = self.eval_ints(left, right)?;
+                Ok(BaseExpr::Int(left.wrapping_mul(right)))
+            }
+            BaseExpr::BinOp(op, left, right) => {
+                let (left, right) = self.eval_ints(left, right)?;
+                // The comparators are unsigned, as in hardware
+                let (unsigned_left, unsigned_right) = (left as u32, right as u32);
+                Ok(match op {
+                    BinOp::Sub => BaseExpr::Int(left.wrapping_sub(right)),
+                    BinOp::Lt => BaseExpr::Bool(unsigned_left < unsigned_right),
+                    BinOp::Gt => BaseExpr::Bool(unsigned_left > unsigned_right),
+                    BinOp::Le => BaseExpr::Bool(unsigned_left <= unsigned_right),
+                    BinOp::Ge => BaseExpr::Bool(unsigned_left >= unsigned_right),
+                    BinOp::Eq => BaseExpr::Bool(left == right),
+                    BinOp::Neq => BaseExpr::Bool(left != right),
+                    BinOp::And => BaseExpr::Int(left & right),
+                    BinOp::Or => BaseExpr::Int(left | right),
+                    BinOp::Xor => BaseExpr::Int(left ^ right),
+                    BinOp::Shl => {
+                        return Err(anyhow::anyhow!(
+                            "Shifts are not part of the source language"
+                        ));
+                    }
+                })
+            }
+            _ => Err(anyhow::anyhow!(
+                "{:?} cannot be evaluated at compile time",
+                expr
+            )),
+        }
+    }
+
+    fn eval_ints(&self, left: &BaseExpr, right: &BaseExpr) -> Result<(i32, i32)> {
+        match (self.eval(left)?, self.eval(right)?) {
+            (BaseExpr::Int(left), BaseExpr::Int(right)) => Ok((left, right)),
+            (left, right) => Err(anyhow::anyhow!(
+                "Expected integer constants, got {:?} and {:?}",
+                left,
+                right
+            )),
+        }
+    }
+}
+
+// Evaluates the `const` declarations, in order, and folds them into the rest of the program
+pub fn eval_consts(program: Program) -> Result<Program> {
+    let mut subst = Substitution::default();
+    for item in &program {
+        let TopLevel::ConstDecl(decl) = item else {
+            continue;
+        };
+        if subst.values.contains_key(&decl.name) {
+            return Err(anyhow::anyhow!("Constant {} is defined twice", decl.name));
+        }
+        let value = subst.eval(&decl.value)?;
+        match (&decl.ty, &value) {
+            (Type::I(1), BaseExpr::Bool(_)) => {}
+            (Type::I(width), BaseExpr::Int(_)) if *width != 1 => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Constant {} of type {:?} cannot hold {:?}",
+                    decl.name,
+                    decl.ty,
+                    value
+                ));
+            }
+        }
+        subst.insert(decl.name.clone(), value);
+    }
+
+    let mut result = vec![];
+    for item in program {
+        match item {
+            TopLevel::ConstDecl(_) => {}
+            TopLevel::Import(path) => {
+                return Err(anyhow::anyhow!(
+                    "Import of {} must be resolved before evaluating constants",
+                    path
+                ));
+            }
+            TopLevel::ExternalDecl(decl) => result.push(TopLevel::ExternalDecl(ExternalDecl {
+                ty: subst.ty(&decl.ty)?,
+                name: decl.name,
+            })),
+            TopLevel::ExternComponent(decl) => {
+                let ports = |ports: &[(Ident, Type)]| {
+                    ports
+                        .iter()
+                        .map(|(port, ty)| Ok((port.clone(), subst.ty(ty)?)))
+                        .collect::<Result<Vec<_>>>()
+                };
+                result.push(TopLevel::ExternComponent(ExternComponent {
+                    inputs: ports(&decl.inputs)?,
+                    outputs: ports(&decl.outputs)?,
+                    ..decl
+                }))
+            }
+            TopLevel::StructDecl(decl) => result.push(TopLevel::StructDecl(StructDecl {
+                fields: decl
+                    .fields
+                    .iter()
+                    .map(|(field, ty)| Ok((field.clone(), subst.ty(ty)?)))
+                    .collect::<Result<_>>()?,
+                name: decl.name,
+            })),
+            TopLevel::FunDef(fundef) => result.push(TopLevel::FunDef(subst.fundef(&fundef)?)),
+        }
+    }
+    Ok(result)
+}
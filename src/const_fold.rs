@@ -0,0 +1,342 @@
+use crate::ast::*;
+use std::collections::HashMap;
+
+// Folds operations on constants and algebraic identities, so that `1 + 2` does not
+// become an adder nor `x * 1` a multiplier:
+//
+//   let a = 1 in let b = 2 in let c = a + b in let d = x * 1 in let e = d + c
+//   ~> let a = 1 in let b = 2 in let c = 3 in let d = x in let e = d + c
+//
+// Values are computed as the hardware does, unsigned and wrapping at the width of the
+// operands. Names bound with `var` may be assigned later and are never constants.
+struct ConstFolder {
+    // Value and width of the variables bound to a constant
+    consts: HashMap<Ident, (u64, usize)>,
+}
+
+impl ConstFolder {
+    fn expr(&mut self, expr: ANormalExpr) -> ANormalExpr {
+        let Expr_(lets, base) = expr;
+        let lets = lets
+            .into_iter()
+            .map(|let_binding| self.let_binding(let_binding))
+            .collect();
+        Expr_(lets, self.base_expr(base))
+    }
+
+    fn let_binding(&mut self, let_binding: ANormalLet) -> ANormalLet {
+        match let_binding {
+            ANormalLet::BindLet(bind_let) => {
+                let value = self.base_expr(bind_let.value);
+                let constant = match (&value, &bind_let.ty) {
+                    (ANormalBaseExpr::Int(n), Type::I(width)) => {
+                        Some((*n as i64 as u64 & mask(*width), *width))
+                    }
+                    (ANormalBaseExpr::Bool(b), _) => Some((*b as u64, 1)),
+                    _ => None,
+                };
+                if let Some(constant) = constant {
+                    self.consts.insert(bind_let.name.clone(), constant);
+                }
+                ANormalLet::BindLet(BindLet_ { value, ..bind_let })
+            }
+            ANormalLet::VarLet(var_let) => ANormalLet::VarLet(BindLet_ {
+                value: self.base_expr(var_let.value),
+                ..var_let
+            }),
+            ANormalLet::NoBindLet(no_bind_let) => ANormalLet::NoBindLet(NoBindLet_ {
+                value: self.base_expr(no_bind_let.value),
+            }),
+            ANormalLet::TupleLet(tuple_let) => ANormalLet::TupleLet(TupleLet_ {
+                value: self.base_expr(tuple_let.value),
+                ..tuple_let
+            }),
+        }
+    }
+
+    fn boxed_expr(&mut self, expr: ANormalExpr) -> Box<ANormalExpr> {
+        Box::new(self.expr(expr))
+    }
+
+    fn base_expr(&mut self, expr: ANormalBaseExpr) -> ANormalBaseExpr {
+        match expr {
+            ANormalBaseExpr::Var(name) => match self.consts.get(&name) {
+                Some(&(value, width)) => constant(value, width),
+                None => None,
+            }
+            .unwrap_or(ANormalBaseExpr::Var(name)),
+            ANormalBaseExpr::Add(left, right) => {
+                match (self.consts.get(&left), self.consts.get(&right)) {
+                    (Some(&(a, width)), Some(&(b, _))) => constant(a.wrapping_add(b), width),
+                    (Some((0, _)), None) => Some(ANormalBaseExpr::Var(right.clone())),
+                    (None, Some((0, _))) => Some(ANormalBaseExpr::Var(left.clone())),
+                    _ => None,
+                }
+                .unwrap_or(ANormalBaseExpr::Add(left, right))
+            }
+            ANormalBaseExpr::Mul(left, right) => {
+                match (self.consts.get(&left), self.consts.get(&right)) {
+                    (Some(&(a, width)), Some(&(b, _))) => constant(a.wrapping_mul(b), width),
+                    (Some(&(0, width)), None) | (None, Some(&(0, width))) => constant(0, width),
+                    (Some((1, _)), None) => Some(ANormalBaseExpr::Var(right.clone())),
+                    (None, Some((1, _))) => Some(ANormalBaseExpr::Var(left.clone())),
+                    _ => None,
+                }
+                .unwrap_or(ANormalBaseExpr::Mul(left, right))
+            }
+            ANormalBaseExpr::BinOp(op, left, right) => self
+                .binop(op, &left, &right)
+                .unwrap_or(ANormalBaseExpr::BinOp(op, left, right)),
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                ANormalBaseExpr::Map(arrays, index, params, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Reduce(array, init, acc, param, body) => {
+                ANormalBaseExpr::Reduce(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Scan(array, init, acc, param, body) => {
+                ANormalBaseExpr::Scan(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::For(counter, start, end, body) => {
+                ANormalBaseExpr::For(counter, start, end, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::While(cond, body) => {
+                ANormalBaseExpr::While(self.boxed_expr(*cond), self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::If(cond, then_branch, else_branch) => ANormalBaseExpr::If(
+                cond,
+                self.boxed_expr(*then_branch),
+                self.boxed_expr(*else_branch),
+            ),
+            expr => expr,
+        }
+    }
+
+    fn binop(&self, op: BinOp, left: &Ident, right: &Ident) -> Option<ANormalBaseExpr> {
+        let var = |name: &Ident| Some(ANormalBaseExpr::Var(name.clone()));
+        match (self.consts.get(left), self.consts.get(right)) {
+            (Some(&(a, width)), Some(&(b, _))) => match op {
+                BinOp::Sub => constant(a.wrapping_sub(b), width),
+                BinOp::Lt => Some(ANormalBaseExpr::Bool(a < b)),
+                BinOp::Gt => Some(ANormalBaseExpr::Bool(a > b)),
+                BinOp::Le => Some(ANormalBaseExpr::Bool(a <= b)),
+                BinOp::Ge => Some(ANormalBaseExpr::Bool(a >= b)),
+                BinOp::Eq => Some(ANormalBaseExpr::Bool(a == b)),
+                BinOp::Neq => Some(ANormalBaseExpr::Bool(a != b)),
+                BinOp::And => constant(a & b, width),
+                BinOp::Or => constant(a | b, width),
+                BinOp::Xor => constant(a ^ b, width),
+                BinOp::Shl => constant(a.checked_shl(b as u32).unwrap_or(0), width),
+            },
+            (None, Some((0, _)))
+                if matches!(op, BinOp::Sub | BinOp::Or | BinOp::Xor | BinOp::Shl) =>
+            {
+                var(left)
+            }
+            (Some((0, _)), None) if matches!(op, BinOp::Or | BinOp::Xor) => var(right),
+            (Some(&(0, width)), None) | (None, Some(&(0, width))) if op == BinOp::And => {
+                constant(0, width)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn mask(width: usize) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+// Values that do not fit a non-negative literal are left to the hardware
+fn constant(value: u64, width: usize) -> Option<ANormalBaseExpr> {
+    let value = value & mask(width);
+    if width == 1 {
+        Some(ANormalBaseExpr::Bool(value == 1))
+    } else {
+        i32::try_from(value).ok().map(ANormalBaseExpr::Int)
+    }
+}
+
+pub fn fold_program(program: ANormalProgram) -> ANormalProgram {
+    program
+        .into_iter()
+        .map(|item| match item {
+            ANormalTopLevel::FunDef(fundef) => {
+                let mut folder = ConstFolder {
+                    consts: HashMap::new(),
+                };
+                ANormalTopLevel::FunDef(FunDef_ {
+                    body: folder.expr(fundef.body),
+                    ..fundef
+                })
+            }
+            item => item,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ANormalBaseExpr::{Add, BinOp as Op, Bool, Int, Mul, Var};
+
+    fn op(op: BinOp, left: &str, right: &str) -> ANormalBaseExpr {
+        Op(op, left.to_string(), right.to_string())
+    }
+
+    fn is_var(value: &ANormalBaseExpr, name: &str) -> bool {
+        matches!(value, Var(var) if var == name)
+    }
+
+    // Folds the bindings, where `x` is an unknown parameter, and returns the value bound
+    // by the last one
+    fn fold(bindings: Vec<(&str, Type, ANormalBaseExpr)>) -> ANormalBaseExpr {
+        let lets = bindings
+            .into_iter()
+            .map(|(name, ty, value)| ANormalLet::BindLet(let_(name, ty, value)))
+            .collect();
+        let mut folder = ConstFolder {
+            consts: HashMap::new(),
+        };
+        let Expr_(mut lets, _) = folder.expr(Expr_(lets, Var("x".to_string())));
+        let Some(ANormalLet::BindLet(last)) = lets.pop() else {
+            panic!("expected a binding")
+        };
+        last.value
+    }
+
+    #[test]
+    fn wraps_at_the_width_of_the_type() {
+        let sum = fold(vec![
+            ("a", Type::I(8), Int(200)),
+            ("b", Type::I(8), Int(100)),
+            ("c", Type::I(8), Add("a".into(), "b".into())),
+        ]);
+        assert!(matches!(sum, Int(44)));
+        let difference = fold(vec![
+            ("a", Type::I(8), Int(0)),
+            ("b", Type::I(8), Int(1)),
+            ("c", Type::I(8), op(BinOp::Sub, "a", "b")),
+        ]);
+        assert!(matches!(difference, Int(255)));
+        let product = fold(vec![
+            ("a", Type::i32(), Int(65536)),
+            ("c", Type::i32(), Mul("a".into(), "a".into())),
+        ]);
+        assert!(matches!(product, Int(0)));
+    }
+
+    #[test]
+    fn takes_the_width_from_the_bound_type() {
+        let narrowed = fold(vec![
+            ("a", Type::I(8), Int(300)),
+            ("b", Type::I(8), Var("a".into())),
+        ]);
+        assert!(matches!(narrowed, Int(44)));
+        let bit = fold(vec![
+            ("a", Type::bool(), Int(3)),
+            ("b", Type::bool(), Var("a".into())),
+        ]);
+        assert!(matches!(bit, Bool(true)));
+    }
+
+    #[test]
+    fn leaves_values_that_are_not_non_negative_literals() {
+        let difference = fold(vec![
+            ("a", Type::i32(), Int(0)),
+            ("b", Type::i32(), Int(1)),
+            ("c", Type::i32(), op(BinOp::Sub, "a", "b")),
+        ]);
+        assert!(matches!(difference, Op(BinOp::Sub, ..)));
+    }
+
+    #[test]
+    fn applies_identities() {
+        let with = |value: ANormalBaseExpr| {
+            fold(vec![
+                ("zero", Type::i32(), Int(0)),
+                ("one", Type::i32(), Int(1)),
+                ("two", Type::i32(), Int(2)),
+                ("r", Type::i32(), value),
+            ])
+        };
+        let mul = |left: &str, right: &str| Mul(left.into(), right.into());
+        let add = |left: &str, right: &str| Add(left.into(), right.into());
+        assert!(matches!(with(mul("x", "zero")), Int(0)));
+        assert!(matches!(with(mul("zero", "x")), Int(0)));
+        assert!(is_var(&with(mul("x", "one")), "x"));
+        assert!(is_var(&with(mul("one", "x")), "x"));
+        assert!(is_var(&with(add("x", "zero")), "x"));
+        assert!(is_var(&with(add("zero", "x")), "x"));
+        assert!(is_var(&with(op(BinOp::Sub, "x", "zero")), "x"));
+        assert!(matches!(
+            with(op(BinOp::Sub, "zero", "x")),
+            Op(BinOp::Sub, ..)
+        ));
+        assert!(is_var(&with(op(BinOp::Or, "zero", "x")), "x"));
+        assert!(is_var(&with(op(BinOp::Xor, "x", "zero")), "x"));
+        assert!(matches!(with(op(BinOp::And, "x", "zero")), Int(0)));
+        assert!(matches!(with(mul("x", "two")), Mul(..)));
+    }
+
+    #[test]
+    fn folds_shifts() {
+        let shift = |ty: Type, value: i32, amount: i32| {
+            fold(vec![
+                ("a", ty.clone(), Int(value)),
+                ("b", ty.clone(), Int(amount)),
+                ("c", ty, op(BinOp::Shl, "a", "b")),
+            ])
+        };
+        assert!(matches!(shift(Type::i32(), 3, 2), Int(12)));
+        assert!(matches!(shift(Type::I(8), 1, 8), Int(0)));
+        assert!(matches!(shift(Type::i32(), 1, 100), Int(0)));
+        let by_zero = fold(vec![
+            ("zero", Type::i32(), Int(0)),
+            ("c", Type::i32(), op(BinOp::Shl, "x", "zero")),
+        ]);
+        assert!(is_var(&by_zero, "x"));
+    }
+
+    #[test]
+    fn compares_as_unsigned() {
+        let compare = |ty: Type, op_: BinOp, left: i32, right: i32| {
+            fold(vec![
+                ("a", ty.clone(), Int(left)),
+                ("b", ty, Int(right)),
+                ("c", Type::bool(), op(op_, "a", "b")),
+            ])
+        };
+        assert!(matches!(compare(Type::i32(), BinOp::Lt, 1, 2), Bool(true)));
+        assert!(matches!(compare(Type::i32(), BinOp::Ge, 1, 2), Bool(false)));
+        assert!(matches!(compare(Type::i32(), BinOp::Gt, -1, 1), Bool(true)));
+        assert!(matches!(
+            compare(Type::I(8), BinOp::Le, -1, 255),
+            Bool(true)
+        ));
+        assert!(matches!(
+            compare(Type::I(8), BinOp::Eq, -1, 255),
+            Bool(true)
+        ));
+        assert!(matches!(
+            compare(Type::I(8), BinOp::Neq, 1, 257),
+            Bool(false)
+        ));
+    }
+
+    #[test]
+    fn folds_bits_to_booleans() {
+        let bits = |op_: BinOp, left: bool, right: bool| {
+            fold(vec![
+                ("a", Type::bool(), Bool(left)),
+                ("b", Type::bool(), Bool(right)),
+                ("c", Type::bool(), op(op_, "a", "b")),
+            ])
+        };
+        assert!(matches!(bits(BinOp::Xor, true, true), Bool(false)));
+        assert!(matches!(bits(BinOp::Or, false, true), Bool(true)));
+        assert!(matches!(bits(BinOp::And, true, true), Bool(true)));
+    }
+}
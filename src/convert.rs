@@ -1,13 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
-    ast::{self, ANormalBindLet, ANormalNoBindLet, Type},
-    calyx_ast,
+    ast::{self, ANormalBindLet, ANormalNoBindLet, ANormalTupleLet, Type},
+    calyx_ast, fusion, unroll,
 };
 use anyhow::Result;
 
 const ADDRESS_WIDTH: usize = 32;
 
+// (params, return type)
+pub type FunType = (Vec<(String, Type)>, Option<Type>);
+
+type ConvertFn<'b> = Box<dyn FnOnce(Option<String>) -> Result<calyx_ast::Control> + 'b>;
+
+// Cells and groups of a `for i in start..end` style loop over a counter register
+struct CounterLoop {
+    count_reg: calyx_ast::Cell,
+    cond_lt: calyx_ast::Cell,
+    init_group: String,
+    cond_group: String,
+    inc_group: String,
+}
+
+impl CounterLoop {
+    fn index(&self) -> calyx_ast::Src {
+        calyx_ast::Src::Port(calyx_ast::Port {
+            cell: self.count_reg.name.clone(),
+            port: "out".to_string(),
+        })
+    }
+
+    fn control(self, mut body: Vec<calyx_ast::Control>) -> calyx_ast::Control {
+        body.push(calyx_ast::Control::GroupName(self.inc_group));
+        calyx_ast::Control::Seq(vec![
+            calyx_ast::Control::GroupName(self.init_group),
+            calyx_ast::Control::While {
+                condition: calyx_ast::Port {
+                    cell: self.cond_lt.name,
+                    port: "out".to_string(),
+                },
+                with: Some(self.cond_group),
+                body,
+            },
+        ])
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Converter {
     pub program: calyx_ast::Program,
@@ -15,12 +53,35 @@ pub struct Converter {
     pub current_func: Option<String>,
     // HashMap<VariableName, CellName>
     pub env: HashMap<String, calyx_ast::Src>,
+    // HashMap<VariableName, Srcs of the tuple components>
+    pub tuple_env: HashMap<String, Vec<calyx_ast::Src>>,
     pub type_env: HashMap<String, ast::Type>,
-    pub fun_type_env: HashMap<String, (Vec<(String, Type)>, Option<Type>)>,
+    pub fun_type_env: HashMap<String, FunType>,
+    // Variables declared with `var`, each backed by its own register
+    pub mutable_vars: HashSet<String>,
+    // HashMap<StructName, (field, width) of each field>
+    pub struct_env: HashMap<String, Vec<(String, usize)>>,
+    // HashMap<ExternComponentName, output port names>
+    pub extern_outputs: HashMap<String, Vec<String>>,
+    // The function compiled to the entry point component
+    pub top: String,
+    // Lanes per iteration of an unrolled map, 1 to not unroll
+    pub unroll: usize,
+    // Lower reductions with an associative operator to a tree of par stages
+    pub tree_reduce: bool,
+    // Lane whose body is being converted, 0 outside unrolled maps
+    lane: usize,
+    in_unrolled_map: bool,
+    // Arrays of the current function to split into one memory per lane
+    bankable: HashSet<String>,
+    // HashMap<ArrayName, memories holding the elements i with i % lanes == bank>
+    banked: HashMap<String, Vec<calyx_ast::Port>>,
+    // HashMap<StructElement, fields>, for elements of struct arrays used only by field
+    fields_read: HashMap<String, HashSet<String>>,
 }
 
 impl Converter {
-    pub fn init() -> Self {
+    pub fn init(top: &str) -> Self {
         let import_names = vec![
             "primitives/core.futil".to_string(),
             "primitives/binary_operators.futil".to_string(),
@@ -36,8 +97,20 @@ impl Converter {
             fresh_idx: 0,
             current_func: None,
             env: HashMap::new(),
+            tuple_env: HashMap::new(),
             type_env: HashMap::new(),
             fun_type_env: HashMap::new(),
+            mutable_vars: HashSet::new(),
+            struct_env: HashMap::new(),
+            extern_outputs: HashMap::new(),
+            top: top.to_string(),
+            unroll: 1,
+            tree_reduce: false,
+            lane: 0,
+            in_unrolled_map: false,
+            bankable: HashSet::new(),
+            banked: HashMap::new(),
+            fields_read: HashMap::new(),
         }
     }
 
@@ -57,7 +130,7 @@ impl Converter {
 
     fn get_current_func(&mut self) -> Result<&mut calyx_ast::Component> {
         if let Some(func_name) = &self.current_func {
-            if func_name == "main" {
+            if *func_name == self.top {
                 return Ok(&mut self.program.main);
             }
             if let Some(component) = self
@@ -84,17 +157,328 @@ impl Converter {
         }
     }
 
+    fn new_memory(&mut self, width: usize, len: usize) -> Result<calyx_ast::Cell> {
+        let cell = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::CombMemD1 {
+                data_width: width,
+                len,
+                address_width: ADDRESS_WIDTH,
+            },
+        };
+        self.get_current_func()?.cells.push(cell.clone());
+        Ok(cell)
+    }
+
+    fn new_register(&mut self, width: usize) -> Result<calyx_ast::Port> {
+        let reg = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::StdReg { width },
+        };
+        let reg_port = calyx_ast::Port {
+            cell: reg.name.clone(),
+            port: "".to_string(),
+        };
+        self.get_current_func()?.cells.push(reg);
+        Ok(reg_port)
+    }
+
+    // Adds a group that writes `src` into the register and returns the group name
+    fn new_register_write(
+        &mut self,
+        reg_port: &calyx_ast::Port,
+        src: calyx_ast::Src,
+    ) -> Result<String> {
+        let mut group = self.new_group();
+        group.wires.push(calyx_ast::Wire {
+            dest: reg_port.port("in"),
+            src,
+        });
+        group.wires.push(calyx_ast::Wire {
+            dest: reg_port.port("write_en"),
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        group.done = Some(reg_port.port("done").into());
+        let group_name = group.name.clone();
+        self.get_current_func()?.wires.groups.push(group);
+        Ok(group_name)
+    }
+
+    // Ports of the per-field memories backing an array of structs
+    fn find_struct_memories(&self, array: &str) -> Result<Vec<calyx_ast::Port>> {
+        self.tuple_env
+            .get(array)
+            .ok_or_else(|| anyhow::anyhow!("Variable {} not found in tuple map", array))?
+            .iter()
+            .map(|src| match src {
+                calyx_ast::Src::Port(port) => Ok(port.clone()),
+                _ => Err(anyhow::anyhow!(
+                    "Expected a port for array variable {}",
+                    array
+                )),
+            })
+            .collect()
+    }
+
+    // Reads `array[index]` into a fresh register and returns its output and the read group
+    fn read_memory(
+        &mut self,
+        array: &calyx_ast::Port,
+        index: calyx_ast::Src,
+        width: usize,
+    ) -> Result<(calyx_ast::Src, String)> {
+        let read_port = self.new_register(width)?;
+        let mut group = self.new_group();
+        group.wires.push(calyx_ast::Wire {
+            dest: array.port("addr0"),
+            src: index,
+        });
+        group.wires.push(calyx_ast::Wire {
+            dest: read_port.port("in"),
+            src: array.clone().into(),
+        });
+        group.wires.push(calyx_ast::Wire {
+            dest: read_port.port("write_en"),
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        group.done = Some(read_port.port("done").into());
+        let group_name = group.name.clone();
+        self.get_current_func()?.wires.groups.push(group);
+        Ok((read_port.port("out").into(), group_name))
+    }
+
+    fn write_memory(
+        &mut self,
+        array: &calyx_ast::Port,
+        index: calyx_ast::Src,
+        value: calyx_ast::Src,
+    ) -> Result<String> {
+        let mut group = self.new_group();
+        group.wires.push(calyx_ast::Wire {
+            dest: array.port("addr0"),
+            src: index,
+        });
+        group.wires.push(calyx_ast::Wire {
+            dest: array.port("write_data"),
+            src: value,
+        });
+        group.wires.push(calyx_ast::Wire {
+            dest: array.port("write_en"),
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        group.done = Some(array.port("done").into());
+        let group_name = group.name.clone();
+        self.get_current_func()?.wires.groups.push(group);
+        Ok(group_name)
+    }
+
+    // Drives a 1-bit condition through a wire in a comb group, for `while ... with` and
+    // `if ... with`
+    fn new_condition(&mut self, cond: calyx_ast::Src) -> Result<(calyx_ast::Port, String)> {
+        let wire = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::StdWire { width: 1 },
+        };
+        let wire_port = calyx_ast::Port {
+            cell: wire.name.clone(),
+            port: "".to_string(),
+        };
+        self.get_current_func()?.cells.push(wire);
+        let mut group = self.new_group();
+        group.wires.push(calyx_ast::Wire {
+            dest: wire_port.port("in"),
+            src: cond,
+        });
+        let group_name = group.name.clone();
+        self.get_current_func()?.wires.groups.push(group);
+        Ok((wire_port.port("out"), group_name))
+    }
+
+    fn new_counter_loop(&mut self, start: usize, end: usize) -> Result<CounterLoop> {
+        self.new_stepped_counter_loop(start, end, 1)
+    }
+
+    fn new_stepped_counter_loop(
+        &mut self,
+        start: usize,
+        end: usize,
+        step: usize,
+    ) -> Result<CounterLoop> {
+        let lane = self.lane;
+        let add_cell = self.get_current_func()?.get_add_cell(ADDRESS_WIDTH, lane);
+        let count_reg = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::StdReg {
+                width: ADDRESS_WIDTH,
+            },
+        };
+        let cond_lt = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::StdLt {
+                width: ADDRESS_WIDTH,
+            },
+        };
+        let count_port = calyx_ast::Port {
+            cell: count_reg.name.clone(),
+            port: "".to_string(),
+        };
+
+        let mut init_group = self.new_group();
+        init_group.wires.push(calyx_ast::Wire {
+            dest: count_port.port("in"),
+            src: calyx_ast::Src::Int {
+                value: start as isize,
+                width: ADDRESS_WIDTH,
+            },
+        });
+        init_group.wires.push(calyx_ast::Wire {
+            dest: count_port.port("write_en"),
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        init_group.done = Some(count_port.port("done").into());
+
+        let mut cond_group = self.new_group();
+        cond_group.wires.push(calyx_ast::Wire {
+            dest: calyx_ast::Port {
+                cell: cond_lt.name.clone(),
+                port: "left".to_string(),
+            },
+            src: count_port.port("out").into(),
+        });
+        cond_group.wires.push(calyx_ast::Wire {
+            dest: calyx_ast::Port {
+                cell: cond_lt.name.clone(),
+                port: "right".to_string(),
+            },
+            src: calyx_ast::Src::Int {
+                value: end as isize,
+                width: ADDRESS_WIDTH,
+            },
+        });
+
+        let mut inc_group = self.new_group();
+        inc_group.wires.push(calyx_ast::Wire {
+            dest: calyx_ast::Port {
+                cell: add_cell.name.clone(),
+                port: "left".to_string(),
+            },
+            src: count_port.port("out").into(),
+        });
+        inc_group.wires.push(calyx_ast::Wire {
+            dest: calyx_ast::Port {
+                cell: add_cell.name.clone(),
+                port: "right".to_string(),
+            },
+            src: calyx_ast::Src::Int {
+                value: step as isize,
+                width: ADDRESS_WIDTH,
+            },
+        });
+        inc_group.wires.push(calyx_ast::Wire {
+            dest: count_port.port("in"),
+            src: calyx_ast::Src::Port(calyx_ast::Port {
+                cell: add_cell.name.clone(),
+                port: "out".to_string(),
+            }),
+        });
+        inc_group.wires.push(calyx_ast::Wire {
+            dest: count_port.port("write_en"),
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        inc_group.done = Some(count_port.port("done").into());
+
+        let counter_loop = CounterLoop {
+            count_reg: count_reg.clone(),
+            cond_lt: cond_lt.clone(),
+            init_group: init_group.name.clone(),
+            cond_group: cond_group.name.clone(),
+            inc_group: inc_group.name.clone(),
+        };
+        let component = self.get_current_func()?;
+        component.cells.push(count_reg);
+        component.cells.push(cond_lt);
+        component.wires.groups.push(init_group);
+        component.wires.groups.push(cond_group);
+        component.wires.groups.push(inc_group);
+        Ok(counter_loop)
+    }
+
+    // Allocates a memory of `size` elements and fills it in a counter loop. `value` gets
+    // the current index and returns the element to store there.
+    fn generate_memory(
+        &mut self,
+        width: usize,
+        size: usize,
+        value: impl FnOnce(calyx_ast::Src) -> calyx_ast::Src,
+    ) -> Result<(calyx_ast::Cell, calyx_ast::Control)> {
+        let memory = self.new_memory(width, size)?;
+        let counter_loop = self.new_counter_loop(0, size)?;
+        let memory_port = calyx_ast::Port {
+            cell: memory.name.clone(),
+            port: "".to_string(),
+        };
+        let mut write_group = self.new_group();
+        write_group.wires.push(calyx_ast::Wire {
+            dest: memory_port.port("addr0"),
+            src: counter_loop.index(),
+        });
+        write_group.wires.push(calyx_ast::Wire {
+            dest: memory_port.port("write_data"),
+            src: value(counter_loop.index()),
+        });
+        write_group.wires.push(calyx_ast::Wire {
+            dest: memory_port.port("write_en"),
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        write_group.done = Some(memory_port.port("done").into());
+        let write_group_name = write_group.name.clone();
+        self.get_current_func()?.wires.groups.push(write_group);
+        let control = counter_loop.control(vec![calyx_ast::Control::GroupName(write_group_name)]);
+        Ok((memory, control))
+    }
+
     pub fn convert(&mut self, ast: ast::ANormalProgram) -> Result<()> {
         for decl in ast {
             match decl {
                 ast::ANormalTopLevel::ExternalDecl(decl) => {
                     self.convert_external_decl(&decl)?;
                 }
+                ast::ANormalTopLevel::ExternComponent(decl) => {
+                    self.convert_extern_component(&decl)?;
+                }
+                ast::ANormalTopLevel::StructDecl(decl) => {
+                    self.convert_struct_decl(&decl)?;
+                }
+                ast::ANormalTopLevel::Import(path) => {
+                    return Err(anyhow::anyhow!(
+                        "Import of {} must be resolved before conversion",
+                        path
+                    ));
+                }
+                ast::ANormalTopLevel::ConstDecl(decl) => {
+                    return Err(anyhow::anyhow!(
+                        "Constant {} must be evaluated before conversion",
+                        decl.name
+                    ));
+                }
                 ast::ANormalTopLevel::FunDef(fundef) => {
                     self.convert_fundef(&fundef)?;
                 }
             }
         }
+        if !self.fun_type_env.contains_key(&self.top) {
+            return Err(anyhow::anyhow!("Top function {} is not defined", self.top));
+        }
         Ok(())
     }
 
@@ -103,6 +487,8 @@ impl Converter {
     fn convert_fundef(&mut self, fundef: &ast::ANormalFunDef) -> Result<()> {
         let ast::FunDef_ {
             name,
+            inline: _,
+            size_params: _,
             params,
             return_type,
             body,
@@ -110,111 +496,187 @@ impl Converter {
         self.fun_type_env
             .insert(name.clone(), (params.clone(), return_type.clone()));
         self.current_func = Some(name.clone());
-        let is_main = name == "main";
-        if is_main && !(params.is_empty() && return_type.is_none()) {
-            return Err(anyhow::anyhow!(
-                "Main function must have no parameters and no return type"
-            ));
-        }
-        if !is_main {
-            let mut component_params: Vec<(String, usize)> = vec![];
-            let mut cells: Vec<calyx_ast::Cell> = vec![];
-            for (param_name, param_type) in params {
-                self.type_env.insert(param_name.clone(), param_type.clone());
-                match param_type {
-                    ast::Type::I(width) => {
-                        component_params.push((param_name.clone(), *width));
+        // The top component is the entry point, so its arrays are external memories
+        let is_top = *name == self.top;
+        let mut component_params: Vec<(String, usize)> = vec![];
+        let mut cells: Vec<calyx_ast::Cell> = vec![];
+        for (param_name, param_type) in params {
+            self.type_env.insert(param_name.clone(), param_type.clone());
+            match param_type {
+                ast::Type::I(width) => {
+                    component_params.push((param_name.clone(), *width));
+                    self.env.insert(
+                        param_name.clone(),
+                        calyx_ast::Src::Port(calyx_ast::Port {
+                            cell: param_name.clone(),
+                            port: "".to_string(),
+                        }),
+                    );
+                }
+                ast::Type::Array(content_ty, size) if matches!(**content_ty, Type::Struct(_)) => {
+                    let Type::Struct(struct_name) = &**content_ty else {
+                        unreachable!()
+                    };
+                    // One memory per field
+                    let mut items = vec![];
+                    for (field, width) in self.struct_fields(struct_name)? {
+                        let field_name = format!("{}_{}", param_name, field);
+                        cells.push(calyx_ast::Cell {
+                            name: field_name.clone(),
+                            is_external: is_top,
+                            is_ref: !is_top,
+                            circuit: calyx_ast::Circuit::CombMemD1 {
+                                data_width: width,
+                                len: *size,
+                                address_width: ADDRESS_WIDTH,
+                            },
+                        });
+                        items.push(calyx_ast::Src::Port(calyx_ast::Port {
+                            cell: field_name,
+                            port: "read_data".to_string(),
+                        }));
+                    }
+                    self.tuple_env.insert(param_name.clone(), items);
+                }
+                ast::Type::Array(content_ty, size) => {
+                    if let ast::Type::I(width) = &**content_ty {
+                        let array_ref_cell = calyx_ast::Cell {
+                            name: param_name.clone(),
+                            is_external: is_top,
+                            is_ref: !is_top,
+                            circuit: calyx_ast::Circuit::CombMemD1 {
+                                data_width: *width,
+                                len: *size,
+                                address_width: ADDRESS_WIDTH,
+                            },
+                        };
+                        cells.push(array_ref_cell);
                         self.env.insert(
                             param_name.clone(),
                             calyx_ast::Src::Port(calyx_ast::Port {
                                 cell: param_name.clone(),
-                                port: "".to_string(),
+                                port: "read_data".to_string(),
                             }),
                         );
+                    } else {
+                        return Err(anyhow::anyhow!(
+                            "Expected an integer type for array parameter"
+                        ));
                     }
-                    ast::Type::Array(content_ty, size) => {
-                        if let ast::Type::I(width) = &**content_ty {
-                            let array_ref_cell = calyx_ast::Cell {
-                                name: param_name.clone(),
-                                is_external: false,
-                                is_ref: true,
-                                circuit: calyx_ast::Circuit::CombMemD1 {
-                                    data_width: *width,
-                                    len: *size,
-                                    address_width: ADDRESS_WIDTH,
-                                },
-                            };
-                            cells.push(array_ref_cell);
-                            self.env.insert(
-                                param_name.clone(),
-                                calyx_ast::Src::Port(calyx_ast::Port {
-                                    cell: param_name.clone(),
-                                    port: "read_data".to_string(),
-                                }),
-                            );
-                        } else {
-                            return Err(anyhow::anyhow!(
-                                "Expected an integer type for array parameter"
-                            ));
-                        }
+                }
+                ast::Type::Struct(struct_name) => {
+                    // One port per field
+                    let mut items = vec![];
+                    for (field, width) in self.struct_fields(struct_name)? {
+                        let field_name = format!("{}_{}", param_name, field);
+                        component_params.push((field_name.clone(), width));
+                        items.push(calyx_ast::Src::Port(calyx_ast::Port {
+                            cell: field_name,
+                            port: "".to_string(),
+                        }));
                     }
-                };
-            }
+                    self.tuple_env.insert(param_name.clone(), items);
+                }
+                ast::Type::Tuple(_) => {
+                    return Err(anyhow::anyhow!(
+                        "Tuple parameters are not supported: {}",
+                        param_name
+                    ));
+                }
+                ast::Type::SymbolicArray(_, size) => {
+                    return Err(anyhow::anyhow!(
+                        "Size {} of parameter {} is not a known constant",
+                        size,
+                        param_name
+                    ));
+                }
+            };
+        }
 
-            let result = if let Some(ty) = return_type {
-                let name = Converter::FUN_OUT_NAME.to_string();
-                match ty {
-                    ast::Type::I(width) => {
-                        self.type_env.insert(name.clone(), ty.clone());
+        let result = if let Some(ty) = return_type {
+            let name = Converter::FUN_OUT_NAME.to_string();
+            match ty {
+                ast::Type::I(width) => {
+                    self.type_env.insert(name.clone(), ty.clone());
+                    self.env.insert(
+                        name.clone(),
+                        calyx_ast::Src::Port(calyx_ast::Port {
+                            cell: name.clone(),
+                            port: "out".to_string(),
+                        }),
+                    );
+                    vec![(name.clone(), *width)]
+                }
+                ast::Type::Array(content_ty, size) => {
+                    if let ast::Type::I(width) = &**content_ty {
+                        // Allocated by the caller, see the Call lowering, or external
+                        // for the top component
+                        let array_cell = calyx_ast::Cell {
+                            name: name.clone(),
+                            is_external: is_top,
+                            is_ref: !is_top,
+                            circuit: calyx_ast::Circuit::CombMemD1 {
+                                data_width: *width,
+                                len: *size,
+                                address_width: ADDRESS_WIDTH,
+                            },
+                        };
+                        cells.push(array_cell);
                         self.env.insert(
                             name.clone(),
                             calyx_ast::Src::Port(calyx_ast::Port {
                                 cell: name.clone(),
-                                port: "out".to_string(),
+                                port: "read_data".to_string(),
                             }),
                         );
-                        vec![(name.clone(), *width)]
-                    }
-                    ast::Type::Array(content_ty, size) => {
-                        if let ast::Type::I(width) = &**content_ty {
-                            let array_cell = calyx_ast::Cell {
-                                name: name.clone(),
-                                is_external: false,
-                                is_ref: false,
-                                circuit: calyx_ast::Circuit::CombMemD1 {
-                                    data_width: *width,
-                                    len: *size,
-                                    address_width: ADDRESS_WIDTH,
-                                },
-                            };
-                            cells.push(array_cell);
-                            self.env.insert(
-                                name.clone(),
-                                calyx_ast::Src::Port(calyx_ast::Port {
-                                    cell: name.clone(),
-                                    port: "read_data".to_string(),
-                                }),
-                            );
-                            vec![]
-                        } else {
-                            return Err(anyhow::anyhow!(
-                                "Expected an integer type for array return type"
-                            ));
-                        }
+                        vec![]
+                    } else {
+                        return Err(anyhow::anyhow!(
+                            "Expected an integer type for array return type"
+                        ));
                     }
                 }
-            } else {
-                vec![]
-            };
+                ast::Type::Tuple(_) | ast::Type::Struct(_) => self.output_ports(ty)?,
+                ast::Type::SymbolicArray(_, size) => {
+                    return Err(anyhow::anyhow!(
+                        "Size {} of the return type is not a known constant",
+                        size
+                    ));
+                }
+            }
+        } else {
+            vec![]
+        };
 
-            let component = calyx_ast::Component {
-                name: name.clone(),
-                params: component_params,
-                result,
-                wires: calyx_ast::Wires::default(),
-                cells,
-                control: vec![],
-            };
+        let component = calyx_ast::Component {
+            name: name.clone(),
+            params: component_params,
+            result,
+            wires: calyx_ast::Wires::default(),
+            cells,
+            control: vec![],
+            is_toplevel: false,
+        };
+        if is_top {
+            // The externals are already cells of the top component
+            let top = &mut self.program.main;
+            if let Some(cell) = component
+                .cells
+                .iter()
+                .find(|cell| top.find_cell(&cell.name).is_some())
+            {
+                return Err(anyhow::anyhow!(
+                    "Parameter {} of top function {} has the name of an external",
+                    cell.name,
+                    name
+                ));
+            }
+            top.name = component.name;
+            top.params = component.params;
+            top.result = component.result;
+            top.cells.extend(component.cells);
+            top.is_toplevel = top.name != "main";
+        } else {
             self.program.components.push(component);
         }
 
@@ -225,62 +687,136 @@ impl Converter {
             Some(name.clone())
         };
 
+        self.bankable = if self.unroll > 1 {
+            unroll::bankable_arrays(body, self.tree_reduce)
+        } else {
+            HashSet::new()
+        };
+        self.fields_read = fields_read(body);
         let control = self.convert_expr(body, out.clone())?;
         self.get_current_func()?.push_control(control);
 
-        if return_type.is_some() && matches!(return_type, Some(ast::Type::I(..))) {
-            let result = self.find_src_by_var(out.as_ref().unwrap())?;
-            let output_cell = calyx_ast::Cell {
-                name: self.fresh_name(),
-                is_external: false,
-                is_ref: false,
-                circuit: calyx_ast::Circuit::StdReg {
-                    width: match return_type.as_ref().unwrap() {
-                        ast::Type::I(width) => *width,
-                        _ => unreachable!("Expected an integer type for output"),
-                    },
-                },
-            };
-            self.get_current_func()?.cells.push(output_cell.clone());
-            let mut group = self.new_group();
-            group.wires.push(calyx_ast::Wire {
+        match return_type {
+            Some(ast::Type::I(width)) => {
+                let result = self.find_src_by_var(out.as_ref().unwrap())?;
+                let group_name = self.write_output(Converter::FUN_OUT_NAME, *width, result)?;
+                self.get_current_func()?
+                    .control
+                    .push(calyx_ast::Control::GroupName(group_name));
+            }
+            Some(ty @ (ast::Type::Tuple(_) | ast::Type::Struct(_))) => {
+                let ports = self.output_ports(ty)?;
+                let out = out.as_ref().unwrap();
+                let items = self.tuple_env.get(out).cloned().ok_or_else(|| {
+                    anyhow::anyhow!("Expected a tuple value to return from {}", name)
+                })?;
+                if items.len() != ports.len() {
+                    return Err(anyhow::anyhow!(
+                        "Function {} returns {} values, but its type has {}",
+                        name,
+                        items.len(),
+                        ports.len()
+                    ));
+                }
+                let mut writes = vec![];
+                for ((port_name, width), item) in ports.into_iter().zip(items) {
+                    let group_name = self.write_output(&port_name, width, item)?;
+                    writes.push(calyx_ast::Control::GroupName(group_name));
+                }
+                self.get_current_func()?
+                    .control
+                    .push(calyx_ast::Control::Par(writes));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn struct_fields(&self, name: &str) -> Result<Vec<(String, usize)>> {
+        self.struct_env
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Struct {} is not declared", name))
+    }
+
+    // Output ports of a function returning a tuple (`_out_0`, `_out_1`, ...) or a struct
+    // (`_out_<field>`)
+    fn output_ports(&self, ty: &Type) -> Result<Vec<(String, usize)>> {
+        match ty {
+            Type::Tuple(tys) => tys
+                .iter()
+                .enumerate()
+                .map(|(i, ty)| match ty {
+                    Type::I(width) => Ok((format!("{}_{}", Converter::FUN_OUT_NAME, i), *width)),
+                    _ => Err(anyhow::anyhow!(
+                        "Expected integer types in tuple return type, but got {:?}",
+                        ty
+                    )),
+                })
+                .collect(),
+            Type::Struct(name) => Ok(self
+                .struct_fields(name)?
+                .into_iter()
+                .map(|(field, width)| (format!("{}_{}", Converter::FUN_OUT_NAME, field), width))
+                .collect()),
+            _ => Err(anyhow::anyhow!(
+                "Expected a tuple or struct type, got {:?}",
+                ty
+            )),
+        }
+    }
+
+    // Latches `result` into a register driving the output port `port_name` and returns
+    // the name of the group doing the write
+    fn write_output(
+        &mut self,
+        port_name: &str,
+        width: usize,
+        result: calyx_ast::Src,
+    ) -> Result<String> {
+        let output_cell = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::StdReg { width },
+        };
+        self.get_current_func()?.cells.push(output_cell.clone());
+        let mut group = self.new_group();
+        group.wires.push(calyx_ast::Wire {
+            dest: calyx_ast::Port {
+                cell: output_cell.name.clone(),
+                port: "in".to_string(),
+            },
+            src: result,
+        });
+        group.wires.push(calyx_ast::Wire {
+            dest: calyx_ast::Port {
+                cell: output_cell.name.clone(),
+                port: "write_en".to_string(),
+            },
+            src: calyx_ast::Src::Int { value: 1, width: 1 },
+        });
+        group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+            cell: output_cell.name.clone(),
+            port: "done".to_string(),
+        }));
+        let group_name = group.name.clone();
+        self.get_current_func()?.wires.groups.push(group);
+        self.get_current_func()?
+            .wires
+            .static_wires
+            .push(calyx_ast::Wire {
                 dest: calyx_ast::Port {
-                    cell: output_cell.name.clone(),
-                    port: "in".to_string(),
+                    cell: port_name.to_string(),
+                    port: "".to_string(),
                 },
-                src: result,
-            });
-            group.wires.push(calyx_ast::Wire {
-                dest: calyx_ast::Port {
+                src: calyx_ast::Src::Port(calyx_ast::Port {
                     cell: output_cell.name.clone(),
-                    port: "write_en".to_string(),
-                },
-                src: calyx_ast::Src::Int { value: 1, width: 1 },
+                    port: "out".to_string(),
+                }),
             });
-            group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                cell: output_cell.name.clone(),
-                port: "done".to_string(),
-            }));
-            self.get_current_func()?
-                .control
-                .push(calyx_ast::Control::GroupName(group.name.clone()));
-            self.get_current_func()?.wires.groups.push(group);
-            self.get_current_func()?
-                .wires
-                .static_wires
-                .push(calyx_ast::Wire {
-                    dest: calyx_ast::Port {
-                        cell: Converter::FUN_OUT_NAME.to_string(),
-                        port: "".to_string(),
-                    },
-                    src: calyx_ast::Src::Port(calyx_ast::Port {
-                        cell: output_cell.name.clone(),
-                        port: "out".to_string(),
-                    }),
-                });
-        }
-
-        Ok(())
+        Ok(group_name)
     }
 
     fn convert_expr(
@@ -312,21 +848,69 @@ impl Converter {
             ast::ANormalLet::NoBindLet(ANormalNoBindLet { value }) => {
                 self.convert_base_expr(value)?(None)
             }
+            ast::ANormalLet::VarLet(ANormalBindLet { name, value, ty }) => {
+                let Type::I(width) = ty else {
+                    return Err(anyhow::anyhow!(
+                        "Mutable variable {} must have an integer type, but got {:?}",
+                        name,
+                        ty
+                    ));
+                };
+                let init_var = self.fresh_name();
+                self.type_env.insert(init_var.clone(), ty.clone());
+                let init_control = self.convert_base_expr(value)?(Some(init_var.clone()))?;
+                let init_value = self.find_src_by_var(&init_var)?;
+                let reg_port = self.new_register(*width)?;
+                let init_group = self.new_register_write(&reg_port, init_value)?;
+                self.type_env.insert(name.clone(), ty.clone());
+                self.mutable_vars.insert(name.clone());
+                self.env.insert(name.clone(), reg_port.port("out").into());
+                let mut controls = vec![init_control, calyx_ast::Control::GroupName(init_group)];
+                controls.retain(|control| !control.is_empty());
+                Ok(calyx_ast::Control::Seq(controls))
+            }
+            ast::ANormalLet::TupleLet(ANormalTupleLet { names, ty, value }) => {
+                let Some(Type::Tuple(tys)) = ty else {
+                    return Err(anyhow::anyhow!("Expected a tuple type for {:?}", names));
+                };
+                let tuple_var = self.fresh_name();
+                let control = self.convert_base_expr(value)?(Some(tuple_var.clone()))?;
+                let items = self.tuple_env.get(&tuple_var).cloned().ok_or_else(|| {
+                    anyhow::anyhow!("Expected a tuple value to bind to {:?}", names)
+                })?;
+                if items.len() != names.len() {
+                    return Err(anyhow::anyhow!(
+                        "Cannot bind {} names to a tuple of {} values",
+                        names.len(),
+                        items.len()
+                    ));
+                }
+                for ((name, ty), item) in names.iter().zip(tys).zip(items) {
+                    self.type_env.insert(name.clone(), ty.clone());
+                    self.env.insert(name.clone(), item);
+                }
+                Ok(control)
+            }
         }
     }
 
     fn convert_base_expr<'a: 'b, 'b>(
         &'a mut self,
         base_expr: &'a ast::ANormalBaseExpr,
-    ) -> Result<Box<dyn FnOnce(Option<String>) -> Result<calyx_ast::Control> + 'b>> {
+    ) -> Result<ConvertFn<'b>> {
         match base_expr {
             ast::ANormalBaseExpr::Int(n) => Ok(Box::new(|dest: Option<String>| {
                 if let Some(dest) = dest {
+                    // Folded constants take the width of the variable they are bound to
+                    let width = match self.type_env.get(&dest) {
+                        Some(Type::I(width)) => *width,
+                        _ => 32,
+                    };
                     self.env.insert(
                         dest.clone(),
                         calyx_ast::Src::Int {
                             value: *n as isize,
-                            width: 32,
+                            width,
                         },
                     );
                 }
@@ -345,23 +929,304 @@ impl Converter {
                 Ok(calyx_ast::Control::empty())
             })),
             ast::ANormalBaseExpr::Var(var) => Ok(Box::new(move |dest: Option<String>| {
+                if let Some(items) = self.tuple_env.get(var).cloned() {
+                    if let Some(dest) = dest {
+                        self.tuple_env.insert(dest.clone(), items);
+                    }
+                    return Ok(calyx_ast::Control::empty());
+                }
                 let src = self.find_src_by_var(var)?;
-                if let Some(dest) = dest {
-                    self.env.insert(dest.clone(), src);
+                let Some(dest) = dest else {
+                    return Ok(calyx_ast::Control::empty());
+                };
+                if self.mutable_vars.contains(var) {
+                    // Take a snapshot so that later assignments do not change `dest`
+                    let width = match self.type_env.get(var) {
+                        Some(Type::I(width)) => *width,
+                        _ => 32,
+                    };
+                    let reg_port = self.new_register(width)?;
+                    let group_name = self.new_register_write(&reg_port, src)?;
+                    self.env.insert(dest, reg_port.port("out").into());
+                    return Ok(calyx_ast::Control::GroupName(group_name));
                 }
+                self.env.insert(dest, src);
                 Ok(calyx_ast::Control::empty())
             })),
-            ast::ANormalBaseExpr::Add(var1, var2) => {
+            ast::ANormalBaseExpr::Assign(var, value) => {
+                if !self.mutable_vars.contains(var) {
+                    return Err(anyhow::anyhow!(
+                        "Cannot assign to {}, which is not declared with var",
+                        var
+                    ));
+                }
+                let calyx_ast::Src::Port(reg_out) = self.find_src_by_var(var)? else {
+                    return Err(anyhow::anyhow!("Expected a register for variable {}", var));
+                };
+                let value = self.find_src_by_var(value)?;
+                Ok(Box::new(move |dest: Option<String>| {
+                    let group_name = self.new_register_write(&reg_out.port(""), value)?;
+                    if let Some(dest) = dest {
+                        self.env.insert(dest, reg_out.into());
+                    }
+                    Ok(calyx_ast::Control::GroupName(group_name))
+                }))
+            }
+            ast::ANormalBaseExpr::BinOp(op, var1, var2) => {
+                let width = match self.type_env.get(var1) {
+                    Some(Type::I(width)) => *width,
+                    _ => 32,
+                };
+                // The wires of the cell follow later assignments to a `var`, so a result
+                // computed from one is latched to keep the value it has here
+                let latch = self.mutable_vars.contains(var1) || self.mutable_vars.contains(var2);
                 let var1 = self.find_src_by_var(var1)?;
                 let var2 = self.find_src_by_var(var2)?;
+                let circuit = match op {
+                    ast::BinOp::Sub => calyx_ast::Circuit::StdSub { width },
+                    ast::BinOp::Lt => calyx_ast::Circuit::StdLt { width },
+                    ast::BinOp::Gt => calyx_ast::Circuit::StdGt { width },
+                    ast::BinOp::Le => calyx_ast::Circuit::StdLe { width },
+                    ast::BinOp::Ge => calyx_ast::Circuit::StdGe { width },
+                    ast::BinOp::Eq => calyx_ast::Circuit::StdEq { width },
+                    ast::BinOp::Neq => calyx_ast::Circuit::StdNeq { width },
+                    ast::BinOp::And => calyx_ast::Circuit::StdAnd { width },
+                    ast::BinOp::Or => calyx_ast::Circuit::StdOr { width },
+                    ast::BinOp::Xor => calyx_ast::Circuit::StdXor { width },
+                    ast::BinOp::Shl => calyx_ast::Circuit::StdLsh { width },
+                };
                 Ok(Box::new(move |dest: Option<String>| {
                     if let Some(dest) = dest {
-                        let new_add_cell_name = self.fresh_name();
-                        let new_add_cell = calyx_ast::Cell {
-                            name: new_add_cell_name.clone(),
+                        let cell = calyx_ast::Cell {
+                            name: self.fresh_name(),
                             is_external: false,
                             is_ref: false,
-                            circuit: calyx_ast::Circuit::StdAdd { width: 32 },
+                            circuit,
+                        };
+                        let cell_port = calyx_ast::Port {
+                            cell: cell.name.clone(),
+                            port: "".to_string(),
+                        };
+                        let component = self.get_current_func()?;
+                        component.cells.push(cell);
+                        component.wires.static_wires.push(calyx_ast::Wire {
+                            dest: cell_port.port("left"),
+                            src: var1,
+                        });
+                        component.wires.static_wires.push(calyx_ast::Wire {
+                            dest: cell_port.port("right"),
+                            src: var2,
+                        });
+                        if latch {
+                            let width = if op.is_comparison() { 1 } else { width };
+                            let reg_port = self.new_register(width)?;
+                            let group_name =
+                                self.new_register_write(&reg_port, cell_port.port("out").into())?;
+                            self.env.insert(dest, reg_port.port("out").into());
+                            return Ok(calyx_ast::Control::GroupName(group_name));
+                        }
+                        self.env.insert(dest, cell_port.port("out").into());
+                    }
+                    Ok(calyx_ast::Control::empty())
+                }))
+            }
+            ast::ANormalBaseExpr::While(cond, body) => {
+                Ok(Box::new(move |_dest: Option<String>| {
+                    let cond_var = self.fresh_name();
+                    self.type_env.insert(cond_var.clone(), Type::bool());
+                    // The condition is re-evaluated before the first iteration and after each one
+                    let cond_control = self.convert_expr(cond, Some(cond_var.clone()))?;
+                    let cond_src = self.find_src_by_var(&cond_var)?;
+                    let (condition, with) = self.new_condition(cond_src)?;
+                    let body_control = self.convert_expr(body, None)?;
+                    let mut body = vec![body_control, cond_control.clone()];
+                    body.retain(|control| !control.is_empty());
+                    let mut controls = vec![
+                        cond_control,
+                        calyx_ast::Control::While {
+                            condition,
+                            with: Some(with),
+                            body,
+                        },
+                    ];
+                    controls.retain(|control| !control.is_empty());
+                    Ok(calyx_ast::Control::Seq(controls))
+                }))
+            }
+            ast::ANormalBaseExpr::If(cond, then_branch, else_branch) => {
+                let cond = self.find_src_by_var(cond)?;
+                Ok(Box::new(move |dest: Option<String>| {
+                    let (condition, with) = self.new_condition(cond)?;
+                    // Both branches write their value into the same result register
+                    let result_reg = match &dest {
+                        Some(dest) => {
+                            let width = match self.type_env.get(dest) {
+                                Some(Type::I(width)) => *width,
+                                _ => 32,
+                            };
+                            Some(self.new_register(width)?)
+                        }
+                        None => None,
+                    };
+                    let mut branches = vec![];
+                    for branch in [then_branch, else_branch] {
+                        let Some(result_reg) = &result_reg else {
+                            branches.push(vec![self.convert_expr(branch, None)?]);
+                            continue;
+                        };
+                        let branch_var = self.fresh_name();
+                        let control = self.convert_expr(branch, Some(branch_var.clone()))?;
+                        let value = self.find_src_by_var(&branch_var)?;
+                        let write_group = self.new_register_write(result_reg, value)?;
+                        branches.push(vec![control, calyx_ast::Control::GroupName(write_group)]);
+                    }
+                    if let (Some(dest), Some(result_reg)) = (dest, &result_reg) {
+                        self.env.insert(dest, result_reg.port("out").into());
+                    }
+                    let else_branch = branches.pop().unwrap();
+                    let then_branch = branches.pop().unwrap();
+                    Ok(calyx_ast::Control::If {
+                        condition,
+                        with: Some(with),
+                        then_branch,
+                        else_branch,
+                    })
+                }))
+            }
+            ast::ANormalBaseExpr::ArrayGet(array, index) => {
+                let Some(Type::Array(content_ty, _)) = self.type_env.get(array) else {
+                    return Err(anyhow::anyhow!("Expected an array type for {}", array));
+                };
+                let content_ty = (**content_ty).clone();
+                let index = self.find_src_by_var(index)?;
+                if let Type::Struct(struct_name) = &content_ty {
+                    let fields = self.struct_fields(struct_name)?;
+                    let memories = self.find_struct_memories(array)?;
+                    return Ok(Box::new(move |dest: Option<String>| {
+                        let Some(dest) = dest else {
+                            return Ok(calyx_ast::Control::empty());
+                        };
+                        // An element only used through its fields reads just those
+                        // memories; the others are never looked at
+                        let fields_read = self.fields_read.get(&dest).cloned();
+                        let mut items = vec![];
+                        let mut reads = vec![];
+                        for (memory, (field, width)) in memories.iter().zip(fields) {
+                            if fields_read
+                                .as_ref()
+                                .is_some_and(|fields_read| !fields_read.contains(&field))
+                            {
+                                items.push(calyx_ast::Src::Int { width, value: 0 });
+                                continue;
+                            }
+                            let (item, group_name) =
+                                self.read_memory(memory, index.clone(), width)?;
+                            items.push(item);
+                            reads.push(calyx_ast::Control::GroupName(group_name));
+                        }
+                        self.tuple_env.insert(dest, items);
+                        Ok(calyx_ast::Control::Par(reads))
+                    }));
+                }
+                let Type::I(width) = content_ty else {
+                    return Err(anyhow::anyhow!("Expected an integer type for {}", array));
+                };
+                let calyx_ast::Src::Port(array) = self.find_src_by_var(array)? else {
+                    return Err(anyhow::anyhow!("Expected a port for array variable"));
+                };
+
+                Ok(Box::new(move |dest: Option<String>| {
+                    let Some(dest) = dest else {
+                        return Ok(calyx_ast::Control::empty());
+                    };
+                    let (item, group_name) = self.read_memory(&array, index, width)?;
+                    self.env.insert(dest, item);
+                    Ok(calyx_ast::Control::GroupName(group_name))
+                }))
+            }
+            ast::ANormalBaseExpr::Field(value, field) => {
+                let Some(Type::Struct(struct_name)) = self.type_env.get(value) else {
+                    return Err(anyhow::anyhow!("Expected a struct type for {}", value));
+                };
+                let fields = self.struct_fields(&struct_name.clone())?;
+                let Some(position) = fields.iter().position(|(name, _)| name == field) else {
+                    return Err(anyhow::anyhow!(
+                        "Struct {} has no field {}",
+                        struct_name,
+                        field
+                    ));
+                };
+                let items = self
+                    .tuple_env
+                    .get(value)
+                    .ok_or_else(|| anyhow::anyhow!("Variable {} not found in tuple map", value))?;
+                let src = items[position].clone();
+                Ok(Box::new(move |dest: Option<String>| {
+                    if let Some(dest) = dest {
+                        self.env.insert(dest, src);
+                    }
+                    Ok(calyx_ast::Control::empty())
+                }))
+            }
+            ast::ANormalBaseExpr::Struct(struct_name, fields) => {
+                // Items are kept in declaration order, whatever order the fields were given in
+                let items: Vec<calyx_ast::Src> = self
+                    .struct_fields(struct_name)?
+                    .iter()
+                    .map(|(field, _)| {
+                        let (_, value) = fields
+                            .iter()
+                            .find(|(given, _)| given == field)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("Struct {} is missing field {}", struct_name, field)
+                            })?;
+                        self.find_src_by_var(value)
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Box::new(move |dest: Option<String>| {
+                    if let Some(dest) = dest {
+                        self.tuple_env.insert(dest, items);
+                    }
+                    Ok(calyx_ast::Control::empty())
+                }))
+            }
+            ast::ANormalBaseExpr::For(counter, start, end, body) => {
+                self.type_env
+                    .insert(counter.clone(), Type::I(ADDRESS_WIDTH));
+                let start = *start;
+                let end = *end;
+                Ok(Box::new(move |_dest: Option<String>| {
+                    let counter_loop = self.new_counter_loop(start, end)?;
+                    self.env.insert(counter.clone(), counter_loop.index());
+                    let body_control = self.convert_expr(body, None)?;
+                    Ok(counter_loop.control(vec![body_control]))
+                }))
+            }
+            ast::ANormalBaseExpr::Tuple(items) => {
+                let items: Vec<calyx_ast::Src> = items
+                    .iter()
+                    .map(|item| self.find_src_by_var(item))
+                    .collect::<Result<_>>()?;
+                Ok(Box::new(move |dest: Option<String>| {
+                    if let Some(dest) = dest {
+                        self.tuple_env.insert(dest.clone(), items);
+                    }
+                    Ok(calyx_ast::Control::empty())
+                }))
+            }
+            ast::ANormalBaseExpr::Add(var1, var2) => {
+                // Latched like a `BinOp` when it reads a `var`
+                let latch = self.mutable_vars.contains(var1) || self.mutable_vars.contains(var2);
+                let var1 = self.find_src_by_var(var1)?;
+                let var2 = self.find_src_by_var(var2)?;
+                Ok(Box::new(move |dest: Option<String>| {
+                    if let Some(dest) = dest {
+                        let new_add_cell_name = self.fresh_name();
+                        let new_add_cell = calyx_ast::Cell {
+                            name: new_add_cell_name.clone(),
+                            is_external: false,
+                            is_ref: false,
+                            circuit: calyx_ast::Circuit::StdAdd { width: 32 },
                         };
                         self.get_current_func()?.cells.push(new_add_cell);
                         let left_wire = calyx_ast::Wire {
@@ -380,6 +1245,19 @@ impl Converter {
                         };
                         self.get_current_func()?.wires.static_wires.push(left_wire);
                         self.get_current_func()?.wires.static_wires.push(right_wire);
+                        if latch {
+                            let reg_port = self.new_register(32)?;
+                            let group_name = self.new_register_write(
+                                &reg_port,
+                                calyx_ast::Port {
+                                    cell: new_add_cell_name,
+                                    port: "out".to_string(),
+                                }
+                                .into(),
+                            )?;
+                            self.env.insert(dest, reg_port.port("out").into());
+                            return Ok(calyx_ast::Control::GroupName(group_name));
+                        }
                         self.env.insert(
                             dest.clone(),
                             calyx_ast::Src::Port(calyx_ast::Port {
@@ -396,7 +1274,8 @@ impl Converter {
                 let var2 = self.find_src_by_var(var2)?;
                 Ok(Box::new(move |dest: Option<String>| {
                     if let Some(dest) = dest {
-                        let mult_cell = self.get_current_func()?.get_mult_cell(32);
+                        let lane = self.lane;
+                        let mult_cell = self.get_current_func()?.get_mult_cell(32, lane);
                         let dest_cell = calyx_ast::Cell {
                             name: dest.clone(),
                             is_external: false,
@@ -466,18 +1345,116 @@ impl Converter {
                     }
                 }))
             }
-            ast::ANormalBaseExpr::NewArray(_, _) => todo!(),
-            ast::ANormalBaseExpr::Map(vars, args, expr) => {
+            ast::ANormalBaseExpr::NewArray(ty, size) if matches!(**ty, Type::Struct(_)) => {
+                let Type::Struct(struct_name) = &**ty else {
+                    unreachable!()
+                };
+                let widths: Vec<usize> = self
+                    .struct_fields(struct_name)?
+                    .into_iter()
+                    .map(|(_, width)| width)
+                    .collect();
+                let size = *size;
+                Ok(Box::new(move |dest: Option<String>| {
+                    let items = widths
+                        .into_iter()
+                        .map(|width| {
+                            let memory = self.new_memory(width, size)?;
+                            Ok(calyx_ast::Src::Port(calyx_ast::Port {
+                                cell: memory.name,
+                                port: "read_data".to_string(),
+                            }))
+                        })
+                        .collect::<Result<_>>()?;
+                    if let Some(dest) = dest {
+                        self.tuple_env.insert(dest, items);
+                    }
+                    Ok(calyx_ast::Control::empty())
+                }))
+            }
+            ast::ANormalBaseExpr::NewArray(ty, size) => {
+                let Type::I(width) = &**ty else {
+                    return Err(anyhow::anyhow!("Expected an integer type for new_array"));
+                };
+                let width = *width;
+                let size = *size;
+                Ok(Box::new(move |dest: Option<String>| {
+                    let memory = self.new_memory(width, size)?;
+                    if let Some(dest) = dest {
+                        self.env.insert(
+                            dest.clone(),
+                            calyx_ast::Src::Port(calyx_ast::Port {
+                                cell: memory.name.clone(),
+                                port: "read_data".to_string(),
+                            }),
+                        );
+                    }
+                    Ok(calyx_ast::Control::empty())
+                }))
+            }
+            ast::ANormalBaseExpr::Range(size) => {
+                let size = *size;
+                Ok(Box::new(move |dest: Option<String>| {
+                    let (memory, control) =
+                        self.generate_memory(ADDRESS_WIDTH, size, |index| index)?;
+                    if let Some(dest) = dest {
+                        self.env.insert(
+                            dest.clone(),
+                            calyx_ast::Src::Port(calyx_ast::Port {
+                                cell: memory.name.clone(),
+                                port: "read_data".to_string(),
+                            }),
+                        );
+                    }
+                    Ok(control)
+                }))
+            }
+            ast::ANormalBaseExpr::Fill(ty, size, value) => {
+                let Type::I(width) = &**ty else {
+                    return Err(anyhow::anyhow!("Expected an integer type for fill"));
+                };
+                let width = *width;
+                let size = *size;
+                let value = self.find_src_by_var(value)?;
+                Ok(Box::new(move |dest: Option<String>| {
+                    let (memory, control) = self.generate_memory(width, size, |_| value)?;
+                    if let Some(dest) = dest {
+                        self.env.insert(
+                            dest.clone(),
+                            calyx_ast::Src::Port(calyx_ast::Port {
+                                cell: memory.name.clone(),
+                                port: "read_data".to_string(),
+                            }),
+                        );
+                    }
+                    Ok(control)
+                }))
+            }
+            ast::ANormalBaseExpr::Map(vars, index, args, expr) => {
                 let Some(Type::Array(content_ty, size)) = self.type_env.get(vars.first().unwrap())
                 else {
                     return Err(anyhow::anyhow!("Expected an array type for map"));
                 };
-                let Type::I(width) = &**content_ty else {
-                    return Err(anyhow::anyhow!("Expected an integer type for map"));
-                };
                 let size = *size;
-                let width = *width;
+                let width = element_width("map", vars.first().unwrap(), content_ty)?;
+                for var in &vars[1..] {
+                    if let Some(Type::Array(content_ty, _)) = self.type_env.get(var) {
+                        element_width("map", var, content_ty)?;
+                    }
+                }
+                if args.len() != vars.len() {
+                    return Err(anyhow::anyhow!(
+                        "Map over {} arrays expects {} lambda parameters, but got {}",
+                        vars.len(),
+                        vars.len(),
+                        args.len()
+                    ));
+                }
                 let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                // Maps nested in an unrolled map already run once per lane
+                if self.unroll > 1 && size > 1 && !self.in_unrolled_map {
+                    return self.convert_unrolled_map(vars, index, args, expr, width, size);
+                }
                 let vars: Vec<calyx_ast::Port> = vars
                     .iter()
                     .map(|var| match self.find_src_by_var(var)? {
@@ -488,20 +1465,13 @@ impl Converter {
                 for arg in &args {
                     self.type_env.insert(arg.clone(), Type::I(width));
                 }
+                if let Some(index) = index {
+                    self.type_env.insert(index.clone(), Type::I(ADDRESS_WIDTH));
+                }
                 Ok(Box::new(move |dest: Option<String>| {
                     let mut seq_vec = vec![];
-                    let add_cell = self.get_current_func()?.get_add_cell(32);
-                    let new_vec = calyx_ast::Cell {
-                        name: self.fresh_name(),
-                        is_external: false,
-                        is_ref: false,
-                        circuit: calyx_ast::Circuit::CombMemD1 {
-                            data_width: width,
-                            len: size,
-                            address_width: ADDRESS_WIDTH,
-                        },
-                    };
-                    self.get_current_func()?.cells.push(new_vec.clone());
+                    let lane = self.lane;
+                    let add_cell = self.get_current_func()?.get_add_cell(32, lane);
                     let count_reg = calyx_ast::Cell {
                         name: self.fresh_name(),
                         is_external: false,
@@ -511,6 +1481,15 @@ impl Converter {
                         },
                     };
                     self.get_current_func()?.cells.push(count_reg.clone());
+                    if let Some(index) = index {
+                        self.env.insert(
+                            index.clone(),
+                            calyx_ast::Src::Port(calyx_ast::Port {
+                                cell: count_reg.name.clone(),
+                                port: "out".to_string(),
+                            }),
+                        );
+                    }
                     let arg_regs: Vec<calyx_ast::Cell> = args
                         .iter()
                         .map(|arg| {
@@ -541,15 +1520,6 @@ impl Converter {
                         },
                     };
                     self.get_current_func()?.cells.push(cond_lt.clone());
-                    if let Some(dest) = dest {
-                        self.env.insert(
-                            dest.clone(),
-                            calyx_ast::Src::Port(calyx_ast::Port {
-                                cell: new_vec.name.clone(),
-                                port: "read_data".to_string(),
-                            }),
-                        );
-                    }
 
                     let mut init_count_reg_group = self.new_group();
                     init_count_reg_group.wires.push(calyx_ast::Wire {
@@ -641,45 +1611,77 @@ impl Converter {
                     }
                     let result_var = self.fresh_name();
                     let body_control = self.convert_expr(expr, Some(result_var.clone()))?;
-                    let result = self.env.get(&result_var).cloned().ok_or_else(|| {
-                        anyhow::anyhow!(
-                            "internal error: Expected result variable {} to be in environment",
-                            result_var
-                        )
-                    })?;
-                    let mut result_reg_group = self.new_group();
-                    result_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: new_vec.name.clone(),
-                            port: "addr0".to_string(),
-                        },
-                        src: calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "out".to_string(),
-                        }),
-                    });
-                    result_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
+                    // A lambda returning a tuple writes each component into its own memory
+                    let results = if let Some(items) = self.tuple_env.get(&result_var) {
+                        items.clone()
+                    } else {
+                        vec![self.env.get(&result_var).cloned().ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "internal error: Expected result variable {} to be in environment",
+                                result_var
+                            )
+                        })?]
+                    };
+
+                    let mut out_ports = vec![];
+                    let mut result_reg_groups = vec![];
+                    for result in results {
+                        let new_vec = calyx_ast::Cell {
+                            name: self.fresh_name(),
+                            is_external: false,
+                            is_ref: false,
+                            circuit: calyx_ast::Circuit::CombMemD1 {
+                                data_width: width,
+                                len: size,
+                                address_width: ADDRESS_WIDTH,
+                            },
+                        };
+                        self.get_current_func()?.cells.push(new_vec.clone());
+                        out_ports.push(calyx_ast::Src::Port(calyx_ast::Port {
                             cell: new_vec.name.clone(),
-                            port: "write_data".to_string(),
-                        },
-                        src: result,
-                    });
-                    result_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
+                            port: "read_data".to_string(),
+                        }));
+
+                        let mut result_reg_group = self.new_group();
+                        result_reg_group.wires.push(calyx_ast::Wire {
+                            dest: calyx_ast::Port {
+                                cell: new_vec.name.clone(),
+                                port: "addr0".to_string(),
+                            },
+                            src: calyx_ast::Src::Port(calyx_ast::Port {
+                                cell: count_reg.name.clone(),
+                                port: "out".to_string(),
+                            }),
+                        });
+                        result_reg_group.wires.push(calyx_ast::Wire {
+                            dest: calyx_ast::Port {
+                                cell: new_vec.name.clone(),
+                                port: "write_data".to_string(),
+                            },
+                            src: result,
+                        });
+                        result_reg_group.wires.push(calyx_ast::Wire {
+                            dest: calyx_ast::Port {
+                                cell: new_vec.name.clone(),
+                                port: "write_en".to_string(),
+                            },
+                            src: calyx_ast::Src::Int { value: 1, width: 1 },
+                        });
+                        result_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
                             cell: new_vec.name.clone(),
-                            port: "write_en".to_string(),
-                        },
-                        src: calyx_ast::Src::Int { value: 1, width: 1 },
-                    });
-                    result_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                        cell: new_vec.name.clone(),
-                        port: "done".to_string(),
-                    }));
-                    self.get_current_func()?
-                        .wires
-                        .groups
-                        .push(result_reg_group.clone());
+                            port: "done".to_string(),
+                        }));
+                        result_reg_groups
+                            .push(calyx_ast::Control::GroupName(result_reg_group.name.clone()));
+                        self.get_current_func()?.wires.groups.push(result_reg_group);
+                    }
+                    if let Some(dest) = dest {
+                        if out_ports.len() == 1 {
+                            self.env.insert(dest.clone(), out_ports.remove(0));
+                        } else {
+                            self.tuple_env.insert(dest.clone(), out_ports);
+                        }
+                    }
 
                     let mut inc_count_group = self.new_group();
                     inc_count_group.wires.push(calyx_ast::Wire {
@@ -738,7 +1740,11 @@ impl Converter {
                     if !body_control.is_empty() {
                         loop_body.push(body_control);
                     }
-                    loop_body.push(calyx_ast::Control::GroupName(result_reg_group.name.clone()));
+                    if result_reg_groups.len() == 1 {
+                        loop_body.append(&mut result_reg_groups);
+                    } else {
+                        loop_body.push(calyx_ast::Control::Par(result_reg_groups));
+                    }
                     loop_body.push(calyx_ast::Control::GroupName(inc_count_group.name.clone()));
 
                     let loop_control = calyx_ast::Control::While {
@@ -756,403 +1762,1105 @@ impl Converter {
                 }))
             }
             ast::ANormalBaseExpr::Reduce(array, init_value, acm, arg, expr) => {
-                let Some(Type::Array(content_ty, size)) = &self.type_env.get(array) else {
-                    return Err(anyhow::anyhow!("Expected an array type for reduction"));
-                };
-                let Type::I(width) = &**content_ty else {
-                    return Err(anyhow::anyhow!("Expected an integer type for reduction"));
+                let size = match self.type_env.get(array) {
+                    Some(Type::Array(_, size)) => *size,
+                    _ => 0,
                 };
-                let size = *size;
-                let width = *width;
-                let calyx_ast::Src::Port(array) = self.find_src_by_var(array)? else {
-                    return Err(anyhow::anyhow!("Expected a port for array variable"));
-                };
-                let init_value = self.find_src_by_var(init_value)?;
-                self.type_env.insert(acm.clone(), Type::I(width));
-                self.type_env.insert(arg.clone(), Type::I(width));
+                // Lanes of an unrolled map already share the per-lane cells
+                if self.tree_reduce
+                    && !self.in_unrolled_map
+                    && is_tree_reducible(size, acm, arg, expr)
+                {
+                    return self.convert_tree_reduce(array, init_value, acm, arg, expr);
+                }
+                self.convert_fold(array, init_value, acm, arg, expr, false)
+            }
+            ast::ANormalBaseExpr::Scan(array, init_value, acm, arg, expr) => {
+                self.convert_fold(array, init_value, acm, arg, expr, true)
+            }
+            ast::ANormalBaseExpr::Call(fun_name, arg_names) => {
+                // A struct argument is passed as one src per field
+                let args: Vec<Vec<calyx_ast::Src>> = arg_names
+                    .iter()
+                    .map(|arg| match self.tuple_env.get(arg) {
+                        Some(items) => Ok(items.clone()),
+                        None => Ok(vec![self.find_src_by_var(arg)?]),
+                    })
+                    .collect::<Result<_>>()?;
+                if *fun_name == self.top {
+                    return Err(anyhow::anyhow!(
+                        "Top function {} cannot be called",
+                        fun_name
+                    ));
+                }
+                let fun_name = fun_name.to_string();
                 Ok(Box::new(move |dest: Option<String>| {
-                    let mut seq_vec = vec![];
-                    let add_cell = self.get_current_func()?.get_add_cell(width);
-                    let acm_reg = calyx_ast::Cell {
-                        name: self.fresh_name(),
+                    let (params, result_ty) = self
+                        .fun_type_env
+                        .get(&fun_name)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Function {} not found in function environment",
+                                fun_name
+                            )
+                        })?
+                        .clone();
+                    if params.len() != args.len() {
+                        return Err(anyhow::anyhow!(
+                            "Function {} expects {} arguments, but {} were given",
+                            fun_name,
+                            params.len(),
+                            args.len()
+                        ));
+                    }
+                    for ((param_name, param_ty), arg) in params.iter().zip(arg_names) {
+                        if let Some(arg_ty) = self.type_env.get(arg)
+                            && arg_ty != param_ty
+                        {
+                            return Err(anyhow::anyhow!(
+                                "Function {} expects parameter {} of type {:?}, but got {:?}",
+                                fun_name,
+                                param_name,
+                                param_ty,
+                                arg_ty
+                            ));
+                        }
+                    }
+                    let fun = self.fresh_name();
+                    let fun_cell = calyx_ast::Cell {
+                        name: fun,
                         is_external: false,
                         is_ref: false,
-                        circuit: calyx_ast::Circuit::StdReg { width },
+                        circuit: calyx_ast::Circuit::FunInstance {
+                            name: fun_name.clone(),
+                        },
                     };
-                    self.env.insert(
-                        acm.clone(),
-                        calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: acm_reg.name.clone(),
-                            port: "out".to_string(),
-                        }),
-                    );
-                    if let Some(dest) = dest {
-                        self.env.insert(
-                            dest.clone(),
-                            calyx_ast::Src::Port(calyx_ast::Port {
-                                cell: acm_reg.name.clone(),
-                                port: "out".to_string(),
-                            }),
-                        );
+                    self.get_current_func()?.cells.push(fun_cell.clone());
+
+                    // Scalars go to input ports, arrays are passed as ref cells
+                    let mut inputs: Vec<(String, calyx_ast::Src)> = vec![];
+                    let mut refs: Vec<(String, String)> = vec![];
+                    for ((param_name, param_ty), arg) in params.iter().zip(&args) {
+                        let names = match param_ty {
+                            Type::Struct(struct_name) => self
+                                .struct_fields(struct_name)?
+                                .into_iter()
+                                .map(|(field, _)| format!("{}_{}", param_name, field))
+                                .collect(),
+                            Type::Array(content_ty, _) => match &**content_ty {
+                                Type::Struct(struct_name) => self
+                                    .struct_fields(struct_name)?
+                                    .into_iter()
+                                    .map(|(field, _)| format!("{}_{}", param_name, field))
+                                    .collect(),
+                                _ => vec![param_name.clone()],
+                            },
+                            _ => vec![param_name.clone()],
+                        };
+                        for (name, src) in names.into_iter().zip(arg) {
+                            if matches!(param_ty, Type::Array(..)) {
+                                let calyx_ast::Src::Port(memory) = src else {
+                                    return Err(anyhow::anyhow!(
+                                        "Expected a memory for parameter {} of {}",
+                                        param_name,
+                                        fun_name
+                                    ));
+                                };
+                                refs.push((name, memory.cell.clone()));
+                            } else {
+                                inputs.push((name, src.clone()));
+                            }
+                        }
                     }
-                    let count_reg = calyx_ast::Cell {
-                        name: self.fresh_name(),
-                        is_external: false,
-                        is_ref: false,
-                        circuit: calyx_ast::Circuit::StdReg {
-                            width: ADDRESS_WIDTH,
-                        },
-                    };
-                    let arg_reg = calyx_ast::Cell {
-                        name: self.fresh_name(),
-                        is_external: false,
-                        is_ref: false,
-                        circuit: calyx_ast::Circuit::StdReg { width },
-                    };
-                    self.env.insert(
-                        arg.clone(),
+
+                    let fun_port = |port: String| {
                         calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: arg_reg.name.clone(),
-                            port: "out".to_string(),
-                        }),
-                    );
-                    let cond_lt = calyx_ast::Cell {
-                        name: self.fresh_name(),
-                        is_external: false,
-                        is_ref: false,
-                        circuit: calyx_ast::Circuit::StdLt {
-                            width: ADDRESS_WIDTH,
-                        },
+                            cell: fun_cell.name.clone(),
+                            port,
+                        })
                     };
-                    let mut init_count_reg_group = self.new_group();
-                    init_count_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "in".to_string(),
-                        },
-                        src: calyx_ast::Src::Int {
-                            value: 0,
-                            width: ADDRESS_WIDTH,
-                        },
-                    });
-                    init_count_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "write_en".to_string(),
-                        },
-                        src: calyx_ast::Src::Int { value: 1, width: 1 },
-                    });
-                    init_count_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                        cell: count_reg.name.clone(),
-                        port: "done".to_string(),
-                    }));
+                    let extern_outputs = self.extern_outputs.get(&fun_name).cloned();
+                    match &result_ty {
+                        // Hand-written components name their own output ports
+                        _ if let Some(outputs) = extern_outputs => {
+                            if let Some(dest) = &dest {
+                                let mut items: Vec<calyx_ast::Src> =
+                                    outputs.into_iter().map(fun_port).collect();
+                                if items.len() == 1 {
+                                    self.env.insert(dest.clone(), items.remove(0));
+                                } else {
+                                    self.tuple_env.insert(dest.clone(), items);
+                                }
+                            }
+                        }
+                        Some(Type::Array(content_ty, size)) => {
+                            // The caller owns the result memory and lends it to the callee
+                            let Type::I(width) = &**content_ty else {
+                                return Err(anyhow::anyhow!(
+                                    "Expected an integer type for array return type"
+                                ));
+                            };
+                            let memory = self.new_memory(*width, *size)?;
+                            refs.push((Converter::FUN_OUT_NAME.to_string(), memory.name.clone()));
+                            if let Some(dest) = &dest {
+                                self.env.insert(
+                                    dest.clone(),
+                                    calyx_ast::Src::Port(calyx_ast::Port {
+                                        cell: memory.name,
+                                        port: "read_data".to_string(),
+                                    }),
+                                );
+                            }
+                        }
+                        Some(ty @ (Type::Tuple(_) | Type::Struct(_))) => {
+                            if let Some(dest) = &dest {
+                                let items = self
+                                    .output_ports(ty)?
+                                    .into_iter()
+                                    .map(|(port, _)| fun_port(port))
+                                    .collect();
+                                self.tuple_env.insert(dest.clone(), items);
+                            }
+                        }
+                        _ => {
+                            if let Some(dest) = &dest {
+                                self.env.insert(
+                                    dest.clone(),
+                                    fun_port(Converter::FUN_OUT_NAME.to_string()),
+                                );
+                            }
+                        }
+                    }
 
-                    let mut init_acm_reg_group = self.new_group();
-                    init_acm_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: acm_reg.name.clone(),
-                            port: "in".to_string(),
-                        },
-                        src: init_value.clone(),
-                    });
-                    init_acm_reg_group.wires.push(calyx_ast::Wire {
+                    if !refs.is_empty() {
+                        return Ok(calyx_ast::Control::Invoke {
+                            cell: fun_cell.name.clone(),
+                            refs,
+                            inputs,
+                        });
+                    }
+
+                    let mut group = self.new_group();
+                    for (port, src) in inputs {
+                        group.wires.push(calyx_ast::Wire {
+                            dest: calyx_ast::Port {
+                                cell: fun_cell.name.clone(),
+                                port,
+                            },
+                            src,
+                        });
+                    }
+                    group.wires.push(calyx_ast::Wire {
                         dest: calyx_ast::Port {
-                            cell: acm_reg.name.clone(),
-                            port: "write_en".to_string(),
+                            cell: fun_cell.name.clone(),
+                            port: "go".to_string(),
                         },
                         src: calyx_ast::Src::Int { value: 1, width: 1 },
                     });
-                    init_acm_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                        cell: acm_reg.name.clone(),
+                    group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                        cell: fun_cell.name.clone(),
                         port: "done".to_string(),
                     }));
-                    let init_control = calyx_ast::Control::Par(vec![
-                        calyx_ast::Control::GroupName(init_count_reg_group.name.clone()),
-                        calyx_ast::Control::GroupName(init_acm_reg_group.name.clone()),
-                    ]);
-                    self.get_current_func()?
-                        .wires
-                        .groups
-                        .push(init_count_reg_group);
-                    self.get_current_func()?
-                        .wires
-                        .groups
-                        .push(init_acm_reg_group);
+                    let group_name = group.name.clone();
+                    self.get_current_func()?.wires.groups.push(group);
+                    Ok(calyx_ast::Control::GroupName(group_name))
+                }))
+            }
+            ast::ANormalBaseExpr::ArraySet(array, index, value)
+                if matches!(
+                    self.type_env.get(array),
+                    Some(Type::Array(content_ty, _)) if matches!(**content_ty, Type::Struct(_))
+                ) =>
+            {
+                let memories = self.find_struct_memories(array)?;
+                let index = self.find_src_by_var(index)?;
+                let items = self
+                    .tuple_env
+                    .get(&**value)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Expected a struct value for {}", value))?;
+
+                Ok(Box::new(move |dest: Option<String>| {
+                    let mut writes = vec![];
+                    for (memory, item) in memories.iter().zip(&items) {
+                        let group_name = self.write_memory(memory, index.clone(), item.clone())?;
+                        writes.push(calyx_ast::Control::GroupName(group_name));
+                    }
+                    if let Some(dest) = dest {
+                        self.tuple_env.insert(dest, items);
+                    }
+                    Ok(calyx_ast::Control::Par(writes))
+                }))
+            }
+            ast::ANormalBaseExpr::ArraySet(array, index, value) => {
+                let calyx_ast::Src::Port(array) = self.find_src_by_var(array)? else {
+                    return Err(anyhow::anyhow!("Expected a port for array variable"));
+                };
+                let index = self.find_src_by_var(index)?;
+                let value = self.find_src_by_var(value)?;
 
-                    seq_vec.push(init_control);
+                Ok(Box::new(move |dest: Option<String>| {
+                    let group_name = self.write_memory(&array, index, value.clone())?;
+                    if let Some(dest) = dest {
+                        self.env.insert(dest.clone(), value);
+                    }
+                    Ok(calyx_ast::Control::GroupName(group_name))
+                }))
+            }
+        }
+    }
 
-                    let mut cond_lt_group = self.new_group();
-                    cond_lt_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: cond_lt.name.clone(),
-                            port: "left".to_string(),
-                        },
-                        src: calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "out".to_string(),
-                        }),
+    // `map` with `--unroll K`: K lanes, each with its own copy of the body, process the
+    // elements count..count+K of an iteration, and the first size % K lanes run once more
+    // after the loop for the remaining elements. The memories allocated by maps are split
+    // into K banks, element i at address i / K of bank i % K, so that each lane reads and
+    // writes its own bank in par. Other memories, such as external inputs, have a single
+    // port, so the lanes read and write them one after another and only their bodies run
+    // in par; a map over an external input gains no more than the overlap of the bodies.
+    // Bodies that access a memory or variable from outside the lambda run one after
+    // another too.
+    fn convert_unrolled_map<'a: 'b, 'b>(
+        &'a mut self,
+        arrays: &'a [ast::Ident],
+        index: &'a Option<String>,
+        args: Vec<String>,
+        expr: &'a ast::ANormalExpr,
+        width: usize,
+        size: usize,
+    ) -> Result<ConvertFn<'b>> {
+        let lanes = self.unroll.min(size);
+        let lane_bodies: Vec<_> = (0..lanes)
+            .map(|lane| {
+                if lane == 0 {
+                    (args.clone(), index.clone(), expr.clone())
+                } else {
+                    unroll::lane_copy(&args, index, expr, lane)
+                }
+            })
+            .collect();
+        let mut vars = vec![];
+        let mut in_banks = vec![];
+        for array in arrays {
+            let calyx_ast::Src::Port(var) = self.find_src_by_var(array)? else {
+                return Err(anyhow::anyhow!("Expected a port for variable {}", array));
+            };
+            vars.push(var);
+            let banks = self.banked.get(array).cloned();
+            if banks.as_ref().is_some_and(|banks| banks.len() != lanes) {
+                return Err(anyhow::anyhow!(
+                    "Array {} is split into a different number of banks than map lanes",
+                    array
+                ));
+            }
+            in_banks.push(banks);
+        }
+        // Lanes accessing a memory or variable from outside the body take turns
+        let shares_state = unroll::outer_state(expr).iter().any(|name| {
+            self.mutable_vars.contains(name)
+                || matches!(self.type_env.get(name), Some(Type::Array(..)))
+        });
+        Ok(Box::new(move |dest: Option<String>| {
+            let counter_loop = self.new_stepped_counter_loop(0, size / lanes * lanes, lanes)?;
+            // A lambda returning a tuple is never bound to a bankable name
+            let banked_out = dest
+                .as_ref()
+                .is_some_and(|dest| self.bankable.contains(dest));
+            // The address of the current elements in the banks, one per iteration, with
+            // the groups setting it to 0 and incrementing it
+            let bank_index = if banked_out || in_banks.iter().any(Option::is_some) {
+                let bank_index = self.new_register(ADDRESS_WIDTH)?;
+                let init = self.new_register_write(
+                    &bank_index,
+                    calyx_ast::Src::Int {
+                        value: 0,
+                        width: ADDRESS_WIDTH,
+                    },
+                )?;
+                let next = self.new_comb_add(bank_index.port("out").into(), 1)?;
+                let incr = self.new_register_write(&bank_index, next.port("out").into())?;
+                Some((calyx_ast::Src::from(bank_index.port("out")), init, incr))
+            } else {
+                None
+            };
+
+            let mut reads = vec![vec![]; vars.len()];
+            let mut bodies = vec![];
+            let mut results = vec![];
+            for (lane, (lane_args, lane_index, lane_body)) in lane_bodies.iter().enumerate() {
+                let index_src = if lane == 0 {
+                    counter_loop.index()
+                } else {
+                    let add_port = self.new_comb_add(counter_loop.index(), lane)?;
+                    add_port.port("out").into()
+                };
+                if let Some(lane_index) = lane_index {
+                    self.env.insert(lane_index.clone(), index_src.clone());
+                    self.type_env
+                        .insert(lane_index.clone(), Type::I(ADDRESS_WIDTH));
+                }
+                let mut arg_srcs: Vec<calyx_ast::Src> = vec![];
+                for (i, ((var, banks), arg)) in
+                    vars.iter().zip(&in_banks).zip(lane_args).enumerate()
+                {
+                    self.type_env.insert(arg.clone(), Type::I(width));
+                    // An array passed twice is read once
+                    let first = arrays.iter().position(|array| *array == arrays[i]).unwrap();
+                    if first < i {
+                        let arg_src = arg_srcs[first].clone();
+                        self.env.insert(arg.clone(), arg_src.clone());
+                        arg_srcs.push(arg_src);
+                        continue;
+                    }
+                    let (arg_src, read_group) = match (banks, &bank_index) {
+                        (Some(banks), Some((bank_address, _, _))) => {
+                            self.read_memory(&banks[lane], bank_address.clone(), width)?
+                        }
+                        _ => self.read_memory(var, index_src.clone(), width)?,
+                    };
+                    self.env.insert(arg.clone(), arg_src.clone());
+                    arg_srcs.push(arg_src);
+                    reads[i].push(calyx_ast::Control::GroupName(read_group));
+                }
+
+                self.lane = lane;
+                self.in_unrolled_map = true;
+                let result_var = self.fresh_name();
+                let body_control = self.convert_expr(lane_body, Some(result_var.clone()));
+                self.lane = 0;
+                self.in_unrolled_map = false;
+                bodies.push(body_control?);
+                // A lambda returning a tuple writes each component into its own memory
+                let lane_results = match self.tuple_env.get(&result_var) {
+                    Some(items) => items.clone(),
+                    None => vec![self.find_src_by_var(&result_var)?],
+                };
+                results.push((index_src, lane_results));
+            }
+
+            let mut out_memories = vec![];
+            let mut out_banks = vec![];
+            if banked_out {
+                for _ in 0..lanes {
+                    let memory = self.new_memory(width, size.div_ceil(lanes))?;
+                    out_banks.push(calyx_ast::Port {
+                        cell: memory.name,
+                        port: "read_data".to_string(),
                     });
-                    cond_lt_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: cond_lt.name.clone(),
-                            port: "right".to_string(),
-                        },
-                        src: calyx_ast::Src::Int {
-                            value: size as isize,
-                            width: ADDRESS_WIDTH,
-                        },
+                }
+            } else {
+                for _ in &results[0].1 {
+                    let memory = self.new_memory(width, size)?;
+                    out_memories.push(calyx_ast::Port {
+                        cell: memory.name,
+                        port: "read_data".to_string(),
                     });
-                    let cond_lt_group_name = cond_lt_group.name.clone();
-                    self.get_current_func()?
-                        .wires
-                        .groups
-                        .push(cond_lt_group.clone());
+                }
+            }
+            let mut writes = vec![vec![]; results[0].1.len()];
+            for (lane, (index_src, lane_results)) in results.into_iter().enumerate() {
+                if let (true, Some((bank_address, _, _))) = (banked_out, &bank_index) {
+                    let write_group = self.write_memory(
+                        &out_banks[lane],
+                        bank_address.clone(),
+                        lane_results[0].clone(),
+                    )?;
+                    writes[0].push(calyx_ast::Control::GroupName(write_group));
+                    continue;
+                }
+                for (j, (memory, result)) in out_memories.iter().zip(lane_results).enumerate() {
+                    let write_group = self.write_memory(memory, index_src.clone(), result)?;
+                    writes[j].push(calyx_ast::Control::GroupName(write_group));
+                }
+            }
+
+            // The first `count` lanes of an iteration
+            let iteration = |count: usize| {
+                // Lanes access their own bank in par and a shared memory in seq
+                let access = |groups: &[Vec<calyx_ast::Control>], banked: &[bool]| {
+                    calyx_ast::Control::Par(
+                        groups
+                            .iter()
+                            .zip(banked)
+                            .filter(|(groups, _)| !groups.is_empty())
+                            .map(|(groups, banked)| {
+                                let groups = groups[..count].to_vec();
+                                if *banked {
+                                    calyx_ast::Control::Par(groups)
+                                } else {
+                                    calyx_ast::Control::Seq(groups)
+                                }
+                            })
+                            .collect(),
+                    )
+                };
+                let banked_in: Vec<bool> = in_banks.iter().map(Option::is_some).collect();
+                let mut body = vec![access(&reads, &banked_in)];
+                let lane_bodies: Vec<_> = bodies[..count]
+                    .iter()
+                    .filter(|body| !body.is_empty())
+                    .cloned()
+                    .collect();
+                if shares_state {
+                    body.extend(lane_bodies);
+                } else if !lane_bodies.is_empty() {
+                    body.push(calyx_ast::Control::Par(lane_bodies));
+                }
+                body.push(access(&writes, &vec![banked_out; writes.len()]));
+                body
+            };
+            let remainder = size % lanes;
+            let mut body = iteration(lanes);
+            let mut seq_vec = vec![];
+            if let Some((_, init, incr)) = &bank_index {
+                seq_vec.push(calyx_ast::Control::GroupName(init.clone()));
+                body.push(calyx_ast::Control::GroupName(incr.clone()));
+            }
+            seq_vec.push(counter_loop.control(body));
+            // The loop leaves the counter at the first remaining element and the bank
+            // address at its address
+            if remainder > 0 {
+                seq_vec.extend(iteration(remainder));
+            }
+
+            if let Some(dest) = dest {
+                if banked_out {
+                    self.env.insert(dest.clone(), out_banks[0].clone().into());
+                    self.banked.insert(dest, out_banks);
+                } else {
+                    let mut out_ports: Vec<calyx_ast::Src> =
+                        out_memories.into_iter().map(Into::into).collect();
+                    if out_ports.len() == 1 {
+                        self.env.insert(dest, out_ports.remove(0));
+                    } else {
+                        self.tuple_env.insert(dest, out_ports);
+                    }
+                }
+            }
+            Ok(calyx_ast::Control::Seq(seq_vec))
+        }))
+    }
+
+    // Adds `left + right` through static wires, for an index that is always needed
+    fn new_comb_add(&mut self, left: calyx_ast::Src, right: usize) -> Result<calyx_ast::Port> {
+        let add = calyx_ast::Cell {
+            name: self.fresh_name(),
+            is_external: false,
+            is_ref: false,
+            circuit: calyx_ast::Circuit::StdAdd {
+                width: ADDRESS_WIDTH,
+            },
+        };
+        let add_port = calyx_ast::Port {
+            cell: add.name.clone(),
+            port: "".to_string(),
+        };
+        let component = self.get_current_func()?;
+        component.cells.push(add);
+        component.wires.static_wires.push(calyx_ast::Wire {
+            dest: add_port.port("left"),
+            src: left,
+        });
+        component.wires.static_wires.push(calyx_ast::Wire {
+            dest: add_port.port("right"),
+            src: calyx_ast::Src::Int {
+                value: right as isize,
+                width: ADDRESS_WIDTH,
+            },
+        });
+        Ok(add_port)
+    }
+
+    // Combines the elements pairwise in par stages, so that the lambda is applied
+    // log2(size) times one after the other instead of size times. The elements are first
+    // loaded into registers: an array banked by an unrolled map is read from all of its
+    // banks in par, taking size / K steps, while any other memory has a single port and
+    // is read one element at a time.
+    fn convert_tree_reduce<'a: 'b, 'b>(
+        &'a mut self,
+        array: &'a ast::Ident,
+        init_value: &'a ast::Ident,
+        acm: &'a ast::Ident,
+        arg: &'a ast::Ident,
+        expr: &'a ast::ANormalExpr,
+    ) -> Result<ConvertFn<'b>> {
+        let Some(Type::Array(content_ty, size)) = &self.type_env.get(array) else {
+            return Err(anyhow::anyhow!("Expected an array type for reduction"));
+        };
+        let size = *size;
+        let width = element_width("reduction", array, content_ty)?;
+        let banks = self.banked.get(array).cloned();
+        let calyx_ast::Src::Port(array) = self.find_src_by_var(array)? else {
+            return Err(anyhow::anyhow!("Expected a port for array variable"));
+        };
+        let init_value = self.find_src_by_var(init_value)?;
+        // A copy of the lambda for each of the size - 1 combinations in the tree and
+        // one to fold in the initial value
+        let params = [acm.clone(), arg.clone()];
+        let copies: Vec<_> = (0..size)
+            .map(|node| unroll::lane_copy(&params, &None, expr, node))
+            .collect();
+        Ok(Box::new(move |dest: Option<String>| {
+            let mut seq_vec = vec![];
+            let mut values = vec![];
+            // Element i is at address i / K of bank i % K, or at i of the whole array
+            let memories = banks.unwrap_or_else(|| vec![array.clone()]);
+            let mut reads = vec![vec![]; memories.len()];
+            for i in 0..size {
+                let bank = i % memories.len();
+                let index = calyx_ast::Src::Int {
+                    value: (i / memories.len()) as isize,
+                    width: ADDRESS_WIDTH,
+                };
+                let (value, read_group) = self.read_memory(&memories[bank], index, width)?;
+                values.push(value);
+                reads[bank].push(calyx_ast::Control::GroupName(read_group));
+            }
+            reads.retain(|reads| !reads.is_empty());
+            if !reads.is_empty() {
+                seq_vec.push(calyx_ast::Control::Par(
+                    reads.into_iter().map(calyx_ast::Control::Seq).collect(),
+                ));
+            }
+
+            let mut copies = copies.iter();
+            while values.len() > 1 {
+                let mut stage = vec![];
+                let mut next_values = vec![];
+                for (lane, pair) in values.chunks(2).enumerate() {
+                    // An odd element out moves on to the next stage
+                    let [left, right] = pair else {
+                        next_values.push(pair[0].clone());
+                        continue;
+                    };
+                    let (params, _, body) = copies.next().unwrap();
+                    let (control, value) = self.convert_combination(
+                        params,
+                        body,
+                        [left.clone(), right.clone()],
+                        width,
+                        lane,
+                    )?;
+                    stage.push(control);
+                    next_values.push(value);
+                }
+                seq_vec.push(calyx_ast::Control::Par(stage));
+                values = next_values;
+            }
+
+            // An empty array reduces to the initial value
+            let result = match values.pop() {
+                Some(value) => {
+                    let (params, _, body) = copies.next().unwrap();
+                    let (control, result) =
+                        self.convert_combination(params, body, [init_value, value], width, 0)?;
+                    seq_vec.push(control);
+                    result
+                }
+                None => init_value,
+            };
+            if let Some(dest) = dest {
+                self.env.insert(dest, result);
+            }
+            Ok(calyx_ast::Control::Seq(seq_vec))
+        }))
+    }
+
+    // Applies a copy of a reduction lambda to two values and stores the result in a
+    // register, returning the control and the register output
+    fn convert_combination(
+        &mut self,
+        params: &[ast::Ident],
+        body: &ast::ANormalExpr,
+        values: [calyx_ast::Src; 2],
+        width: usize,
+        lane: usize,
+    ) -> Result<(calyx_ast::Control, calyx_ast::Src)> {
+        for (param, value) in params.iter().zip(values) {
+            self.env.insert(param.clone(), value);
+            self.type_env.insert(param.clone(), Type::I(width));
+        }
+        let result_var = self.fresh_name();
+        self.type_env.insert(result_var.clone(), Type::I(width));
+        self.lane = lane;
+        let body_control = self.convert_expr(body, Some(result_var.clone()));
+        self.lane = 0;
+        let body_control = body_control?;
+        let result_reg = self.new_register(width)?;
+        let result = self.find_src_by_var(&result_var)?;
+        let write_group = self.new_register_write(&result_reg, result)?;
+        let mut controls = vec![body_control, calyx_ast::Control::GroupName(write_group)];
+        controls.retain(|control| !control.is_empty());
+        Ok((
+            calyx_ast::Control::Seq(controls),
+            result_reg.port("out").into(),
+        ))
+    }
+
+    // Lowers reduce, and scan when `is_scan` is set: the accumulator after each step is
+    // also written into an output memory of the same length as the input.
+    fn convert_fold<'a: 'b, 'b>(
+        &'a mut self,
+        array: &'a ast::Ident,
+        init_value: &'a ast::Ident,
+        acm: &'a ast::Ident,
+        arg: &'a ast::Ident,
+        expr: &'a ast::ANormalExpr,
+        is_scan: bool,
+    ) -> Result<ConvertFn<'b>> {
+        let Some(Type::Array(content_ty, size)) = &self.type_env.get(array) else {
+            return Err(anyhow::anyhow!("Expected an array type for reduction"));
+        };
+        let size = *size;
+        let width = element_width("reduction", array, content_ty)?;
+        let calyx_ast::Src::Port(array) = self.find_src_by_var(array)? else {
+            return Err(anyhow::anyhow!("Expected a port for array variable"));
+        };
+        let init_value = self.find_src_by_var(init_value)?;
+        self.type_env.insert(acm.clone(), Type::I(width));
+        self.type_env.insert(arg.clone(), Type::I(width));
+        Ok(Box::new(move |dest: Option<String>| {
+            let mut seq_vec = vec![];
+            let lane = self.lane;
+            let add_cell = self.get_current_func()?.get_add_cell(width, lane);
+            let acm_reg = calyx_ast::Cell {
+                name: self.fresh_name(),
+                is_external: false,
+                is_ref: false,
+                circuit: calyx_ast::Circuit::StdReg { width },
+            };
+            self.env.insert(
+                acm.clone(),
+                calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: acm_reg.name.clone(),
+                    port: "out".to_string(),
+                }),
+            );
+            let scan_vec = if is_scan {
+                let scan_vec = calyx_ast::Cell {
+                    name: self.fresh_name(),
+                    is_external: false,
+                    is_ref: false,
+                    circuit: calyx_ast::Circuit::CombMemD1 {
+                        data_width: width,
+                        len: size,
+                        address_width: ADDRESS_WIDTH,
+                    },
+                };
+                self.get_current_func()?.cells.push(scan_vec.clone());
+                Some(scan_vec)
+            } else {
+                None
+            };
+            if let Some(dest) = dest {
+                let result_cell = scan_vec.as_ref().unwrap_or(&acm_reg);
+                self.env.insert(
+                    dest.clone(),
+                    calyx_ast::Src::Port(calyx_ast::Port {
+                        cell: result_cell.name.clone(),
+                        port: if is_scan { "read_data" } else { "out" }.to_string(),
+                    }),
+                );
+            }
+            let count_reg = calyx_ast::Cell {
+                name: self.fresh_name(),
+                is_external: false,
+                is_ref: false,
+                circuit: calyx_ast::Circuit::StdReg {
+                    width: ADDRESS_WIDTH,
+                },
+            };
+            let arg_reg = calyx_ast::Cell {
+                name: self.fresh_name(),
+                is_external: false,
+                is_ref: false,
+                circuit: calyx_ast::Circuit::StdReg { width },
+            };
+            self.env.insert(
+                arg.clone(),
+                calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: arg_reg.name.clone(),
+                    port: "out".to_string(),
+                }),
+            );
+            let cond_lt = calyx_ast::Cell {
+                name: self.fresh_name(),
+                is_external: false,
+                is_ref: false,
+                circuit: calyx_ast::Circuit::StdLt {
+                    width: ADDRESS_WIDTH,
+                },
+            };
+            let mut init_count_reg_group = self.new_group();
+            init_count_reg_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "in".to_string(),
+                },
+                src: calyx_ast::Src::Int {
+                    value: 0,
+                    width: ADDRESS_WIDTH,
+                },
+            });
+            init_count_reg_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "write_en".to_string(),
+                },
+                src: calyx_ast::Src::Int { value: 1, width: 1 },
+            });
+            init_count_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                cell: count_reg.name.clone(),
+                port: "done".to_string(),
+            }));
+
+            let mut init_acm_reg_group = self.new_group();
+            init_acm_reg_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: acm_reg.name.clone(),
+                    port: "in".to_string(),
+                },
+                src: init_value.clone(),
+            });
+            init_acm_reg_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: acm_reg.name.clone(),
+                    port: "write_en".to_string(),
+                },
+                src: calyx_ast::Src::Int { value: 1, width: 1 },
+            });
+            init_acm_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                cell: acm_reg.name.clone(),
+                port: "done".to_string(),
+            }));
+            let init_control = calyx_ast::Control::Par(vec![
+                calyx_ast::Control::GroupName(init_count_reg_group.name.clone()),
+                calyx_ast::Control::GroupName(init_acm_reg_group.name.clone()),
+            ]);
+            self.get_current_func()?
+                .wires
+                .groups
+                .push(init_count_reg_group);
+            self.get_current_func()?
+                .wires
+                .groups
+                .push(init_acm_reg_group);
+
+            seq_vec.push(init_control);
+
+            let mut cond_lt_group = self.new_group();
+            cond_lt_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: cond_lt.name.clone(),
+                    port: "left".to_string(),
+                },
+                src: calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "out".to_string(),
+                }),
+            });
+            cond_lt_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: cond_lt.name.clone(),
+                    port: "right".to_string(),
+                },
+                src: calyx_ast::Src::Int {
+                    value: size as isize,
+                    width: ADDRESS_WIDTH,
+                },
+            });
+            let cond_lt_group_name = cond_lt_group.name.clone();
+            self.get_current_func()?
+                .wires
+                .groups
+                .push(cond_lt_group.clone());
+
+            let mut read_array_group = self.new_group();
+            read_array_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: array.cell.clone(),
+                    port: "addr0".to_string(),
+                },
+                src: calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "out".to_string(),
+                }),
+            });
+            read_array_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: arg_reg.name.clone(),
+                    port: "in".to_string(),
+                },
+                src: calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: array.cell.clone(),
+                    port: "read_data".to_string(),
+                }),
+            });
+            read_array_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: arg_reg.name.clone(),
+                    port: "write_en".to_string(),
+                },
+                src: calyx_ast::Src::Int { value: 1, width: 1 },
+            });
+            read_array_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                cell: arg_reg.name.clone(),
+                port: "done".to_string(),
+            }));
 
-                    let mut read_array_group = self.new_group();
-                    read_array_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: array.cell.clone(),
-                            port: "addr0".to_string(),
-                        },
-                        src: calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "out".to_string(),
-                        }),
-                    });
-                    read_array_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: arg_reg.name.clone(),
-                            port: "in".to_string(),
-                        },
-                        src: calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: array.cell.clone(),
-                            port: "read_data".to_string(),
-                        }),
-                    });
-                    read_array_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: arg_reg.name.clone(),
-                            port: "write_en".to_string(),
-                        },
-                        src: calyx_ast::Src::Int { value: 1, width: 1 },
-                    });
-                    read_array_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                        cell: arg_reg.name.clone(),
-                        port: "done".to_string(),
-                    }));
+            let result_var = self.fresh_name();
+            self.type_env.insert(result_var.clone(), Type::I(width));
 
-                    let result_var = self.fresh_name();
-                    self.type_env.insert(result_var.clone(), Type::I(width));
+            let body_group = self.convert_expr(expr, Some(result_var.clone()))?;
 
-                    let body_group = self.convert_expr(expr, Some(result_var.clone()))?;
+            let result = self.env.get(&result_var).cloned().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "internal error: Expected result variable {} to be in environment",
+                    result_var
+                )
+            })?;
 
-                    let result = self.env.get(&result_var).cloned().ok_or_else(|| {
-                        anyhow::anyhow!(
-                            "internal error: Expected result variable {} to be in environment",
-                            result_var
-                        )
-                    })?;
+            let mut result_reg_group = self.new_group();
+            result_reg_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: acm_reg.name.clone(),
+                    port: "in".to_string(),
+                },
+                src: result,
+            });
+            result_reg_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: acm_reg.name.clone(),
+                    port: "write_en".to_string(),
+                },
+                src: calyx_ast::Src::Int { value: 1, width: 1 },
+            });
+            result_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                cell: acm_reg.name.clone(),
+                port: "done".to_string(),
+            }));
 
-                    let mut result_reg_group = self.new_group();
-                    result_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: acm_reg.name.clone(),
-                            port: "in".to_string(),
-                        },
-                        src: result,
-                    });
-                    result_reg_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: acm_reg.name.clone(),
-                            port: "write_en".to_string(),
-                        },
-                        src: calyx_ast::Src::Int { value: 1, width: 1 },
-                    });
-                    result_reg_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+            let scan_write_group = if let Some(scan_vec) = &scan_vec {
+                let mut scan_write_group = self.new_group();
+                scan_write_group.wires.push(calyx_ast::Wire {
+                    dest: calyx_ast::Port {
+                        cell: scan_vec.name.clone(),
+                        port: "addr0".to_string(),
+                    },
+                    src: calyx_ast::Src::Port(calyx_ast::Port {
+                        cell: count_reg.name.clone(),
+                        port: "out".to_string(),
+                    }),
+                });
+                scan_write_group.wires.push(calyx_ast::Wire {
+                    dest: calyx_ast::Port {
+                        cell: scan_vec.name.clone(),
+                        port: "write_data".to_string(),
+                    },
+                    src: calyx_ast::Src::Port(calyx_ast::Port {
                         cell: acm_reg.name.clone(),
-                        port: "done".to_string(),
-                    }));
+                        port: "out".to_string(),
+                    }),
+                });
+                scan_write_group.wires.push(calyx_ast::Wire {
+                    dest: calyx_ast::Port {
+                        cell: scan_vec.name.clone(),
+                        port: "write_en".to_string(),
+                    },
+                    src: calyx_ast::Src::Int { value: 1, width: 1 },
+                });
+                scan_write_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: scan_vec.name.clone(),
+                    port: "done".to_string(),
+                }));
+                Some(scan_write_group)
+            } else {
+                None
+            };
 
-                    let mut inc_count_group = self.new_group();
-                    inc_count_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: add_cell.name.clone(),
-                            port: "left".to_string(),
-                        },
-                        src: calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "out".to_string(),
-                        }),
-                    });
-                    inc_count_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: add_cell.name.clone(),
-                            port: "right".to_string(),
-                        },
-                        src: calyx_ast::Src::Int {
-                            value: 1,
-                            width: ADDRESS_WIDTH,
-                        },
-                    });
-                    inc_count_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "in".to_string(),
-                        },
-                        src: calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: add_cell.name.clone(),
-                            port: "out".to_string(),
-                        }),
-                    });
-                    inc_count_group.wires.push(calyx_ast::Wire {
-                        dest: calyx_ast::Port {
-                            cell: count_reg.name.clone(),
-                            port: "write_en".to_string(),
-                        },
-                        src: calyx_ast::Src::Int { value: 1, width: 1 },
-                    });
-                    inc_count_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                        cell: count_reg.name.clone(),
-                        port: "done".to_string(),
-                    }));
+            let mut inc_count_group = self.new_group();
+            inc_count_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: add_cell.name.clone(),
+                    port: "left".to_string(),
+                },
+                src: calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "out".to_string(),
+                }),
+            });
+            inc_count_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: add_cell.name.clone(),
+                    port: "right".to_string(),
+                },
+                src: calyx_ast::Src::Int {
+                    value: 1,
+                    width: ADDRESS_WIDTH,
+                },
+            });
+            inc_count_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "in".to_string(),
+                },
+                src: calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: add_cell.name.clone(),
+                    port: "out".to_string(),
+                }),
+            });
+            inc_count_group.wires.push(calyx_ast::Wire {
+                dest: calyx_ast::Port {
+                    cell: count_reg.name.clone(),
+                    port: "write_en".to_string(),
+                },
+                src: calyx_ast::Src::Int { value: 1, width: 1 },
+            });
+            inc_count_group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
+                cell: count_reg.name.clone(),
+                port: "done".to_string(),
+            }));
 
-                    let mut while_body = vec![];
-                    while_body.push(calyx_ast::Control::GroupName(read_array_group.name.clone()));
-                    if !body_group.is_empty() {
-                        while_body.push(body_group);
-                    }
-                    while_body.push(calyx_ast::Control::GroupName(result_reg_group.name.clone()));
-                    while_body.push(calyx_ast::Control::GroupName(inc_count_group.name.clone()));
+            let mut while_body = vec![];
+            while_body.push(calyx_ast::Control::GroupName(read_array_group.name.clone()));
+            if !body_group.is_empty() {
+                while_body.push(body_group);
+            }
+            while_body.push(calyx_ast::Control::GroupName(result_reg_group.name.clone()));
+            if let Some(scan_write_group) = &scan_write_group {
+                while_body.push(calyx_ast::Control::GroupName(scan_write_group.name.clone()));
+            }
+            while_body.push(calyx_ast::Control::GroupName(inc_count_group.name.clone()));
 
-                    seq_vec.push(calyx_ast::Control::While {
-                        condition: calyx_ast::Port {
-                            cell: cond_lt.name.clone(),
-                            port: "out".to_string(),
-                        },
-                        with: Some(cond_lt_group_name),
-                        body: while_body,
-                    });
+            seq_vec.push(calyx_ast::Control::While {
+                condition: calyx_ast::Port {
+                    cell: cond_lt.name.clone(),
+                    port: "out".to_string(),
+                },
+                with: Some(cond_lt_group_name),
+                body: while_body,
+            });
 
-                    self.get_current_func()?.wires.groups.push(read_array_group);
-                    self.get_current_func()?.wires.groups.push(result_reg_group);
-                    self.get_current_func()?.wires.groups.push(inc_count_group);
+            self.get_current_func()?.wires.groups.push(read_array_group);
+            self.get_current_func()?.wires.groups.push(result_reg_group);
+            if let Some(scan_write_group) = scan_write_group {
+                self.get_current_func()?.wires.groups.push(scan_write_group);
+            }
+            self.get_current_func()?.wires.groups.push(inc_count_group);
 
-                    self.get_current_func()?.cells.push(acm_reg);
-                    self.get_current_func()?.cells.push(count_reg);
-                    self.get_current_func()?.cells.push(arg_reg);
-                    self.get_current_func()?.cells.push(cond_lt);
+            self.get_current_func()?.cells.push(acm_reg);
+            self.get_current_func()?.cells.push(count_reg);
+            self.get_current_func()?.cells.push(arg_reg);
+            self.get_current_func()?.cells.push(cond_lt);
 
-                    Ok(calyx_ast::Control::Seq(seq_vec))
-                }))
+            Ok(calyx_ast::Control::Seq(seq_vec))
+        }))
+    }
+
+    fn convert_extern_component(&mut self, decl: &ast::ExternComponent) -> Result<()> {
+        let mut port_names = HashSet::new();
+        for (port, ty) in decl.inputs.iter().chain(&decl.outputs) {
+            if !matches!(ty, Type::I(_)) {
+                return Err(anyhow::anyhow!(
+                    "Port {} of extern component {} must have an integer type, but got {:?}",
+                    port,
+                    decl.name,
+                    ty
+                ));
             }
-            ast::ANormalBaseExpr::Call(fun_name, args) => {
-                let args: Vec<calyx_ast::Src> = args
-                    .iter()
-                    .map(|arg| self.find_src_by_var(arg))
-                    .collect::<Result<_>>()?;
-                let fun_name = fun_name.to_string();
-                Ok(Box::new(move |dest: Option<String>| {
-                    let (params, result_ty) = self
-                        .fun_type_env
-                        .get(&fun_name)
-                        .ok_or_else(|| {
-                            anyhow::anyhow!(
-                                "Function {} not found in function environment",
-                                fun_name
-                            )
-                        })?
-                        .clone();
-                    let is_contain_array =
-                        params.iter().any(|(_, ty)| matches!(ty, Type::Array(_, _)))
-                            || result_ty
-                                .as_ref()
-                                .map_or(false, |ty| matches!(ty, Type::Array(_, _)));
-                    if is_contain_array {
-                        todo!()
-                    } else {
-                        let fun = self.fresh_name();
-                        let fun_cell = calyx_ast::Cell {
-                            name: fun,
-                            is_external: false,
-                            is_ref: false,
-                            circuit: calyx_ast::Circuit::FunInstance {
-                                name: fun_name.clone(),
-                            },
-                        };
-                        self.get_current_func()?.cells.push(fun_cell.clone());
-                        let mut group = self.new_group();
-                        for (i, (param_name, _)) in params.iter().enumerate() {
-                            group.wires.push(calyx_ast::Wire {
-                                dest: calyx_ast::Port {
-                                    cell: fun_cell.name.clone(),
-                                    port: param_name.clone(),
-                                },
-                                src: args[i].clone(),
-                            });
-                        }
-                        group.wires.push(calyx_ast::Wire {
-                            dest: calyx_ast::Port {
-                                cell: fun_cell.name.clone(),
-                                port: "go".to_string(),
-                            },
-                            src: calyx_ast::Src::Int { value: 1, width: 1 },
-                        });
-                        if let Some(dest) = dest {
-                            self.env.insert(
-                                dest.clone(),
-                                calyx_ast::Src::Port(calyx_ast::Port {
-                                    cell: fun_cell.name.clone(),
-                                    port: Converter::FUN_OUT_NAME.to_string(),
-                                }),
-                            );
-                        }
-                        group.done = Some(calyx_ast::Src::Port(calyx_ast::Port {
-                            cell: fun_cell.name.clone(),
-                            port: "done".to_string(),
-                        }));
-                        let group_name = group.name.clone();
-                        self.get_current_func()?.wires.groups.push(group);
-                        Ok(calyx_ast::Control::GroupName(group_name))
-                    }
-                }))
+            // Every Calyx component already has these
+            if ["go", "done", "clk", "reset"].contains(&port.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "Port {} of extern component {} is reserved",
+                    port,
+                    decl.name
+                ));
             }
-            ast::ANormalBaseExpr::ArraySet(array, index, value) => {
-                let calyx_ast::Src::Port(array) = self.find_src_by_var(array)? else {
-                    return Err(anyhow::anyhow!("Expected a port for array variable"));
-                };
-                let index = self.find_src_by_var(index)?;
-                let value = self.find_src_by_var(value)?;
-
-                Ok(Box::new(move |dest: Option<String>| {
-                    let mut group = self.new_group();
-                    group.wires.push(calyx_ast::Wire {
-                        dest: array.port("addr0"),
-                        src: index.clone(),
-                    });
-                    group.wires.push(calyx_ast::Wire {
-                        dest: array.port("write_data"),
-                        src: value.clone(),
-                    });
-                    group.wires.push(calyx_ast::Wire {
-                        dest: array.port("write_en"),
-                        src: calyx_ast::Src::Int { value: 1, width: 1 },
-                    });
-                    group.done = Some(array.port("done").into());
-                    let group_name = group.name.clone();
-                    self.get_current_func()?.wires.groups.push(group);
-                    if let Some(dest) = dest {
-                        self.env.insert(dest.clone(), value);
-                    }
-                    Ok(calyx_ast::Control::GroupName(group_name))
-                }))
+            if !port_names.insert(port) {
+                return Err(anyhow::anyhow!(
+                    "Port {} of extern component {} is declared twice",
+                    port,
+                    decl.name
+                ));
             }
         }
+        if !self.program.import_names.contains(&decl.path) {
+            self.program.import_names.push(decl.path.clone());
+        }
+        self.fun_type_env
+            .insert(decl.name.clone(), (decl.inputs.clone(), decl.return_type()));
+        self.extern_outputs.insert(
+            decl.name.clone(),
+            decl.outputs.iter().map(|(port, _)| port.clone()).collect(),
+        );
+        Ok(())
+    }
+
+    fn convert_struct_decl(&mut self, decl: &ast::StructDecl) -> Result<()> {
+        let fields = decl
+            .fields
+            .iter()
+            .map(|(field, ty)| match ty {
+                Type::I(width) => Ok((field.clone(), *width)),
+                _ => Err(anyhow::anyhow!(
+                    "Field {} of struct {} must have an integer type, but got {:?}",
+                    field,
+                    decl.name,
+                    ty
+                )),
+            })
+            .collect::<Result<_>>()?;
+        self.struct_env.insert(decl.name.clone(), fields);
+        Ok(())
     }
 
     fn convert_external_decl(&mut self, decl: &ast::ExternalDecl) -> Result<()> {
+        if let ast::Type::I(width) = &decl.ty {
+            // A scalar is an external register, read and assigned like a `var`
+            self.program.main.cells.push(calyx_ast::Cell {
+                name: decl.name.clone(),
+                is_external: true,
+                is_ref: false,
+                circuit: calyx_ast::Circuit::StdReg { width: *width },
+            });
+            self.env.insert(
+                decl.name.clone(),
+                calyx_ast::Port {
+                    cell: decl.name.clone(),
+                    port: "out".to_string(),
+                }
+                .into(),
+            );
+            self.type_env.insert(decl.name.clone(), decl.ty.clone());
+            self.mutable_vars.insert(decl.name.clone());
+            return Ok(());
+        }
         let ast::Type::Array(ty, size) = &decl.ty else {
             return Err(anyhow::anyhow!("Unsupported type in external declaration"));
         };
+        if let ast::Type::Struct(struct_name) = &**ty {
+            // An array of structs is one external memory per field
+            let mut items = vec![];
+            for (field, width) in self.struct_fields(struct_name)? {
+                let field_name = format!("{}_{}", decl.name, field);
+                self.program.main.cells.push(calyx_ast::Cell {
+                    name: field_name.clone(),
+                    is_external: true,
+                    is_ref: false,
+                    circuit: calyx_ast::Circuit::CombMemD1 {
+                        data_width: width,
+                        len: *size,
+                        address_width: ADDRESS_WIDTH,
+                    },
+                });
+                items.push(calyx_ast::Src::Port(calyx_ast::Port {
+                    cell: field_name,
+                    port: "read_data".to_string(),
+                }));
+            }
+            self.tuple_env.insert(decl.name.clone(), items);
+            self.type_env.insert(decl.name.clone(), decl.ty.clone());
+            return Ok(());
+        }
         let ast::Type::I(width) = &**ty else {
             return Err(anyhow::anyhow!("Unsupported type in external declaration"));
         };
@@ -1176,3 +2884,141 @@ impl Converter {
         Ok(())
     }
 }
+
+// Reductions over more elements stay a sequential loop under `--tree-reduce`, as the
+// tree takes a register and a copy of the lambda per element
+pub const MAX_TREE_REDUCE_SIZE: usize = 64;
+
+pub fn is_tree_reducible(size: usize, acm: &str, arg: &str, body: &ast::ANormalExpr) -> bool {
+    size <= MAX_TREE_REDUCE_SIZE && is_associative(acm, arg, body)
+}
+
+// Lambda parameters of maps and reductions are registers, so the elements have to be
+// integers; an array of structs is mapped over the arrays of its fields instead
+fn element_width(construct: &str, array: &str, content_ty: &Type) -> Result<usize> {
+    match content_ty {
+        Type::I(width) => Ok(*width),
+        Type::Struct(struct_name) => Err(anyhow::anyhow!(
+            "A {} over {}, an array of struct {}, is not supported; use arrays of its fields",
+            construct,
+            array,
+            struct_name
+        )),
+        _ => Err(anyhow::anyhow!(
+            "Expected an integer type for {}",
+            construct
+        )),
+    }
+}
+
+// Whether `(acm, arg) => body` is `+`, `*`, `&`, `|`, `^`, min or max of its
+// parameters, whose result does not depend on how the elements are grouped
+pub fn is_associative(acm: &str, arg: &str, body: &ast::ANormalExpr) -> bool {
+    let operands =
+        |left: &str, right: &str| (left == acm && right == arg) || (left == arg && right == acm);
+    let returned = |expr: &ast::ANormalExpr| match expr {
+        ast::Expr_(lets, ast::ANormalBaseExpr::Var(name)) if lets.is_empty() => Some(name.clone()),
+        _ => None,
+    };
+    let ast::Expr_(lets, base) = body;
+    // The result may be bound to a name that is then returned
+    let (lets, base) = match (lets.as_slice(), base) {
+        ([lets @ .., ast::ANormalLet::BindLet(last)], ast::ANormalBaseExpr::Var(name))
+            if last.name == *name =>
+        {
+            (lets, &last.value)
+        }
+        _ => (lets.as_slice(), base),
+    };
+    match (lets, base) {
+        ([], ast::ANormalBaseExpr::Add(left, right))
+        | ([], ast::ANormalBaseExpr::Mul(left, right))
+        | (
+            [],
+            ast::ANormalBaseExpr::BinOp(
+                ast::BinOp::And | ast::BinOp::Or | ast::BinOp::Xor,
+                left,
+                right,
+            ),
+        ) => operands(left, right),
+        // `if acm < arg then acm else arg` and the other ways to write min and max
+        (
+            [ast::ANormalLet::BindLet(cond_let)],
+            ast::ANormalBaseExpr::If(cond, then_branch, else_branch),
+        ) if cond_let.name == *cond => {
+            let ast::ANormalBaseExpr::BinOp(
+                ast::BinOp::Lt | ast::BinOp::Gt | ast::BinOp::Le | ast::BinOp::Ge,
+                left,
+                right,
+            ) = &cond_let.value
+            else {
+                return false;
+            };
+            match (returned(then_branch), returned(else_branch)) {
+                (Some(then_value), Some(else_value)) => {
+                    operands(left, right) && operands(&then_value, &else_value)
+                }
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+// Fields of the names that are used only to access fields
+fn fields_read(body: &ast::ANormalExpr) -> HashMap<String, HashSet<String>> {
+    let mut accesses = HashMap::new();
+    field_accesses(body, &mut accesses);
+    let mut uses = HashMap::new();
+    fusion::count_uses(body, &mut uses);
+    accesses
+        .into_iter()
+        .filter(|(name, fields)| uses.get(name) == Some(&fields.len()))
+        .map(|(name, fields)| (name, fields.into_iter().collect()))
+        .collect()
+}
+
+fn field_accesses(expr: &ast::ANormalExpr, accesses: &mut HashMap<String, Vec<String>>) {
+    let ast::Expr_(lets, base) = expr;
+    for let_binding in lets {
+        match let_binding {
+            ast::ANormalLet::BindLet(bind_let) | ast::ANormalLet::VarLet(bind_let) => {
+                base_expr_field_accesses(&bind_let.value, accesses)
+            }
+            ast::ANormalLet::NoBindLet(no_bind_let) => {
+                base_expr_field_accesses(&no_bind_let.value, accesses)
+            }
+            ast::ANormalLet::TupleLet(tuple_let) => {
+                base_expr_field_accesses(&tuple_let.value, accesses)
+            }
+        }
+    }
+    base_expr_field_accesses(base, accesses);
+}
+
+fn base_expr_field_accesses(
+    expr: &ast::ANormalBaseExpr,
+    accesses: &mut HashMap<String, Vec<String>>,
+) {
+    match expr {
+        ast::ANormalBaseExpr::Field(value, field) => {
+            accesses
+                .entry(value.clone())
+                .or_default()
+                .push(field.clone());
+        }
+        ast::ANormalBaseExpr::Map(_, _, _, body)
+        | ast::ANormalBaseExpr::Reduce(_, _, _, _, body)
+        | ast::ANormalBaseExpr::Scan(_, _, _, _, body)
+        | ast::ANormalBaseExpr::For(_, _, _, body) => field_accesses(body, accesses),
+        ast::ANormalBaseExpr::While(cond, body) => {
+            field_accesses(cond, accesses);
+            field_accesses(body, accesses);
+        }
+        ast::ANormalBaseExpr::If(_, then_branch, else_branch) => {
+            field_accesses(then_branch, accesses);
+            field_accesses(else_branch, accesses);
+        }
+        _ => {}
+    }
+}
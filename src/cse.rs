@@ -0,0 +1,331 @@
+use crate::ast::*;
+use std::collections::{HashMap, HashSet};
+
+// Binds a repeated computation to the variable that first computed it, so that it does
+// not become a second adder or multiplier:
+//
+//   let a = x * y in let b = x * y in let c = a + b
+//   ~> let a = x * y in let b = a in let c = a + b
+//
+// A computation can be reused in its scope and the scopes nested in it. Array reads are
+// never reused, as the memory may be written in between, and a computation that reads a
+// `var` or an external register is forgotten once it is assigned.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Int(i32, usize),
+    Bool(bool),
+    Add(Ident, Ident),
+    Mul(Ident, Ident),
+    BinOp(BinOp, Ident, Ident),
+    Field(Ident, Ident),
+}
+
+struct Cse {
+    // Computations available in the current scope, with the variable holding each
+    available: HashMap<Key, Ident>,
+    // Variables bound to another variable, replaced by it in the keys
+    aliases: HashMap<Ident, Ident>,
+    // Variables whose value changes over time
+    mutable: HashSet<Ident>,
+    // External registers
+    registers: HashSet<Ident>,
+}
+
+impl Cse {
+    fn expr(&mut self, expr: ANormalExpr) -> ANormalExpr {
+        let available = self.available.clone();
+        let Expr_(lets, base) = expr;
+        let lets = lets
+            .into_iter()
+            .map(|let_binding| self.let_binding(let_binding))
+            .collect();
+        let base = self.base_expr(base);
+        self.available = available;
+        Expr_(lets, base)
+    }
+
+    fn let_binding(&mut self, let_binding: ANormalLet) -> ANormalLet {
+        let mut assigned = HashSet::new();
+        self.let_assigned(&let_binding, &mut assigned);
+        self.forget(&assigned);
+        match let_binding {
+            ANormalLet::BindLet(bind_let) => {
+                let mut value = self.base_expr(bind_let.value);
+                if let Some(key) = self.key(&value, &bind_let.ty) {
+                    match self.available.get(&key) {
+                        Some(first) => value = ANormalBaseExpr::Var(first.clone()),
+                        None => {
+                            self.available.insert(key, bind_let.name.clone());
+                        }
+                    }
+                }
+                if let ANormalBaseExpr::Var(name) = &value
+                    && !self.mutable.contains(name)
+                {
+                    let name = self.resolve(name);
+                    self.aliases.insert(bind_let.name.clone(), name);
+                }
+                ANormalLet::BindLet(BindLet_ { value, ..bind_let })
+            }
+            ANormalLet::VarLet(var_let) => {
+                self.mutable.insert(var_let.name.clone());
+                ANormalLet::VarLet(BindLet_ {
+                    value: self.base_expr(var_let.value),
+                    ..var_let
+                })
+            }
+            ANormalLet::NoBindLet(no_bind_let) => ANormalLet::NoBindLet(NoBindLet_ {
+                value: self.base_expr(no_bind_let.value),
+            }),
+            ANormalLet::TupleLet(tuple_let) => ANormalLet::TupleLet(TupleLet_ {
+                value: self.base_expr(tuple_let.value),
+                ..tuple_let
+            }),
+        }
+    }
+
+    // The variables assigned by a binding, in it or in the scopes nested in it. A call
+    // may assign any external register.
+    fn let_assigned(&self, let_binding: &ANormalLet, assigned: &mut HashSet<Ident>) {
+        let value = match let_binding {
+            ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => &bind_let.value,
+            ANormalLet::NoBindLet(no_bind_let) => &no_bind_let.value,
+            ANormalLet::TupleLet(tuple_let) => &tuple_let.value,
+        };
+        self.base_expr_assigned(value, assigned);
+    }
+
+    fn expr_assigned(&self, expr: &ANormalExpr, assigned: &mut HashSet<Ident>) {
+        let Expr_(lets, base) = expr;
+        for let_binding in lets {
+            self.let_assigned(let_binding, assigned);
+        }
+        self.base_expr_assigned(base, assigned);
+    }
+
+    fn base_expr_assigned(&self, expr: &ANormalBaseExpr, assigned: &mut HashSet<Ident>) {
+        match expr {
+            ANormalBaseExpr::Assign(var, _) => {
+                assigned.insert(var.clone());
+            }
+            ANormalBaseExpr::Call(..) => assigned.extend(self.registers.iter().cloned()),
+            ANormalBaseExpr::Map(_, _, _, body)
+            | ANormalBaseExpr::Reduce(_, _, _, _, body)
+            | ANormalBaseExpr::Scan(_, _, _, _, body)
+            | ANormalBaseExpr::For(_, _, _, body) => self.expr_assigned(body, assigned),
+            ANormalBaseExpr::While(cond, body) => {
+                self.expr_assigned(cond, assigned);
+                self.expr_assigned(body, assigned);
+            }
+            ANormalBaseExpr::If(_, then_branch, else_branch) => {
+                self.expr_assigned(then_branch, assigned);
+                self.expr_assigned(else_branch, assigned);
+            }
+            _ => {}
+        }
+    }
+
+    // Drops the computations reading the given variables, which a binding or the scopes
+    // nested in it assign, before it and anything after it can reuse them
+    fn forget(&mut self, assigned: &HashSet<Ident>) {
+        if assigned.is_empty() {
+            return;
+        }
+        self.available.retain(|key, _| match key {
+            Key::Add(left, right) | Key::Mul(left, right) | Key::BinOp(_, left, right) => {
+                !assigned.contains(left) && !assigned.contains(right)
+            }
+            _ => true,
+        });
+    }
+
+    fn resolve(&self, name: &Ident) -> Ident {
+        self.aliases.get(name).unwrap_or(name).clone()
+    }
+
+    fn key(&self, value: &ANormalBaseExpr, ty: &Type) -> Option<Key> {
+        let operands = |left: &Ident, right: &Ident, commutative: bool| {
+            let (left, right) = (self.resolve(left), self.resolve(right));
+            if commutative && right < left {
+                (right, left)
+            } else {
+                (left, right)
+            }
+        };
+        match value {
+            ANormalBaseExpr::Int(n) => match ty {
+                Type::I(width) => Some(Key::Int(*n, *width)),
+                _ => None,
+            },
+            ANormalBaseExpr::Bool(b) => Some(Key::Bool(*b)),
+            ANormalBaseExpr::Add(left, right) => {
+                let (left, right) = operands(left, right, true);
+                Some(Key::Add(left, right))
+            }
+            ANormalBaseExpr::Mul(left, right) => {
+                let (left, right) = operands(left, right, true);
+                Some(Key::Mul(left, right))
+            }
+            ANormalBaseExpr::BinOp(op, left, right) => {
+                let commutative = matches!(
+                    op,
+                    BinOp::Eq | BinOp::Neq | BinOp::And | BinOp::Or | BinOp::Xor
+                );
+                let (left, right) = operands(left, right, commutative);
+                Some(Key::BinOp(*op, left, right))
+            }
+            ANormalBaseExpr::Field(value, field) => {
+                Some(Key::Field(self.resolve(value), field.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn boxed_expr(&mut self, expr: ANormalExpr) -> Box<ANormalExpr> {
+        Box::new(self.expr(expr))
+    }
+
+    fn base_expr(&mut self, expr: ANormalBaseExpr) -> ANormalBaseExpr {
+        match expr {
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                ANormalBaseExpr::Map(arrays, index, params, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Reduce(array, init, acc, param, body) => {
+                ANormalBaseExpr::Reduce(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Scan(array, init, acc, param, body) => {
+                ANormalBaseExpr::Scan(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::For(counter, start, end, body) => {
+                ANormalBaseExpr::For(counter, start, end, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::While(cond, body) => {
+                ANormalBaseExpr::While(self.boxed_expr(*cond), self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::If(cond, then_branch, else_branch) => ANormalBaseExpr::If(
+                cond,
+                self.boxed_expr(*then_branch),
+                self.boxed_expr(*else_branch),
+            ),
+            expr => expr,
+        }
+    }
+}
+
+pub fn eliminate_common_subexpressions(program: ANormalProgram) -> ANormalProgram {
+    // External registers can be assigned anywhere
+    let registers: HashSet<Ident> = program
+        .iter()
+        .filter_map(|item| match item {
+            ANormalTopLevel::ExternalDecl(decl) if matches!(decl.ty, Type::I(_)) => {
+                Some(decl.name.clone())
+            }
+            _ => None,
+        })
+        .collect();
+    program
+        .into_iter()
+        .map(|item| match item {
+            ANormalTopLevel::FunDef(fundef) => {
+                let mut cse = Cse {
+                    available: HashMap::new(),
+                    aliases: HashMap::new(),
+                    mutable: registers.clone(),
+                    registers: registers.clone(),
+                };
+                ANormalTopLevel::FunDef(FunDef_ {
+                    body: cse.expr(fundef.body),
+                    ..fundef
+                })
+            }
+            item => item,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ANormalBaseExpr::{Assign, Call, Int, Mul, Var};
+
+    fn mul(left: &str, right: &str) -> ANormalBaseExpr {
+        Mul(left.to_string(), right.to_string())
+    }
+
+    fn bind(name: &str, value: ANormalBaseExpr) -> ANormalLet {
+        ANormalLet::BindLet(let_(name, Type::i32(), value))
+    }
+
+    // Runs the pass over `main(x, y)` with the given body, next to an external register
+    // `r`, and returns the value bound to `name`
+    fn value_of(lets: Vec<ANormalLet>, name: &str) -> ANormalBaseExpr {
+        let program = vec![
+            ANormalTopLevel::ExternalDecl(ExternalDecl {
+                name: "r".to_string(),
+                ty: Type::i32(),
+            }),
+            ANormalTopLevel::FunDef(FunDef_ {
+                name: "main".to_string(),
+                inline: Inline::Auto,
+                size_params: vec![],
+                params: vec![
+                    ("x".to_string(), Type::i32()),
+                    ("y".to_string(), Type::i32()),
+                ],
+                return_type: None,
+                body: Expr_(lets, Int(0)),
+            }),
+        ];
+        let Some(ANormalTopLevel::FunDef(fundef)) = eliminate_common_subexpressions(program).pop()
+        else {
+            panic!("expected a function")
+        };
+        let Expr_(lets, _) = fundef.body;
+        lets.into_iter()
+            .find_map(|let_binding| match let_binding {
+                ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let)
+                    if bind_let.name == name =>
+                {
+                    Some(bind_let.value)
+                }
+                _ => None,
+            })
+            .expect("binding not found")
+    }
+
+    fn is_var(value: &ANormalBaseExpr, name: &str) -> bool {
+        matches!(value, Var(var) if var == name)
+    }
+
+    #[test]
+    fn reuses_the_first_binding_of_a_product() {
+        let lets = vec![bind("a", mul("x", "y")), bind("b", mul("y", "x"))];
+        assert!(is_var(&value_of(lets, "b"), "a"));
+    }
+
+    #[test]
+    fn forgets_a_product_once_an_operand_is_assigned() {
+        let lets = vec![
+            ANormalLet::VarLet(let_("v", Type::i32(), Var("x".to_string()))),
+            bind("a", mul("v", "y")),
+            bind("one", Int(1)),
+            ANormalLet::NoBindLet(NoBindLet_ {
+                value: Assign("v".to_string(), "one".to_string()),
+            }),
+            bind("b", mul("v", "y")),
+        ];
+        assert!(matches!(value_of(lets, "b"), Mul(..)));
+    }
+
+    #[test]
+    fn forgets_a_register_read_across_a_call() {
+        let lets = vec![
+            bind("a", mul("r", "x")),
+            ANormalLet::NoBindLet(NoBindLet_ {
+                value: Call("f".to_string(), vec![]),
+            }),
+            bind("b", mul("r", "x")),
+        ];
+        assert!(matches!(value_of(lets, "b"), Mul(..)));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::ast::*;
+use crate::fusion::is_pure_base_expr;
+use std::collections::{HashMap, HashSet};
+
+// Removes the bindings whose value is never used and has no side effect, which would
+// still become cells and groups, and the functions that cannot be reached from the top,
+// which would still become components. Names are unique after alpha conversion, so one
+// set of live names serves all the scopes of a function; each scope is swept from its
+// result backwards, so that a binding only used by dead ones is dead too.
+struct Liveness {
+    live: HashSet<Ident>,
+}
+
+impl Liveness {
+    fn expr(&mut self, expr: ANormalExpr) -> ANormalExpr {
+        let Expr_(lets, base) = expr;
+        let base = self.base_expr(base);
+        let mut lets: Vec<ANormalLet> = lets
+            .into_iter()
+            .rev()
+            .filter_map(|let_binding| self.let_binding(let_binding))
+            .collect();
+        lets.reverse();
+        Expr_(lets, base)
+    }
+
+    // None when the binding can be removed
+    fn let_binding(&mut self, let_binding: ANormalLet) -> Option<ANormalLet> {
+        let (names, value) = match &let_binding {
+            ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => {
+                (vec![&bind_let.name], &bind_let.value)
+            }
+            ANormalLet::NoBindLet(no_bind_let) => (vec![], &no_bind_let.value),
+            ANormalLet::TupleLet(tuple_let) => (tuple_let.names.iter().collect(), &tuple_let.value),
+        };
+        if names.iter().all(|name| !self.live.contains(*name)) && is_pure_base_expr(value) {
+            return None;
+        }
+        Some(match let_binding {
+            ANormalLet::BindLet(bind_let) => ANormalLet::BindLet(BindLet_ {
+                value: self.base_expr(bind_let.value),
+                ..bind_let
+            }),
+            ANormalLet::VarLet(var_let) => ANormalLet::VarLet(BindLet_ {
+                value: self.base_expr(var_let.value),
+                ..var_let
+            }),
+            ANormalLet::NoBindLet(no_bind_let) => ANormalLet::NoBindLet(NoBindLet_ {
+                value: self.base_expr(no_bind_let.value),
+            }),
+            ANormalLet::TupleLet(tuple_let) => ANormalLet::TupleLet(TupleLet_ {
+                value: self.base_expr(tuple_let.value),
+                ..tuple_let
+            }),
+        })
+    }
+
+    fn use_names<'a>(&mut self, names: impl IntoIterator<Item = &'a Ident>) {
+        self.live.extend(names.into_iter().cloned());
+    }
+
+    fn boxed_expr(&mut self, expr: ANormalExpr) -> Box<ANormalExpr> {
+        Box::new(self.expr(expr))
+    }
+
+    // Sweeps the scopes nested in `expr` and marks the names it uses live
+    fn base_expr(&mut self, expr: ANormalBaseExpr) -> ANormalBaseExpr {
+        match expr {
+            ANormalBaseExpr::Int(_)
+            | ANormalBaseExpr::Bool(_)
+            | ANormalBaseExpr::NewArray(..)
+            | ANormalBaseExpr::Range(_) => {}
+            ANormalBaseExpr::Var(ref name)
+            | ANormalBaseExpr::Fill(_, _, ref name)
+            | ANormalBaseExpr::Field(ref name, _) => self.use_names([name]),
+            ANormalBaseExpr::Add(ref left, ref right)
+            | ANormalBaseExpr::Mul(ref left, ref right)
+            | ANormalBaseExpr::BinOp(_, ref left, ref right)
+            | ANormalBaseExpr::ArrayGet(ref left, ref right)
+            | ANormalBaseExpr::Assign(ref left, ref right) => self.use_names([left, right]),
+            ANormalBaseExpr::ArraySet(ref array, ref index, ref value) => {
+                self.use_names([array, &**index, &**value])
+            }
+            ANormalBaseExpr::Call(_, ref names) | ANormalBaseExpr::Tuple(ref names) => {
+                self.use_names(names)
+            }
+            ANormalBaseExpr::Struct(_, ref fields) => {
+                self.use_names(fields.iter().map(|(_, value)| value))
+            }
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                let body = self.boxed_expr(*body);
+                self.use_names(&arrays);
+                return ANormalBaseExpr::Map(arrays, index, params, body);
+            }
+            ANormalBaseExpr::Reduce(array, init, acc, param, body) => {
+                let body = self.boxed_expr(*body);
+                self.use_names([&array, &init]);
+                return ANormalBaseExpr::Reduce(array, init, acc, param, body);
+            }
+            ANormalBaseExpr::Scan(array, init, acc, param, body) => {
+                let body = self.boxed_expr(*body);
+                self.use_names([&array, &init]);
+                return ANormalBaseExpr::Scan(array, init, acc, param, body);
+            }
+            ANormalBaseExpr::For(counter, start, end, body) => {
+                return ANormalBaseExpr::For(counter, start, end, self.boxed_expr(*body));
+            }
+            ANormalBaseExpr::While(cond, body) => {
+                let body = self.boxed_expr(*body);
+                return ANormalBaseExpr::While(self.boxed_expr(*cond), body);
+            }
+            ANormalBaseExpr::If(cond, then_branch, else_branch) => {
+                let then_branch = self.boxed_expr(*then_branch);
+                let else_branch = self.boxed_expr(*else_branch);
+                self.use_names([&cond]);
+                return ANormalBaseExpr::If(cond, then_branch, else_branch);
+            }
+        }
+        expr
+    }
+}
+
+fn expr_calls<'a>(expr: &'a ANormalExpr, calls: &mut Vec<&'a Ident>) {
+    let Expr_(lets, base) = expr;
+    for let_binding in lets {
+        match let_binding {
+            ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => {
+                base_expr_calls(&bind_let.value, calls)
+            }
+            ANormalLet::NoBindLet(no_bind_let) => base_expr_calls(&no_bind_let.value, calls),
+            ANormalLet::TupleLet(tuple_let) => base_expr_calls(&tuple_let.value, calls),
+        }
+    }
+    base_expr_calls(base, calls);
+}
+
+fn base_expr_calls<'a>(expr: &'a ANormalBaseExpr, calls: &mut Vec<&'a Ident>) {
+    match expr {
+        ANormalBaseExpr::Call(name, _) => calls.push(name),
+        ANormalBaseExpr::Map(_, _, _, body)
+        | ANormalBaseExpr::Reduce(_, _, _, _, body)
+        | ANormalBaseExpr::Scan(_, _, _, _, body)
+        | ANormalBaseExpr::For(_, _, _, body) => expr_calls(body, calls),
+        ANormalBaseExpr::While(cond, body) => {
+            expr_calls(cond, calls);
+            expr_calls(body, calls);
+        }
+        ANormalBaseExpr::If(_, then_branch, else_branch) => {
+            expr_calls(then_branch, calls);
+            expr_calls(else_branch, calls);
+        }
+        _ => {}
+    }
+}
+
+pub fn eliminate_dead_code(program: ANormalProgram, top: &str) -> ANormalProgram {
+    let program: ANormalProgram = program
+        .into_iter()
+        .map(|item| match item {
+            ANormalTopLevel::FunDef(fundef) => {
+                let mut liveness = Liveness {
+                    live: HashSet::new(),
+                };
+                ANormalTopLevel::FunDef(FunDef_ {
+                    body: liveness.expr(fundef.body),
+                    ..fundef
+                })
+            }
+            item => item,
+        })
+        .collect();
+
+    // The calls left in each function, to find the ones reachable from the top
+    let mut callees = HashMap::new();
+    for item in &program {
+        if let ANormalTopLevel::FunDef(fundef) = item {
+            let mut calls = vec![];
+            expr_calls(&fundef.body, &mut calls);
+            callees.insert(&fundef.name, calls);
+        }
+    }
+    let mut reachable = HashSet::from([top.to_string()]);
+    let mut stack = vec![top.to_string()];
+    while let Some(name) = stack.pop() {
+        for callee in callees.get(&name).into_iter().flatten() {
+            if reachable.insert((*callee).clone()) {
+                stack.push((*callee).clone());
+            }
+        }
+    }
+
+    program
+        .into_iter()
+        .filter(|item| match item {
+            ANormalTopLevel::FunDef(fundef) => reachable.contains(&fundef.name),
+            _ => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ANormalBaseExpr::{Add, ArraySet, Call, Int, Mul};
+
+    fn fundef(name: &str, lets: Vec<ANormalLet>) -> ANormalTopLevel {
+        ANormalTopLevel::FunDef(FunDef_ {
+            name: name.to_string(),
+            inline: Inline::Never,
+            size_params: vec![],
+            params: vec![
+                ("x".to_string(), Type::i32()),
+                ("y".to_string(), Type::i32()),
+            ],
+            return_type: None,
+            body: Expr_(lets, Int(0)),
+        })
+    }
+
+    fn bind(name: &str, value: ANormalBaseExpr) -> ANormalLet {
+        ANormalLet::BindLet(let_(name, Type::i32(), value))
+    }
+
+    // `main` with the given body, which may call `helper`, and `unused`, which calls it
+    // too; returns the functions left and the names bound in `main`
+    fn eliminate(lets: Vec<ANormalLet>) -> (Vec<Ident>, Vec<Ident>) {
+        let call = |name: &str| bind(name, Call("helper".to_string(), vec!["x".to_string()]));
+        let program = vec![
+            ANormalTopLevel::ExternalDecl(ExternalDecl {
+                name: "a".to_string(),
+                ty: Type::array(Type::i32(), 4),
+            }),
+            fundef("main", lets),
+            fundef("helper", vec![]),
+            fundef("unused", vec![call("h")]),
+        ];
+        let mut functions = vec![];
+        let mut bound = vec![];
+        for item in eliminate_dead_code(program, "main") {
+            let ANormalTopLevel::FunDef(fundef) = item else {
+                continue;
+            };
+            if fundef.name == "main" {
+                let Expr_(lets, _) = &fundef.body;
+                bound = lets
+                    .iter()
+                    .filter_map(|let_binding| match let_binding {
+                        ANormalLet::BindLet(bind_let) => Some(bind_let.name.clone()),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            functions.push(fundef.name);
+        }
+        (functions, bound)
+    }
+
+    #[test]
+    fn removes_unused_pure_bindings() {
+        let (_, bound) = eliminate(vec![
+            bind("product", Mul("x".into(), "y".into())),
+            // Only used by a dead binding
+            bind("sum", Add("x".into(), "y".into())),
+            bind("twice", Add("sum".into(), "sum".into())),
+        ]);
+        assert!(bound.is_empty());
+    }
+
+    #[test]
+    fn keeps_array_writes_and_calls() {
+        let (_, bound) = eliminate(vec![
+            bind("index", Int(1)),
+            bind(
+                "write",
+                ArraySet("a".into(), Box::new("index".into()), Box::new("x".into())),
+            ),
+            bind("result", Call("helper".into(), vec!["y".into()])),
+        ]);
+        assert_eq!(bound, vec!["index", "write", "result"]);
+    }
+
+    #[test]
+    fn drops_the_functions_unreachable_from_the_top() {
+        let (functions, _) = eliminate(vec![bind("result", Call("helper".into(), vec![]))]);
+        assert_eq!(functions, vec!["main", "helper"]);
+        let (functions, _) = eliminate(vec![]);
+        assert_eq!(functions, vec!["main"]);
+    }
+}
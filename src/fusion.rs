@@ -0,0 +1,650 @@
+use crate::ast::*;
+use crate::convert::is_tree_reducible;
+use std::collections::HashMap;
+
+// Merges a map into the map consuming its result when nothing else uses the
+// intermediate array, so that both run in one loop without allocating the array:
+//
+//   let t = map(a, (x) => f) in ... map(t, b, (y, z) => g)
+//   ~> ... map(a, b, (x, z) => let y = f in g)
+//
+// A reduce over the result of a map likewise becomes a single loop feeding each mapped
+// value into the accumulator:
+//
+//   let t = map(a, (x) => f) in ... let s = reduce(t, init, (acc, y) => g)
+//   ~> ... var s = init in
+//          let _ = for s_i in 0..n do (let x = a[s_i] in let y = f in let acc = s in
+//                                      s := g)
+//
+// The map is only moved down to its consumer when this cannot be observed: neither
+// lambda has side effects and nothing between the two writes memory. A reduce that
+// `--tree-reduce` lowers to par stages is left as it is.
+struct Fusion {
+    // Number of times each variable is used in the function body
+    uses: HashMap<Ident, usize>,
+    // Element type of each array in scope of the function
+    elements: HashMap<Ident, Type>,
+    tree_reduce: bool,
+}
+
+impl Fusion {
+    fn expr(&self, expr: ANormalExpr) -> ANormalExpr {
+        let Expr_(lets, base) = expr;
+        let mut lets: Vec<ANormalLet> = lets
+            .into_iter()
+            .map(|let_binding| self.let_binding(let_binding))
+            .collect();
+        while let Some((producer, consumer)) = self.find_fusable(&lets) {
+            let ANormalLet::BindLet(producer) = lets.remove(producer) else {
+                unreachable!()
+            };
+            // The consumer moved up by one
+            let ANormalLet::BindLet(consumer_let) = lets.remove(consumer - 1) else {
+                unreachable!()
+            };
+            let fused = match consumer_let.value {
+                ANormalBaseExpr::Reduce(..) => {
+                    fuse_map_reduce(producer, consumer_let, &self.elements)
+                }
+                value => vec![ANormalLet::BindLet(BindLet_ {
+                    value: fuse_maps(producer, value),
+                    ..consumer_let
+                })],
+            };
+            lets.splice(consumer - 1..consumer - 1, fused);
+        }
+        Expr_(lets, self.base_expr(base))
+    }
+
+    fn let_binding(&self, let_binding: ANormalLet) -> ANormalLet {
+        match let_binding {
+            ANormalLet::BindLet(bind_let) => ANormalLet::BindLet(BindLet_ {
+                value: self.base_expr(bind_let.value),
+                ..bind_let
+            }),
+            ANormalLet::VarLet(var_let) => ANormalLet::VarLet(BindLet_ {
+                value: self.base_expr(var_let.value),
+                ..var_let
+            }),
+            ANormalLet::NoBindLet(no_bind_let) => ANormalLet::NoBindLet(NoBindLet_ {
+                value: self.base_expr(no_bind_let.value),
+            }),
+            ANormalLet::TupleLet(tuple_let) => ANormalLet::TupleLet(TupleLet_ {
+                value: self.base_expr(tuple_let.value),
+                ..tuple_let
+            }),
+        }
+    }
+
+    fn boxed_expr(&self, expr: ANormalExpr) -> Box<ANormalExpr> {
+        Box::new(self.expr(expr))
+    }
+
+    fn base_expr(&self, expr: ANormalBaseExpr) -> ANormalBaseExpr {
+        match expr {
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                ANormalBaseExpr::Map(arrays, index, params, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Reduce(array, init, acc, param, body) => {
+                ANormalBaseExpr::Reduce(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Scan(array, init, acc, param, body) => {
+                ANormalBaseExpr::Scan(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::For(counter, start, end, body) => {
+                ANormalBaseExpr::For(counter, start, end, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::While(cond, body) => {
+                ANormalBaseExpr::While(self.boxed_expr(*cond), self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::If(cond, then_branch, else_branch) => ANormalBaseExpr::If(
+                cond,
+                self.boxed_expr(*then_branch),
+                self.boxed_expr(*else_branch),
+            ),
+            expr => expr,
+        }
+    }
+
+    // Finds `let t: T[n] = map(...)` and the later map or reduce that is the only use of `t`
+    fn find_fusable(&self, lets: &[ANormalLet]) -> Option<(usize, usize)> {
+        for (producer, let_binding) in lets.iter().enumerate() {
+            let ANormalLet::BindLet(BindLet_ {
+                name,
+                ty: Type::Array(_, size),
+                value: ANormalBaseExpr::Map(arrays, _, _, body),
+            }) = let_binding
+            else {
+                continue;
+            };
+            if self.uses.get(name) != Some(&1) || !is_pure_expr(body) {
+                continue;
+            }
+            for (consumer, later) in lets.iter().enumerate().skip(producer + 1) {
+                let consumer_body = match later {
+                    ANormalLet::BindLet(BindLet_ {
+                        value: ANormalBaseExpr::Map(arrays, _, _, body),
+                        ..
+                    }) if arrays.contains(name) => Some(body),
+                    ANormalLet::BindLet(BindLet_ {
+                        value: ANormalBaseExpr::Reduce(array, _, acc, param, body),
+                        ..
+                    }) if array == name => {
+                        if self.tree_reduce && is_tree_reducible(*size, acc, param, body) {
+                            break;
+                        }
+                        // The loop reads the map's inputs, whose element types it needs
+                        if !arrays.iter().all(|array| self.elements.contains_key(array)) {
+                            break;
+                        }
+                        Some(body)
+                    }
+                    _ => None,
+                };
+                if let Some(body) = consumer_body {
+                    if is_pure_expr(body) {
+                        return Some((producer, consumer));
+                    }
+                    break;
+                }
+                if !is_pure_let(later) {
+                    break;
+                }
+            }
+        }
+        None
+    }
+}
+
+fn fuse_maps(producer: ANormalBindLet, consumer: ANormalBaseExpr) -> ANormalBaseExpr {
+    let ANormalBindLet {
+        name: intermediate,
+        ty: Type::Array(element_ty, _),
+        value: ANormalBaseExpr::Map(first_arrays, first_index, first_params, first_body),
+    } = producer
+    else {
+        unreachable!()
+    };
+    let ANormalBaseExpr::Map(arrays, index, params, body) = consumer else {
+        unreachable!()
+    };
+    let Expr_(mut lets, first_result) = *first_body;
+
+    let mut fused_arrays = vec![];
+    let mut fused_params = vec![];
+    for (array, param) in arrays.into_iter().zip(params) {
+        if array == intermediate {
+            fused_arrays.extend(first_arrays.iter().cloned());
+            fused_params.extend(first_params.iter().cloned());
+            lets.push(ANormalLet::BindLet(let_(
+                &param,
+                *element_ty.clone(),
+                first_result.clone(),
+            )));
+        } else {
+            fused_arrays.push(array);
+            fused_params.push(param);
+        }
+    }
+    // Both lambdas may take the index
+    let fused_index = match (first_index, index) {
+        (Some(first_index), Some(index)) => {
+            lets.push(ANormalLet::BindLet(let_(
+                &index,
+                Type::i32(),
+                ANormalBaseExpr::Var(first_index.clone()),
+            )));
+            Some(first_index)
+        }
+        (first_index, index) => first_index.or(index),
+    };
+    let Expr_(body_lets, body_result) = *body;
+    lets.extend(body_lets);
+    ANormalBaseExpr::Map(
+        fused_arrays,
+        fused_index,
+        fused_params,
+        Box::new(Expr_(lets, body_result)),
+    )
+}
+
+fn fuse_map_reduce(
+    producer: ANormalBindLet,
+    consumer: ANormalBindLet,
+    elements: &HashMap<Ident, Type>,
+) -> Vec<ANormalLet> {
+    let ANormalBindLet {
+        ty: Type::Array(element_ty, size),
+        value: ANormalBaseExpr::Map(arrays, index, params, map_body),
+        ..
+    } = producer
+    else {
+        unreachable!()
+    };
+    let ANormalBaseExpr::Reduce(_, init, acc, param, reduce_body) = consumer.value else {
+        unreachable!()
+    };
+    // The accumulator is the result itself. Suffixed names cannot clash with
+    // alpha-converted ones, which end in a number.
+    let acc_var = consumer.name;
+    let counter = format!("{}_i", acc_var);
+    let step = format!("{}_step", acc_var);
+
+    let mut lets = vec![];
+    for (array, param) in arrays.iter().zip(&params) {
+        lets.push(ANormalLet::BindLet(let_(
+            param,
+            elements[array].clone(),
+            ANormalBaseExpr::ArrayGet(array.clone(), counter.clone()),
+        )));
+    }
+    if let Some(index) = index {
+        lets.push(ANormalLet::BindLet(let_(
+            &index,
+            Type::i32(),
+            ANormalBaseExpr::Var(counter.clone()),
+        )));
+    }
+    let Expr_(map_lets, map_result) = *map_body;
+    lets.extend(map_lets);
+    lets.push(ANormalLet::BindLet(let_(&param, *element_ty, map_result)));
+    lets.push(ANormalLet::BindLet(let_(
+        &acc,
+        consumer.ty.clone(),
+        ANormalBaseExpr::Var(acc_var.clone()),
+    )));
+    let Expr_(reduce_lets, reduce_result) = *reduce_body;
+    lets.extend(reduce_lets);
+    lets.push(ANormalLet::BindLet(let_(
+        &step,
+        consumer.ty.clone(),
+        reduce_result,
+    )));
+    let body = Expr_(lets, ANormalBaseExpr::Assign(acc_var.clone(), step));
+
+    vec![
+        ANormalLet::VarLet(let_(
+            &acc_var,
+            consumer.ty.clone(),
+            ANormalBaseExpr::Var(init),
+        )),
+        ANormalLet::NoBindLet(NoBindLet_ {
+            value: ANormalBaseExpr::For(counter, 0, size, Box::new(body)),
+        }),
+    ]
+}
+
+fn is_pure_let(let_binding: &ANormalLet) -> bool {
+    match let_binding {
+        ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => {
+            is_pure_base_expr(&bind_let.value)
+        }
+        ANormalLet::NoBindLet(no_bind_let) => is_pure_base_expr(&no_bind_let.value),
+        ANormalLet::TupleLet(tuple_let) => is_pure_base_expr(&tuple_let.value),
+    }
+}
+
+fn is_pure_expr(expr: &ANormalExpr) -> bool {
+    let Expr_(lets, base) = expr;
+    lets.iter().all(is_pure_let) && is_pure_base_expr(base)
+}
+
+// Whether `expr` writes no memory or variable from outside and calls no function, so
+// that it can be evaluated at another time
+pub fn is_pure_base_expr(expr: &ANormalBaseExpr) -> bool {
+    match expr {
+        ANormalBaseExpr::ArraySet(..) | ANormalBaseExpr::Assign(..) | ANormalBaseExpr::Call(..) => {
+            false
+        }
+        ANormalBaseExpr::Map(_, _, _, body)
+        | ANormalBaseExpr::Reduce(_, _, _, _, body)
+        | ANormalBaseExpr::Scan(_, _, _, _, body)
+        | ANormalBaseExpr::For(_, _, _, body) => is_pure_expr(body),
+        ANormalBaseExpr::While(cond, body) => is_pure_expr(cond) && is_pure_expr(body),
+        ANormalBaseExpr::If(_, then_branch, else_branch) => {
+            is_pure_expr(then_branch) && is_pure_expr(else_branch)
+        }
+        _ => true,
+    }
+}
+
+pub fn count_uses(expr: &ANormalExpr, uses: &mut HashMap<Ident, usize>) {
+    let Expr_(lets, base) = expr;
+    for let_binding in lets {
+        match let_binding {
+            ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => {
+                count_base_expr_uses(&bind_let.value, uses)
+            }
+            ANormalLet::NoBindLet(no_bind_let) => count_base_expr_uses(&no_bind_let.value, uses),
+            ANormalLet::TupleLet(tuple_let) => count_base_expr_uses(&tuple_let.value, uses),
+        }
+    }
+    count_base_expr_uses(base, uses);
+}
+
+fn count_base_expr_uses(expr: &ANormalBaseExpr, uses: &mut HashMap<Ident, usize>) {
+    let mut used = |names: &[&Ident]| {
+        for name in names {
+            *uses.entry((*name).clone()).or_default() += 1;
+        }
+    };
+    match expr {
+        ANormalBaseExpr::Int(_)
+        | ANormalBaseExpr::Bool(_)
+        | ANormalBaseExpr::NewArray(..)
+        | ANormalBaseExpr::Range(_) => {}
+        ANormalBaseExpr::Var(name)
+        | ANormalBaseExpr::Fill(_, _, name)
+        | ANormalBaseExpr::Field(name, _) => used(&[name]),
+        ANormalBaseExpr::Add(left, right)
+        | ANormalBaseExpr::Mul(left, right)
+        | ANormalBaseExpr::BinOp(_, left, right)
+        | ANormalBaseExpr::ArrayGet(left, right)
+        | ANormalBaseExpr::Assign(left, right) => used(&[left, right]),
+        ANormalBaseExpr::ArraySet(array, index, value) => used(&[array, index, value]),
+        ANormalBaseExpr::Call(_, names) | ANormalBaseExpr::Tuple(names) => {
+            used(&names.iter().collect::<Vec<_>>())
+        }
+        ANormalBaseExpr::Struct(_, fields) => {
+            used(&fields.iter().map(|(_, value)| value).collect::<Vec<_>>())
+        }
+        ANormalBaseExpr::Map(arrays, _, _, body) => {
+            used(&arrays.iter().collect::<Vec<_>>());
+            count_uses(body, uses);
+        }
+        ANormalBaseExpr::Reduce(array, init, _, _, body)
+        | ANormalBaseExpr::Scan(array, init, _, _, body) => {
+            used(&[array, init]);
+            count_uses(body, uses);
+        }
+        ANormalBaseExpr::For(_, _, _, body) => count_uses(body, uses),
+        ANormalBaseExpr::While(cond, body) => {
+            count_uses(cond, uses);
+            count_uses(body, uses);
+        }
+        ANormalBaseExpr::If(cond, then_branch, else_branch) => {
+            used(&[cond]);
+            count_uses(then_branch, uses);
+            count_uses(else_branch, uses);
+        }
+    }
+}
+
+fn add_element_type(elements: &mut HashMap<Ident, Type>, name: &Ident, ty: &Type) {
+    if let Type::Array(element_ty, _) = ty {
+        elements.insert(name.clone(), *element_ty.clone());
+    }
+}
+
+// The element types of the arrays bound in `expr` and the scopes nested in it
+fn element_types(expr: &ANormalExpr, elements: &mut HashMap<Ident, Type>) {
+    let Expr_(lets, base) = expr;
+    for let_binding in lets {
+        let value = match let_binding {
+            ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => {
+                add_element_type(elements, &bind_let.name, &bind_let.ty);
+                &bind_let.value
+            }
+            ANormalLet::NoBindLet(no_bind_let) => &no_bind_let.value,
+            ANormalLet::TupleLet(tuple_let) => {
+                if let Some(Type::Tuple(tys)) = &tuple_let.ty {
+                    for (name, ty) in tuple_let.names.iter().zip(tys) {
+                        add_element_type(elements, name, ty);
+                    }
+                }
+                &tuple_let.value
+            }
+        };
+        base_expr_element_types(value, elements);
+    }
+    base_expr_element_types(base, elements);
+}
+
+fn base_expr_element_types(expr: &ANormalBaseExpr, elements: &mut HashMap<Ident, Type>) {
+    match expr {
+        ANormalBaseExpr::Map(_, _, _, body)
+        | ANormalBaseExpr::Reduce(_, _, _, _, body)
+        | ANormalBaseExpr::Scan(_, _, _, _, body)
+        | ANormalBaseExpr::For(_, _, _, body) => element_types(body, elements),
+        ANormalBaseExpr::While(cond, body) => {
+            element_types(cond, elements);
+            element_types(body, elements);
+        }
+        ANormalBaseExpr::If(_, then_branch, else_branch) => {
+            element_types(then_branch, elements);
+            element_types(else_branch, elements);
+        }
+        _ => {}
+    }
+}
+
+pub fn fuse_program(program: ANormalProgram, tree_reduce: bool) -> ANormalProgram {
+    let mut externals = HashMap::new();
+    for item in &program {
+        if let ANormalTopLevel::ExternalDecl(decl) = item {
+            add_element_type(&mut externals, &decl.name, &decl.ty);
+        }
+    }
+    program
+        .into_iter()
+        .map(|item| match item {
+            ANormalTopLevel::FunDef(fundef) => {
+                let mut uses = HashMap::new();
+                count_uses(&fundef.body, &mut uses);
+                let mut elements = externals.clone();
+                for (name, ty) in &fundef.params {
+                    add_element_type(&mut elements, name, ty);
+                }
+                element_types(&fundef.body, &mut elements);
+                let fusion = Fusion {
+                    uses,
+                    elements,
+                    tree_reduce,
+                };
+                ANormalTopLevel::FunDef(FunDef_ {
+                    body: fusion.expr(fundef.body),
+                    ..fundef
+                })
+            }
+            item => item,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(lets: Vec<ANormalLet>, base: ANormalBaseExpr) -> ANormalExpr {
+        Expr_(lets, base)
+    }
+
+    fn bind(name: &str, ty: Type, value: ANormalBaseExpr) -> ANormalLet {
+        ANormalLet::BindLet(let_(name, ty, value))
+    }
+
+    fn names(names: &[&str]) -> Vec<Ident> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn array(element_ty: Type, size: usize) -> Type {
+        Type::Array(Box::new(element_ty), size)
+    }
+
+    // Fuses the body of `main`, reading the external arrays `a` and `b`, and returns it
+    fn fuse(a: Type, b: Type, body: ANormalExpr) -> ANormalExpr {
+        let program = vec![
+            ANormalTopLevel::ExternalDecl(ExternalDecl {
+                name: "a".to_string(),
+                ty: a,
+            }),
+            ANormalTopLevel::ExternalDecl(ExternalDecl {
+                name: "b".to_string(),
+                ty: b,
+            }),
+            ANormalTopLevel::FunDef(FunDef_ {
+                name: "main".to_string(),
+                inline: Inline::default(),
+                size_params: vec![],
+                params: vec![],
+                return_type: Some(Type::i32()),
+                body,
+            }),
+        ];
+        let Some(ANormalTopLevel::FunDef(fundef)) = fuse_program(program, false).pop() else {
+            panic!("expected main")
+        };
+        fundef.body
+    }
+
+    #[test]
+    fn reads_the_map_inputs_with_their_own_element_type() {
+        // let t: i32[4] = map(a, (x) => if x then 1 else 0) in reduce(t, 0, (acc, y) => acc + y)
+        let to_int = expr(
+            vec![],
+            ANormalBaseExpr::If(
+                "x".to_string(),
+                Box::new(expr(vec![], ANormalBaseExpr::Int(1))),
+                Box::new(expr(vec![], ANormalBaseExpr::Int(0))),
+            ),
+        );
+        let sum = expr(
+            vec![bind(
+                "r",
+                Type::i32(),
+                ANormalBaseExpr::Add("acc".to_string(), "y".to_string()),
+            )],
+            ANormalBaseExpr::Var("r".to_string()),
+        );
+        let body = expr(
+            vec![
+                bind(
+                    "t",
+                    array(Type::i32(), 4),
+                    ANormalBaseExpr::Map(names(&["a"]), None, names(&["x"]), Box::new(to_int)),
+                ),
+                bind("zero", Type::i32(), ANormalBaseExpr::Int(0)),
+                bind(
+                    "s",
+                    Type::i32(),
+                    ANormalBaseExpr::Reduce(
+                        "t".to_string(),
+                        "zero".to_string(),
+                        "acc".to_string(),
+                        "y".to_string(),
+                        Box::new(sum),
+                    ),
+                ),
+            ],
+            ANormalBaseExpr::Var("s".to_string()),
+        );
+        let Expr_(lets, _) = fuse(array(Type::bool(), 4), array(Type::i32(), 4), body);
+        let loop_body = lets.iter().find_map(|let_binding| match let_binding {
+            ANormalLet::NoBindLet(NoBindLet_ {
+                value: ANormalBaseExpr::For(_, _, _, body),
+            }) => Some(&**body),
+            _ => None,
+        });
+        let Some(Expr_(loop_lets, _)) = loop_body else {
+            panic!("expected the reduce to become a loop")
+        };
+        let element = loop_lets.iter().find_map(|let_binding| match let_binding {
+            ANormalLet::BindLet(bind_let) if bind_let.name == "x" => Some(&bind_let.ty),
+            _ => None,
+        });
+        assert_eq!(element, Some(&Type::bool()));
+        let mapped = loop_lets.iter().find_map(|let_binding| match let_binding {
+            ANormalLet::BindLet(bind_let) if bind_let.name == "y" => Some(&bind_let.ty),
+            _ => None,
+        });
+        assert_eq!(mapped, Some(&Type::i32()));
+    }
+
+    // let t: i32[4] = map(a, (x) => first) in <between> let u: i32[4] = map(t, b, (y, z) => y + z)
+    fn fuse_two_maps(first: ANormalExpr, between: Vec<ANormalLet>) -> Vec<ANormalLet> {
+        let sum = expr(
+            vec![bind(
+                "w",
+                Type::i32(),
+                ANormalBaseExpr::Add("y".to_string(), "z".to_string()),
+            )],
+            ANormalBaseExpr::Var("w".to_string()),
+        );
+        let mut lets = vec![bind(
+            "t",
+            array(Type::i32(), 4),
+            ANormalBaseExpr::Map(names(&["a"]), None, names(&["x"]), Box::new(first)),
+        )];
+        lets.extend(between);
+        lets.push(bind(
+            "u",
+            array(Type::i32(), 4),
+            ANormalBaseExpr::Map(names(&["t", "b"]), None, names(&["y", "z"]), Box::new(sum)),
+        ));
+        let body = expr(lets, ANormalBaseExpr::Var("u".to_string()));
+        let Expr_(lets, _) = fuse(array(Type::i32(), 4), array(Type::i32(), 4), body);
+        lets
+    }
+
+    // The arrays read by the map bound to `name`
+    fn map_arrays(lets: &[ANormalLet], name: &str) -> Option<Vec<Ident>> {
+        lets.iter().find_map(|let_binding| match let_binding {
+            ANormalLet::BindLet(BindLet_ {
+                name: bound,
+                value: ANormalBaseExpr::Map(arrays, ..),
+                ..
+            }) if bound == name => Some(arrays.clone()),
+            _ => None,
+        })
+    }
+
+    fn square() -> ANormalExpr {
+        expr(
+            vec![bind(
+                "sq",
+                Type::i32(),
+                ANormalBaseExpr::Mul("x".to_string(), "x".to_string()),
+            )],
+            ANormalBaseExpr::Var("sq".to_string()),
+        )
+    }
+
+    #[test]
+    fn fuses_a_map_into_its_only_consumer() {
+        let lets = fuse_two_maps(square(), vec![]);
+        assert_eq!(lets.len(), 1);
+        assert_eq!(map_arrays(&lets, "u"), Some(names(&["a", "b"])));
+    }
+
+    #[test]
+    fn keeps_a_map_whose_result_is_read_elsewhere() {
+        let lets = fuse_two_maps(
+            square(),
+            vec![
+                bind("zero", Type::i32(), ANormalBaseExpr::Int(0)),
+                bind(
+                    "first",
+                    Type::i32(),
+                    ANormalBaseExpr::ArrayGet("t".to_string(), "zero".to_string()),
+                ),
+            ],
+        );
+        assert_eq!(map_arrays(&lets, "t"), Some(names(&["a"])));
+        assert_eq!(map_arrays(&lets, "u"), Some(names(&["t", "b"])));
+    }
+
+    #[test]
+    fn keeps_a_map_calling_a_function() {
+        let call = expr(
+            vec![bind(
+                "c",
+                Type::i32(),
+                ANormalBaseExpr::Call("f".to_string(), names(&["x"])),
+            )],
+            ANormalBaseExpr::Var("c".to_string()),
+        );
+        let lets = fuse_two_maps(call, vec![]);
+        assert_eq!(map_arrays(&lets, "t"), Some(names(&["a"])));
+        assert_eq!(map_arrays(&lets, "u"), Some(names(&["t", "b"])));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::alpha::AlphaContext;
+use crate::ast::*;
+use std::collections::HashMap;
+
+// Functions whose body costs at most this many operations are inlined unless marked
+// `noinline`
+const INLINE_THRESHOLD: usize = 4;
+// Loops are never inlined automatically
+const LOOP_COST: usize = usize::MAX / 2;
+
+// Replaces calls to small or `inline` functions by their body, so that they do not
+// need a component instance with its go/done handshake. Runs after monomorphization;
+// every inlined copy is renamed apart, so variable names stay unique.
+#[derive(Debug)]
+struct Inliner {
+    // Functions seen so far, with their own calls already inlined
+    fundefs: HashMap<Ident, FunDef>,
+    renamer: AlphaContext,
+}
+
+impl Inliner {
+    fn fundef(&mut self, fundef: FunDef) -> FunDef {
+        let body = self.expr(fundef.body);
+        let fundef = FunDef { body, ..fundef };
+        self.fundefs.insert(fundef.name.clone(), fundef.clone());
+        fundef
+    }
+
+    fn expr(&mut self, expr: Expr) -> Expr {
+        let Expr_(lets, base) = expr;
+        let lets = lets
+            .into_iter()
+            .map(|let_binding| self.let_binding(let_binding))
+            .collect();
+        Expr_(lets, self.base_expr(base))
+    }
+
+    fn let_binding(&mut self, let_binding: Let) -> Let {
+        match let_binding {
+            Let::BindLet(bind_let) => Let::BindLet(BindLet {
+                value: self.base_expr(bind_let.value),
+                ..bind_let
+            }),
+            Let::VarLet(var_let) => Let::VarLet(BindLet {
+                value: self.base_expr(var_let.value),
+                ..var_let
+            }),
+            Let::NoBindLet(no_bind_let) => Let::NoBindLet(NoBindLet {
+                value: self.base_expr(no_bind_let.value),
+            }),
+            Let::TupleLet(tuple_let) => Let::TupleLet(TupleLet {
+                value: self.base_expr(tuple_let.value),
+                ..tuple_let
+            }),
+        }
+    }
+
+    fn boxed(&mut self, expr: BaseExpr) -> Box<BaseExpr> {
+        Box::new(self.base_expr(expr))
+    }
+
+    fn boxed_expr(&mut self, expr: Expr) -> Box<Expr> {
+        Box::new(self.expr(expr))
+    }
+
+    fn base_expr(&mut self, expr: BaseExpr) -> BaseExpr {
+        match expr {
+            BaseExpr::Int(_) | BaseExpr::Bool(_) | BaseExpr::Var(_) => expr,
+            BaseExpr::NewArray(..) | BaseExpr::Range(_) => expr,
+            BaseExpr::Add(left, right) => BaseExpr::Add(self.boxed(*left), self.boxed(*right)),
+            BaseExpr::Mul(left, right) => BaseExpr::Mul(self.boxed(*left), self.boxed(*right)),
+            BaseExpr::BinOp(op, left, right) => {
+                BaseExpr::BinOp(op, self.boxed(*left), self.boxed(*right))
+            }
+            BaseExpr::Fill(ty, size, value) => BaseExpr::Fill(ty, size, self.boxed(*value)),
+            BaseExpr::Map(arrays, index, params, body) => {
+                let arrays = arrays
+                    .into_iter()
+                    .map(|array| self.base_expr(array))
+                    .collect();
+                BaseExpr::Map(arrays, index, params, self.boxed_expr(*body))
+            }
+            BaseExpr::Reduce(array, init, param1, param2, body) => BaseExpr::Reduce(
+                self.boxed(*array),
+                self.boxed(*init),
+                param1,
+                param2,
+                self.boxed_expr(*body),
+            ),
+            BaseExpr::Scan(array, init, param1, param2, body) => BaseExpr::Scan(
+                self.boxed(*array),
+                self.boxed(*init),
+                param1,
+                param2,
+                self.boxed_expr(*body),
+            ),
+            BaseExpr::Call(name, args) => {
+                let args: Vec<BaseExpr> = args.into_iter().map(|arg| self.base_expr(arg)).collect();
+                self.call(name, args)
+            }
+            BaseExpr::ArraySet(name, index, value) => {
+                BaseExpr::ArraySet(name, self.boxed(*index), self.boxed(*value))
+            }
+            BaseExpr::ArrayGet(name, index) => BaseExpr::ArrayGet(name, self.boxed(*index)),
+            BaseExpr::Tuple(items) => {
+                BaseExpr::Tuple(items.into_iter().map(|item| self.base_expr(item)).collect())
+            }
+            BaseExpr::For(counter, start, end, body) => {
+                BaseExpr::For(counter, start, end, self.boxed_expr(*body))
+            }
+            BaseExpr::Assign(name, value) => BaseExpr::Assign(name, self.boxed(*value)),
+            BaseExpr::Field(value, field) => BaseExpr::Field(self.boxed(*value), field),
+            BaseExpr::Struct(name, fields) => BaseExpr::Struct(
+                name,
+                fields
+                    .into_iter()
+                    .map(|(field, value)| (field, self.base_expr(value)))
+                    .collect(),
+            ),
+            BaseExpr::Block(body) => BaseExpr::Block(self.boxed_expr(*body)),
+            BaseExpr::While(cond, body) => {
+                BaseExpr::While(self.boxed(*cond), self.boxed_expr(*body))
+            }
+            BaseExpr::If(cond, then_branch, else_branch) => BaseExpr::If(
+                self.boxed(*cond),
+                self.boxed_expr(*then_branch),
+                self.boxed_expr(*else_branch),
+            ),
+        }
+    }
+
+    // Calls to functions defined later are left alone, which also keeps recursive
+    // functions from being inlined into themselves
+    fn call(&mut self, name: Ident, args: Vec<BaseExpr>) -> BaseExpr {
+        let Some(callee) = self.fundefs.get(&name) else {
+            return BaseExpr::Call(name, args);
+        };
+        // Arity errors are reported by normalization
+        if !should_inline(callee) || callee.params.len() != args.len() {
+            return BaseExpr::Call(name, args);
+        }
+
+        // Binding the arguments to the parameters evaluates each of them once, as a
+        // call would
+        let (params, Expr_(body_lets, body_base)) =
+            self.renamer.rename_body(&callee.params, &callee.body);
+        let mut lets: Vec<Let> = params
+            .into_iter()
+            .zip(&callee.params)
+            .zip(args)
+            .map(|((param, (_, ty)), arg)| Let::BindLet(let_(&param, ty.clone(), arg)))
+            .collect();
+        lets.extend(body_lets);
+        BaseExpr::Block(Box::new(Expr_(lets, body_base)))
+    }
+}
+
+fn should_inline(fundef: &FunDef) -> bool {
+    match fundef.inline {
+        Inline::Always => true,
+        Inline::Never => false,
+        Inline::Auto => expr_cost(&fundef.body) <= INLINE_THRESHOLD,
+    }
+}
+
+fn expr_cost(expr: &Expr) -> usize {
+    let Expr_(lets, base) = expr;
+    lets.iter()
+        .map(|let_binding| match let_binding {
+            Let::BindLet(bind_let) | Let::VarLet(bind_let) => cost(&bind_let.value),
+            Let::NoBindLet(no_bind_let) => cost(&no_bind_let.value),
+            Let::TupleLet(tuple_let) => cost(&tuple_let.value),
+        })
+        .fold(cost(base), usize::saturating_add)
+}
+
+// Roughly the number of cells and groups `expr` turns into
+fn cost(expr: &BaseExpr) -> usize {
+    let sum = |exprs: &[&BaseExpr]| {
+        exprs
+            .iter()
+            .map(|expr| cost(expr))
+            .fold(0, usize::saturating_add)
+    };
+    match expr {
+        BaseExpr::Int(_) | BaseExpr::Bool(_) | BaseExpr::Var(_) => 0,
+        BaseExpr::Add(left, right)
+        | BaseExpr::Mul(left, right)
+        | BaseExpr::BinOp(_, left, right) => 1 + sum(&[left, right]),
+        BaseExpr::ArrayGet(_, index) => 1 + cost(index),
+        BaseExpr::ArraySet(_, index, value) => 1 + sum(&[index, value]),
+        BaseExpr::Assign(_, value) => 1 + cost(value),
+        BaseExpr::Field(value, _) => cost(value),
+        BaseExpr::Tuple(items) => items.iter().map(cost).fold(0, usize::saturating_add),
+        BaseExpr::Struct(_, fields) => fields
+            .iter()
+            .map(|(_, value)| cost(value))
+            .fold(0, usize::saturating_add),
+        BaseExpr::Call(_, args) => args.iter().map(cost).fold(1, usize::saturating_add),
+        BaseExpr::Block(body) => expr_cost(body),
+        BaseExpr::If(cond, then_branch, else_branch) => (1 + cost(cond))
+            .saturating_add(expr_cost(then_branch))
+            .saturating_add(expr_cost(else_branch)),
+        BaseExpr::NewArray(..)
+        | BaseExpr::Range(_)
+        | BaseExpr::Fill(..)
+        | BaseExpr::Map(..)
+        | BaseExpr::Reduce(..)
+        | BaseExpr::Scan(..)
+        | BaseExpr::For(..)
+        | BaseExpr::While(..) => LOOP_COST,
+    }
+}
+
+pub fn inline_program(program: Program) -> Program {
+    let mut inliner = Inliner {
+        fundefs: HashMap::new(),
+        renamer: AlphaContext::tagged("i"),
+    };
+    program
+        .into_iter()
+        .map(|item| match item {
+            TopLevel::FunDef(fundef) => TopLevel::FunDef(inliner.fundef(fundef)),
+            item => item,
+        })
+        .collect()
+}
@@ -0,0 +1,95 @@
+use crate::ast::{Program, TopLevel};
+use crate::parser::hls;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// Parses a program and the files it imports into a single program. Imported items come
+// before the items of the importing file, and every file is included once, however many
+// times it is imported.
+#[derive(Debug, Default)]
+struct Loader {
+    loaded: HashSet<PathBuf>,
+    // Files whose imports are being loaded, to report cycles
+    loading: Vec<PathBuf>,
+    // Top-level name -> file defining it
+    defined_in: HashMap<String, PathBuf>,
+    items: Vec<TopLevel>,
+}
+
+impl Loader {
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let path = path
+            .canonicalize()
+            .map_err(|err| anyhow::anyhow!("Cannot open {}: {}", path.display(), err))?;
+        if self.loading.contains(&path) {
+            return Err(anyhow::anyhow!("Import cycle through {}", path.display()));
+        }
+        if self.loaded.contains(&path) {
+            return Ok(());
+        }
+        let source = std::fs::read_to_string(&path)
+            .map_err(|err| anyhow::anyhow!("Cannot read {}: {}", path.display(), err))?;
+        self.loading.push(path.clone());
+        self.load_source(&source, &path)?;
+        self.loading.pop();
+        self.loaded.insert(path);
+        Ok(())
+    }
+
+    fn load_source(&mut self, source: &str, path: &Path) -> Result<()> {
+        let program =
+            hls::program(source).map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for item in program {
+            match item {
+                TopLevel::Import(import) => self.load_file(&dir.join(import))?,
+                item => {
+                    if let Some(name) = top_level_name(&item)
+                        && let Some(previous) = self.defined_in.insert(name.clone(), path.into())
+                    {
+                        if previous == path {
+                            return Err(anyhow::anyhow!(
+                                "{} is defined twice in {}",
+                                name,
+                                path.display()
+                            ));
+                        }
+                        return Err(anyhow::anyhow!(
+                            "{} is defined in both {} and {}",
+                            name,
+                            previous.display(),
+                            path.display()
+                        ));
+                    }
+                    self.items.push(item);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn top_level_name(item: &TopLevel) -> Option<String> {
+    match item {
+        TopLevel::Import(_) => None,
+        TopLevel::ExternalDecl(decl) => Some(decl.name.clone()),
+        TopLevel::ExternComponent(decl) => Some(decl.name.clone()),
+        TopLevel::StructDecl(decl) => Some(decl.name.clone()),
+        TopLevel::ConstDecl(decl) => Some(decl.name.clone()),
+        TopLevel::FunDef(fundef) => Some(fundef.name.clone()),
+    }
+}
+
+pub fn load_program(path: &Path) -> Result<Program> {
+    let mut loader = Loader::default();
+    loader.load_file(path)?;
+    Ok(loader.items)
+}
+
+// Imports are resolved relative to `path`, which does not have to exist
+pub fn load_program_from_source(source: &str, path: &Path) -> Result<Program> {
+    let mut loader = Loader::default();
+    loader.load_source(source, path)?;
+    Ok(loader.items)
+}
@@ -1,28 +1,104 @@
 pub mod a_normalize;
 pub mod alpha;
 pub mod ast;
+pub mod call_graph;
 pub mod calyx_ast;
+pub mod const_eval;
+pub mod const_fold;
 pub mod convert;
+pub mod cse;
+pub mod dead_code;
+pub mod fusion;
+pub mod inliner;
+pub mod loader;
+pub mod monomorphize;
 pub mod parser;
+pub mod strength_reduction;
+pub mod unroll;
 
 use alpha::alpha_convert_program;
 use anyhow::Result;
-use parser::hls;
+use std::path::Path;
+
+#[derive(Debug)]
+struct Options {
+    path: Option<String>,
+    // Function compiled to the entry point component
+    top: String,
+    // Lanes per iteration of every map, 1 to not unroll
+    unroll: usize,
+    // Lower associative reductions to a tree of par stages
+    tree_reduce: bool,
+}
+
+// hls [--top name] [--unroll K] [--tree-reduce] [file.hls]; the built-in example is compiled when no file is given
+fn parse_args() -> Result<Options> {
+    let mut options = Options {
+        path: None,
+        top: "main".to_string(),
+        unroll: 1,
+        tree_reduce: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--top" => {
+                options.top = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--top expects a function name"))?;
+            }
+            "--unroll" => {
+                options.unroll = args
+                    .next()
+                    .and_then(|factor| factor.parse().ok())
+                    .filter(|factor| *factor > 0)
+                    .ok_or_else(|| anyhow::anyhow!("--unroll expects a positive factor"))?;
+            }
+            "--tree-reduce" => options.tree_reduce = true,
+            _ if arg.starts_with("--") => {
+                return Err(anyhow::anyhow!("Unknown option {}", arg));
+            }
+            _ if options.path.is_none() => options.path = Some(arg),
+            _ => return Err(anyhow::anyhow!("Unexpected argument {}", arg)),
+        }
+    }
+    Ok(options)
+}
 
 fn main() -> Result<()> {
-    let program = hls::program(INPUT)?;
-    let alpha_converted = alpha_convert_program(&program);
-    let normalized = a_normalize::normalize_program(alpha_converted)?;
-    let mut converter = convert::Converter::init();
-    converter.convert(normalized)?;
-    println!("{}", converter.program);
+    let options = parse_args()?;
+    let program = match &options.path {
+        Some(path) => loader::load_program(Path::new(path))?,
+        None => loader::load_program_from_source(INPUT, Path::new("main.hls"))?,
+    };
+    println!("{}", compile(program, &options)?);
     Ok(())
 }
 
+// Runs the passes from a loaded program to the Calyx program
+fn compile(program: ast::Program, options: &Options) -> Result<calyx_ast::Program> {
+    let program = const_eval::eval_consts(program)?;
+    let program = call_graph::order_functions(program)?;
+    let alpha_converted = alpha_convert_program(&program, &options.top);
+    let monomorphized = monomorphize::monomorphize_program(alpha_converted)?;
+    let inlined = inliner::inline_program(monomorphized);
+    let normalized = a_normalize::normalize_program(inlined)?;
+    let folded = const_fold::fold_program(normalized);
+    let shared = cse::eliminate_common_subexpressions(folded);
+    let reduced = strength_reduction::reduce_strength(shared);
+    let live = dead_code::eliminate_dead_code(reduced, &options.top);
+    let fused = fusion::fuse_program(live, options.tree_reduce);
+    let mut converter = convert::Converter::init(&options.top);
+    converter.unroll = options.unroll;
+    converter.tree_reduce = options.tree_reduce;
+    converter.convert(fused)?;
+    Ok(converter.program)
+}
+
 static INPUT: &str = r#"
 external a: i32[4];
 external b: i32[4];
-external out: i32[1];
+external out: i32;
 
 fn add(a: i32, b: i32) -> i32 = a + b;
 
@@ -30,5 +106,317 @@ fn main() =
     let sum_a_b: i32[4] = map(a, b, (x, y) => add(x, y)) in
     let squared: i32[4] = map(sum_a_b, (x) => x * x) in
     let result: i32 = reduce(squared, 0, (x, y) => add(x, y)) in
-    out[0] := result;
+    out := result;
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calyx_ast::{Circuit, Component, Control};
+    use std::collections::HashSet;
+
+    fn compile_source(source: &str, unroll: usize, tree_reduce: bool) -> calyx_ast::Program {
+        let options = Options {
+            path: None,
+            top: "main".to_string(),
+            unroll,
+            tree_reduce,
+        };
+        let program = loader::load_program_from_source(source, Path::new("test.hls")).unwrap();
+        compile(program, &options).unwrap()
+    }
+
+    // The memories whose address the groups under `control` drive
+    fn addressed_memories(
+        component: &Component,
+        control: &Control,
+        memories: &mut HashSet<String>,
+    ) {
+        match control {
+            Control::Seq(controls) | Control::Par(controls) => {
+                for control in controls {
+                    addressed_memories(component, control, memories);
+                }
+            }
+            Control::While { body, .. } => {
+                for control in body {
+                    addressed_memories(component, control, memories);
+                }
+            }
+            Control::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                for control in then_branch.iter().chain(else_branch) {
+                    addressed_memories(component, control, memories);
+                }
+            }
+            Control::GroupName(name) => {
+                let group = component
+                    .wires
+                    .groups
+                    .iter()
+                    .find(|group| group.name == *name);
+                for wire in group.into_iter().flat_map(|group| &group.wires) {
+                    if wire.dest.port == "addr0" {
+                        memories.insert(wire.dest.cell.clone());
+                    }
+                }
+            }
+            Control::Invoke { .. } => {}
+        }
+    }
+
+    // Fails when two branches of a par drive the address of the same memory
+    fn assert_no_par_conflicts(component: &Component, control: &Control) {
+        let children: Vec<&Control> = match control {
+            Control::Seq(controls) => controls.iter().collect(),
+            Control::Par(controls) => {
+                let mut seen = HashSet::new();
+                for branch in controls {
+                    let mut memories = HashSet::new();
+                    addressed_memories(component, branch, &mut memories);
+                    for memory in memories {
+                        assert!(
+                            seen.insert(memory.clone()),
+                            "{} is addressed in par",
+                            memory
+                        );
+                    }
+                }
+                controls.iter().collect()
+            }
+            Control::While { body, .. } => body.iter().collect(),
+            Control::If {
+                then_branch,
+                else_branch,
+                ..
+            } => then_branch.iter().chain(else_branch).collect(),
+            Control::GroupName(_) | Control::Invoke { .. } => vec![],
+        };
+        for child in children {
+            assert_no_par_conflicts(component, child);
+        }
+    }
+
+    fn used_groups(control: &Control, groups: &mut HashSet<String>) {
+        match control {
+            Control::Seq(controls) | Control::Par(controls) => {
+                for control in controls {
+                    used_groups(control, groups);
+                }
+            }
+            Control::While { with, body, .. } => {
+                groups.extend(with.clone());
+                for control in body {
+                    used_groups(control, groups);
+                }
+            }
+            Control::If {
+                with,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                groups.extend(with.clone());
+                for control in then_branch.iter().chain(else_branch) {
+                    used_groups(control, groups);
+                }
+            }
+            Control::GroupName(name) => {
+                groups.insert(name.clone());
+            }
+            Control::Invoke { .. } => {}
+        }
+    }
+
+    fn assert_groups_used(component: &Component) {
+        let mut groups = HashSet::new();
+        for control in &component.control {
+            used_groups(control, &mut groups);
+        }
+        for group in &component.wires.groups {
+            assert!(groups.contains(&group.name), "{} is never run", group.name);
+        }
+    }
+
+    fn memory_lengths(component: &Component) -> Vec<usize> {
+        component
+            .cells
+            .iter()
+            .filter_map(|cell| match cell.circuit {
+                Circuit::CombMemD1 { len, .. } if !cell.is_external => Some(len),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unrolled_lanes_take_turns_on_a_shared_memory() {
+        let program = compile_source(
+            r#"
+external a: i32[4];
+external b: i32[4];
+external out: i32[4];
+fn main() =
+    let c: i32[4] = mapi(a, (i, x) => x + b[i]) in
+    for i in 0..4 do out[i] := c[i];
+"#,
+            2,
+            false,
+        );
+        for control in &program.main.control {
+            assert_no_par_conflicts(&program.main, control);
+        }
+        // Nothing is banked, so there is no bank address to set
+        assert_groups_used(&program.main);
+    }
+
+    #[test]
+    fn maps_feeding_maps_are_banked_per_lane() {
+        let program = compile_source(
+            r#"
+external a: i32[7];
+external out: i32[7];
+fn main() =
+    let c: i32[7] = map(a, (x) => x + 1) in
+    let d: i32[7] = map(c, (x) => x * 3) in
+    let e: i32[7] = map(c, d, (x, y) => x + y) in
+    for i in 0..7 do out[i] := e[i];
+"#,
+            3,
+            false,
+        );
+        for control in &program.main.control {
+            assert_no_par_conflicts(&program.main, control);
+        }
+        // `c` in 3 banks of ceil(7 / 3) elements, `e` in one memory read element by element
+        let mut lengths = memory_lengths(&program.main);
+        lengths.sort();
+        assert_eq!(lengths, vec![3, 3, 3, 7]);
+    }
+
+    fn count_cells(component: &Component, circuit: fn(&Circuit) -> bool) -> usize {
+        component
+            .cells
+            .iter()
+            .filter(|cell| circuit(&cell.circuit))
+            .count()
+    }
+
+    fn has_loop(control: &Control) -> bool {
+        match control {
+            Control::While { .. } => true,
+            Control::Seq(controls) | Control::Par(controls) => controls.iter().any(has_loop),
+            Control::If {
+                then_branch,
+                else_branch,
+                ..
+            } => then_branch.iter().chain(else_branch).any(has_loop),
+            Control::GroupName(_) | Control::Invoke { .. } => false,
+        }
+    }
+
+    fn sum_of_external(size: usize) -> calyx_ast::Program {
+        let source = format!(
+            r#"
+external a: i32[{}];
+external out: i32;
+fn main() =
+    let s: i32 = reduce(a, 0, (acc, x) => acc + x) in
+    out := s;
+"#,
+            size
+        );
+        compile_source(&source, 1, true)
+    }
+
+    #[test]
+    fn tree_reduces_without_a_loop() {
+        // size - 1 adders in the tree and one for the initial value
+        for size in [0, 1, 2, 5, 8] {
+            let program = sum_of_external(size);
+            let adders = count_cells(&program.main, |circuit| {
+                matches!(circuit, Circuit::StdAdd { .. })
+            });
+            assert_eq!(adders, size, "size {}", size);
+            assert!(!program.main.control.iter().any(has_loop), "size {}", size);
+            for control in &program.main.control {
+                assert_no_par_conflicts(&program.main, control);
+            }
+            assert_groups_used(&program.main);
+        }
+    }
+
+    #[test]
+    fn large_reductions_stay_a_loop() {
+        let program = sum_of_external(convert::MAX_TREE_REDUCE_SIZE + 1);
+        assert!(program.main.control.iter().any(has_loop));
+    }
+
+    #[test]
+    fn tree_reads_the_banks_of_an_unrolled_map_in_par() {
+        let program = compile_source(
+            r#"
+external a: i32[6];
+external out: i32;
+fn main() =
+    let t: i32[6] = map(a, (x) => x * 3) in
+    let s: i32 = reduce(t, 0, (acc, y) => acc + y) in
+    out := s;
+"#,
+            2,
+            true,
+        );
+        for control in &program.main.control {
+            assert_no_par_conflicts(&program.main, control);
+        }
+        assert_eq!(memory_lengths(&program.main), vec![3, 3]);
+    }
+
+    #[test]
+    fn struct_elements_read_only_the_accessed_fields() {
+        let program = compile_source(
+            r#"
+struct Px { r: i32, g: i32, b: i32 };
+external pix: Px[4];
+external out: i32;
+fn main() =
+    out := pix[2].g;
+"#,
+            1,
+            false,
+        );
+        let mut memories = HashSet::new();
+        for control in &program.main.control {
+            addressed_memories(&program.main, control, &mut memories);
+        }
+        assert_eq!(memories, HashSet::from(["pix_g".to_string()]));
+    }
+
+    #[test]
+    fn maps_over_struct_arrays_are_rejected() {
+        let source = r#"
+struct Px { r: i32, g: i32 };
+external pix: Px[4];
+external out: i32[4];
+fn main() =
+    let t: i32[4] = map(pix, (p) => p.r + p.g) in
+    out[0] := t[1];
+"#;
+        let options = Options {
+            path: None,
+            top: "main".to_string(),
+            unroll: 1,
+            tree_reduce: false,
+        };
+        let program = loader::load_program_from_source(source, Path::new("test.hls")).unwrap();
+        let error = compile(program, &options).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("an array of struct Px, is not supported")
+        );
+    }
+}
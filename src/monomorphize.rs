@@ -0,0 +1,311 @@
+use crate::ast::*;
+use crate::const_eval::Substitution;
+use anyhow::Result;
+use std::collections::HashMap;
+
+// Specializes functions with size parameters, `fn sum<N>(a: i32[N])`, once per distinct
+// list of sizes they are called with. Runs after alpha conversion, so that every variable
+// name is unique and a flat type map is enough to infer the sizes from the arguments.
+#[derive(Debug, Default)]
+struct Monomorphizer {
+    templates: HashMap<Ident, FunDef>,
+    // (template, sizes) -> specialized name
+    instances: HashMap<(Ident, Vec<usize>), Ident>,
+    // Specializations still to be generated: (template, sizes, specialized name)
+    worklist: Vec<(Ident, Vec<usize>, Ident)>,
+    // Types of the externals and of the variables seen so far
+    types: HashMap<Ident, Type>,
+}
+
+impl Monomorphizer {
+    fn fundef(&mut self, fundef: FunDef) -> Result<FunDef> {
+        for (name, ty) in &fundef.params {
+            self.types.insert(name.clone(), ty.clone());
+        }
+        let body = self.expr(fundef.body)?;
+        Ok(FunDef { body, ..fundef })
+    }
+
+    fn specialize(&mut self, template: &str, sizes: &[usize], name: Ident) -> Result<FunDef> {
+        let template = &self.templates[template];
+        let mut subst = Substitution::default();
+        for (size_param, size) in template.size_params.iter().zip(sizes) {
+            subst.insert(size_param.clone(), BaseExpr::Int(*size as i32));
+        }
+        let specialized = subst.fundef(&FunDef {
+            size_params: vec![],
+            ..template.clone()
+        })?;
+        self.fundef(FunDef {
+            name,
+            ..specialized
+        })
+    }
+
+    fn expr(&mut self, expr: Expr) -> Result<Expr> {
+        let Expr_(lets, base) = expr;
+        let mut new_lets = vec![];
+        for let_binding in lets {
+            new_lets.push(self.let_binding(let_binding)?);
+        }
+        Ok(Expr_(new_lets, self.base_expr(base)?))
+    }
+
+    fn let_binding(&mut self, let_binding: Let) -> Result<Let> {
+        Ok(match let_binding {
+            Let::BindLet(bind_let) => {
+                self.types
+                    .insert(bind_let.name.clone(), bind_let.ty.clone());
+                Let::BindLet(BindLet {
+                    value: self.base_expr(bind_let.value)?,
+                    ..bind_let
+                })
+            }
+            Let::VarLet(var_let) => {
+                self.types.insert(var_let.name.clone(), var_let.ty.clone());
+                Let::VarLet(BindLet {
+                    value: self.base_expr(var_let.value)?,
+                    ..var_let
+                })
+            }
+            Let::NoBindLet(no_bind_let) => Let::NoBindLet(NoBindLet {
+                value: self.base_expr(no_bind_let.value)?,
+            }),
+            Let::TupleLet(tuple_let) => {
+                if let Some(Type::Tuple(tys)) = &tuple_let.ty {
+                    for (name, ty) in tuple_let.names.iter().zip(tys) {
+                        self.types.insert(name.clone(), ty.clone());
+                    }
+                }
+                Let::TupleLet(TupleLet {
+                    value: self.base_expr(tuple_let.value)?,
+                    ..tuple_let
+                })
+            }
+        })
+    }
+
+    fn boxed(&mut self, expr: BaseExpr) -> Result<Box<BaseExpr>> {
+        Ok(Box::new(self.base_expr(expr)?))
+    }
+
+    fn boxed_expr(&mut self, expr: Expr) -> Result<Box<Expr>> {
+        Ok(Box::new(self.expr(expr)?))
+    }
+
+    fn base_expr(&mut self, expr: BaseExpr) -> Result<BaseExpr> {
+        Ok(match expr {
+            BaseExpr::Int(_) | BaseExpr::Bool(_) | BaseExpr::Var(_) => expr,
+            BaseExpr::NewArray(..) | BaseExpr::Range(_) => expr,
+            BaseExpr::Add(left, right) => BaseExpr::Add(self.boxed(*left)?, self.boxed(*right)?),
+            BaseExpr::Mul(left, right) => BaseExpr::Mul(self.boxed(*left)?, self.boxed(*right)?),
+            BaseExpr::BinOp(op, left, right) => {
+                BaseExpr::BinOp(op, self.boxed(*left)?, self.boxed(*right)?)
+            }
+            BaseExpr::Fill(ty, size, value) => BaseExpr::Fill(ty, size, self.boxed(*value)?),
+            BaseExpr::Map(arrays, index, params, body) => {
+                let arrays = arrays
+                    .into_iter()
+                    .map(|array| self.base_expr(array))
+                    .collect::<Result<_>>()?;
+                BaseExpr::Map(arrays, index, params, self.boxed_expr(*body)?)
+            }
+            BaseExpr::Reduce(array, init, param1, param2, body) => BaseExpr::Reduce(
+                self.boxed(*array)?,
+                self.boxed(*init)?,
+                param1,
+                param2,
+                self.boxed_expr(*body)?,
+            ),
+            BaseExpr::Scan(array, init, param1, param2, body) => BaseExpr::Scan(
+                self.boxed(*array)?,
+                self.boxed(*init)?,
+                param1,
+                param2,
+                self.boxed_expr(*body)?,
+            ),
+            BaseExpr::Call(name, args) => {
+                let args: Vec<BaseExpr> = args
+                    .into_iter()
+                    .map(|arg| self.base_expr(arg))
+                    .collect::<Result<_>>()?;
+                let name = if self.templates.contains_key(&name) {
+                    self.instantiate(&name, &args)?
+                } else {
+                    name
+                };
+                BaseExpr::Call(name, args)
+            }
+            BaseExpr::ArraySet(name, index, value) => {
+                BaseExpr::ArraySet(name, self.boxed(*index)?, self.boxed(*value)?)
+            }
+            BaseExpr::ArrayGet(name, index) => BaseExpr::ArrayGet(name, self.boxed(*index)?),
+            BaseExpr::Tuple(items) => BaseExpr::Tuple(
+                items
+                    .into_iter()
+                    .map(|item| self.base_expr(item))
+                    .collect::<Result<_>>()?,
+            ),
+            BaseExpr::For(counter, start, end, body) => {
+                self.types.insert(counter.clone(), Type::i32());
+                BaseExpr::For(counter, start, end, self.boxed_expr(*body)?)
+            }
+            BaseExpr::Assign(name, value) => BaseExpr::Assign(name, self.boxed(*value)?),
+            BaseExpr::Field(value, field) => BaseExpr::Field(self.boxed(*value)?, field),
+            BaseExpr::Struct(name, fields) => BaseExpr::Struct(
+                name,
+                fields
+                    .into_iter()
+                    .map(|(field, value)| Ok((field, self.base_expr(value)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            BaseExpr::Block(body) => BaseExpr::Block(self.boxed_expr(*body)?),
+            BaseExpr::While(cond, body) => {
+                BaseExpr::While(self.boxed(*cond)?, self.boxed_expr(*body)?)
+            }
+            BaseExpr::If(cond, then_branch, else_branch) => BaseExpr::If(
+                self.boxed(*cond)?,
+                self.boxed_expr(*then_branch)?,
+                self.boxed_expr(*else_branch)?,
+            ),
+        })
+    }
+
+    fn arg_type(&self, arg: &BaseExpr) -> Option<Type> {
+        match arg {
+            BaseExpr::Int(_) => Some(Type::i32()),
+            BaseExpr::Bool(_) => Some(Type::bool()),
+            BaseExpr::Var(name) => self.types.get(name).cloned(),
+            BaseExpr::NewArray(ty, Size::Lit(size)) | BaseExpr::Fill(ty, Size::Lit(size), _) => {
+                Some(Type::Array(ty.clone(), *size))
+            }
+            BaseExpr::Range(Size::Lit(size)) => Some(Type::array(Type::i32(), *size)),
+            _ => None,
+        }
+    }
+
+    // Returns the name of the specialization of `template` matching the argument types
+    fn instantiate(&mut self, template: &str, args: &[BaseExpr]) -> Result<Ident> {
+        let fundef = &self.templates[template];
+        if fundef.params.len() != args.len() {
+            return Err(anyhow::anyhow!(
+                "Function {} expects {} arguments, but {} were given",
+                template,
+                fundef.params.len(),
+                args.len()
+            ));
+        }
+        let mut bound = HashMap::new();
+        for ((param_name, param_ty), arg) in fundef.params.iter().zip(args) {
+            if let Some(arg_ty) = self.arg_type(arg) {
+                unify(param_ty, &arg_ty, &mut bound).map_err(|err| {
+                    anyhow::anyhow!("Argument {} of {}: {}", param_name, template, err)
+                })?;
+            }
+        }
+        let sizes = fundef
+            .size_params
+            .iter()
+            .map(|size_param| {
+                bound.get(size_param).copied().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Cannot infer size {} of {}; bind the arguments with annotated lets",
+                        size_param,
+                        template
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let key = (template.to_string(), sizes.clone());
+        if let Some(name) = self.instances.get(&key) {
+            return Ok(name.clone());
+        }
+        let sizes_str: Vec<String> = sizes.iter().map(|size| size.to_string()).collect();
+        let name = format!("{}__{}", template, sizes_str.join("_"));
+        self.instances.insert(key, name.clone());
+        self.worklist
+            .push((template.to_string(), sizes, name.clone()));
+        Ok(name)
+    }
+}
+
+// Binds the size parameters in `param_ty` by matching it against `arg_ty`
+fn unify(param_ty: &Type, arg_ty: &Type, bound: &mut HashMap<Ident, usize>) -> Result<()> {
+    match (param_ty, arg_ty) {
+        (Type::SymbolicArray(param_inner, name), Type::Array(arg_inner, size)) => {
+            if let Some(previous) = bound.insert(name.clone(), *size)
+                && previous != *size
+            {
+                return Err(anyhow::anyhow!(
+                    "size {} is both {} and {}",
+                    name,
+                    previous,
+                    size
+                ));
+            }
+            unify(param_inner, arg_inner, bound)
+        }
+        (Type::Array(param_inner, _), Type::Array(arg_inner, _)) => {
+            unify(param_inner, arg_inner, bound)
+        }
+        (Type::Tuple(param_tys), Type::Tuple(arg_tys)) if param_tys.len() == arg_tys.len() => {
+            for (param_ty, arg_ty) in param_tys.iter().zip(arg_tys) {
+                unify(param_ty, arg_ty, bound)?;
+            }
+            Ok(())
+        }
+        // Mismatches other than sizes are reported by the type checks of later passes
+        _ => Ok(()),
+    }
+}
+
+pub fn monomorphize_program(program: Program) -> Result<Program> {
+    let mut mono = Monomorphizer::default();
+    for item in &program {
+        match item {
+            TopLevel::ExternalDecl(decl) => {
+                mono.types.insert(decl.name.clone(), decl.ty.clone());
+            }
+            TopLevel::FunDef(fundef) if !fundef.size_params.is_empty() => {
+                mono.templates.insert(fundef.name.clone(), fundef.clone());
+            }
+            _ => {}
+        }
+    }
+
+    let mut items = vec![];
+    for item in program {
+        match item {
+            TopLevel::FunDef(fundef) if fundef.size_params.is_empty() => {
+                items.push(TopLevel::FunDef(mono.fundef(fundef)?));
+            }
+            item => items.push(item),
+        }
+    }
+
+    // Specializing a function can request more specializations
+    let mut specializations: HashMap<Ident, Vec<FunDef>> = HashMap::new();
+    while let Some((template, sizes, name)) = mono.worklist.pop() {
+        let specialized = mono.specialize(&template, &sizes, name)?;
+        specializations
+            .entry(template)
+            .or_default()
+            .push(specialized);
+    }
+
+    // Specializations take the place of their template, so that they are still defined
+    // before their callers
+    let mut result = vec![];
+    for item in items {
+        match item {
+            TopLevel::FunDef(fundef) if !fundef.size_params.is_empty() => {
+                let mut instances = specializations.remove(&fundef.name).unwrap_or_default();
+                instances.sort_by(|a, b| a.name.cmp(&b.name));
+                result.extend(instances.into_iter().map(TopLevel::FunDef));
+            }
+            item => result.push(item),
+        }
+    }
+    Ok(result)
+}
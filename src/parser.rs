@@ -7,7 +7,11 @@ parser! {
             = _ items:item() ** whitespace() _ { items }
 
         rule item() -> TopLevel
-            = ed:external_decl() { TopLevel::ExternalDecl(ed) }
+            = "import" _ "\"" path:$((!"\"" [_])*) "\"" _ ";" { TopLevel::Import(path.to_string()) }
+            / ec:extern_component() { TopLevel::ExternComponent(ec) }
+            / ed:external_decl() { TopLevel::ExternalDecl(ed) }
+            / sd:struct_decl() { TopLevel::StructDecl(sd) }
+            / cd:const_decl() { TopLevel::ConstDecl(cd) }
             / fd:fundef() { TopLevel::FunDef(fd) }
 
         rule external_decl() -> ExternalDecl
@@ -15,17 +19,41 @@ parser! {
                 ExternalDecl { name, ty }
             }
 
+        rule extern_component() -> ExternComponent
+            = "extern" whitespace() "component" whitespace() name:identifier() _ "(" _ inputs:param_list() _ ")" _ "->" _ "(" _ outputs:param_list() _ ")" _ "from" _ "\"" path:$((!"\"" [_])*) "\"" _ ";" {
+                ExternComponent { name, inputs, outputs, path: path.to_string() }
+            }
+
+        rule struct_decl() -> StructDecl
+            = "struct" _ name:identifier() _ "{" _ fields:param() ** (_ "," _) _ ","? _ "}" _ ";" {
+                StructDecl { name, fields }
+            }
+
+        rule const_decl() -> ConstDecl
+            = "const" _ name:identifier() _ ":" _ ty:type_annotation() _ "=" _ value:base_expr() _ ";" {
+                ConstDecl { name, ty, value }
+            }
+
         pub rule fundef() -> FunDef
-            = "fn" _ name:identifier() _ "(" _ params:param_list() _ ")" _ return_type:return_type_annotation()? _ "=" _ body:expr() _ ";" {
-                FunDef { name, params, return_type, body }
+            = inline:inline_attribute() "fn" _ name:identifier() _ size_params:size_params() _ "(" _ params:param_list() _ ")" _ return_type:return_type_annotation()? _ "=" _ body:expr() _ ";" {
+                FunDef { name, inline, size_params, params, return_type, body }
             }
-            / "fn" _ name:identifier() _ "()" _ return_type:return_type_annotation()? _ "=" _ body:expr() _ ";" {
-                FunDef { name, params: vec![], return_type, body }
+            / inline:inline_attribute() "fn" _ name:identifier() _ size_params:size_params() _ "()" _ return_type:return_type_annotation()? _ "=" _ body:expr() _ ";" {
+                FunDef { name, inline, size_params, params: vec![], return_type, body }
             }
-            / "fn" _ name:identifier() _ return_type:return_type_annotation()? _ "=" _ body:expr() _ ";" {
-                FunDef { name, params: vec![], return_type, body }
+            / inline:inline_attribute() "fn" _ name:identifier() _ return_type:return_type_annotation()? _ "=" _ body:expr() _ ";" {
+                FunDef { name, inline, size_params: vec![], params: vec![], return_type, body }
             }
 
+        rule inline_attribute() -> Inline
+            = "inline" whitespace() { Inline::Always }
+            / "noinline" whitespace() { Inline::Never }
+            / { Inline::Auto }
+
+        rule size_params() -> Vec<Ident>
+            = "<" _ names:identifier() ++ (_ "," _) _ ">" { names }
+            / { vec![] }
+
         rule param_list() -> Vec<(Ident, Type)>
             = params:param() ** (_ "," _) { params }
             / { vec![] }
@@ -40,11 +68,16 @@ parser! {
             = inner:basic_type() _ "[" _ size:number() _ "]" {
                 Type::Array(Box::new(inner), size as usize)
             }
+            / inner:basic_type() _ "[" _ size:identifier() _ "]" {
+                Type::SymbolicArray(Box::new(inner), size)
+            }
             / basic_type()
+            / "(" _ tys:type_annotation() **<2,> (_ "," _) _ ")" { Type::Tuple(tys) }
 
         rule basic_type() -> Type
             = "i32" { Type::I(32) }
             / "bool" { Type::I(1) }
+            / name:identifier() { Type::Struct(name) }
 
         pub rule expr() -> Expr
             = lets:let_bindings() _ "in" _ base:base_expr() {
@@ -64,13 +97,36 @@ parser! {
             / "let" _ name:identifier() _ ":" _ ty:type_annotation() _ "=" _ value:base_expr() {
                 Let::BindLet(BindLet { name, ty, value })
             }
+            / "var" _ name:identifier() _ ":" _ ty:type_annotation() _ "=" _ value:base_expr() {
+                Let::VarLet(BindLet { name, ty, value })
+            }
+            / "let" _ "(" _ names:identifier() **<2,> (_ "," _) _ ")" _ ty:(":" _ ty:type_annotation() _ { ty })? "=" _ value:base_expr() {
+                Let::TupleLet(TupleLet { names, ty, value })
+            }
 
         pub rule base_expr() -> BaseExpr
             = array:identifier() _ "[" _ index:base_expr() _ "]" _ ":=" _ value:base_expr() {
                 BaseExpr::ArraySet(array, Box::new(index), Box::new(value))
             }
+            / name:identifier() _ ":=" _ value:base_expr() {
+                BaseExpr::Assign(name, Box::new(value))
+            }
             / precedence! {
+                left:(@) _ "==" _ right:@ { BaseExpr::BinOp(BinOp::Eq, Box::new(left), Box::new(right)) }
+                left:(@) _ "!=" _ right:@ { BaseExpr::BinOp(BinOp::Neq, Box::new(left), Box::new(right)) }
+                left:(@) _ "<=" _ right:@ { BaseExpr::BinOp(BinOp::Le, Box::new(left), Box::new(right)) }
+                left:(@) _ ">=" _ right:@ { BaseExpr::BinOp(BinOp::Ge, Box::new(left), Box::new(right)) }
+                left:(@) _ "<" _ right:@ { BaseExpr::BinOp(BinOp::Lt, Box::new(left), Box::new(right)) }
+                left:(@) _ ">" _ right:@ { BaseExpr::BinOp(BinOp::Gt, Box::new(left), Box::new(right)) }
+                --
+                left:(@) _ "|" _ right:@ { BaseExpr::BinOp(BinOp::Or, Box::new(left), Box::new(right)) }
+                --
+                left:(@) _ "^" _ right:@ { BaseExpr::BinOp(BinOp::Xor, Box::new(left), Box::new(right)) }
+                --
+                left:(@) _ "&" _ right:@ { BaseExpr::BinOp(BinOp::And, Box::new(left), Box::new(right)) }
+                --
                 left:(@) _ "+" _ right:@ { BaseExpr::Add(Box::new(left), Box::new(right)) }
+                left:(@) _ "-" _ right:@ { BaseExpr::BinOp(BinOp::Sub, Box::new(left), Box::new(right)) }
                 --
                 left:(@) _ "*" _ right:@ { BaseExpr::Mul(Box::new(left), Box::new(right)) }
                 --
@@ -81,22 +137,91 @@ parser! {
             = n:number() { BaseExpr::Int(n) }
             / b:boolean() { BaseExpr::Bool(b) }
             / func_call:function_call() { func_call }
+            / "for" _ counter:identifier() _ "in" _ start:size() _ ".." _ end:size() _ "do" _ body:expr() {
+                BaseExpr::For(counter, start, end, Box::new(body))
+            }
+            / "while" _ cond:base_expr() _ "do" _ body:expr() {
+                BaseExpr::While(Box::new(cond), Box::new(body))
+            }
+            / "if" _ cond:base_expr() _ "then" _ then_branch:expr() _ "else" _ else_branch:expr() {
+                BaseExpr::If(Box::new(cond), Box::new(then_branch), Box::new(else_branch))
+            }
+            / array:identifier() _ "[" _ index:base_expr() _ "]" _ "." _ field:identifier() {
+                BaseExpr::Field(Box::new(BaseExpr::ArrayGet(array, Box::new(index))), field)
+            }
+            / array:identifier() _ "[" _ index:base_expr() _ "]" {
+                BaseExpr::ArrayGet(array, Box::new(index))
+            }
+            / name:identifier() "." field:identifier() {
+                BaseExpr::Field(Box::new(BaseExpr::Var(name)), field)
+            }
+            / name:identifier() _ "{" _ fields:(field:identifier() _ ":" _ value:base_expr() { (field, value) }) ** (_ "," _) _ ","? _ "}" {
+                BaseExpr::Struct(name, fields)
+            }
             / id:identifier() { BaseExpr::Var(id) }
             / "(" _ e:base_expr() _ ")" { e }
+            / "(" _ items:base_expr() **<2,> (_ "," _) _ ")" !(_ "=>") { BaseExpr::Tuple(items) }
 
         rule function_call() -> BaseExpr
-            = "new_array" _ "<" _ ty:type_annotation() _ ">" _ "[" _ size:number() _ "]" {
-                BaseExpr::NewArray(Box::new(ty), size as usize)
+            = "new_array" _ "<" _ ty:type_annotation() _ ">" _ "[" _ size:size() _ "]" {
+                BaseExpr::NewArray(Box::new(ty), size)
+            }
+            / "range" _ "<" _ size:size() _ ">" _ "(" _ ")" {
+                BaseExpr::Range(size)
+            }
+            / "fill" _ "<" _ ty:type_annotation() _ ">" _ "[" _ size:size() _ "]" _ "(" _ value:base_expr() _ ")" {
+                BaseExpr::Fill(Box::new(ty), size, Box::new(value))
+            }
+            / "mapi" _ "(" _ arrays:array_list() _ "," _ lambda:lambda_expr_multi() _ ")" {?
+                let (mut params, body) = lambda;
+                if params.len() < 2 {
+                    Err("index and element parameters")
+                } else {
+                    let index = params.remove(0);
+                    Ok(BaseExpr::Map(arrays, Some(index), params, Box::new(body)))
+                }
+            }
+            / "mapi" _ "(" _ args:array_list() _ ")" {?
+                let mut arrays = args;
+                match arrays.pop() {
+                    Some(BaseExpr::Var(fun_name)) if !arrays.is_empty() => {
+                        let (mut params, body) = function_lambda(fun_name, arrays.len() + 1);
+                        let index = params.remove(0);
+                        Ok(BaseExpr::Map(arrays, Some(index), params, Box::new(body)))
+                    }
+                    _ => Err("lambda or function name"),
+                }
             }
             / "map" _ "(" _ arrays:array_list() _ "," _ lambda:lambda_expr_multi() _ ")" {
-                BaseExpr::Map(arrays, lambda.0, Box::new(lambda.1))
+                BaseExpr::Map(arrays, None, lambda.0, Box::new(lambda.1))
             }
             / "map" _ "(" _ array:base_expr() _ "," _ lambda:lambda_expr() _ ")" {
-                BaseExpr::Map(vec![array], vec![lambda.0], Box::new(lambda.1))
+                BaseExpr::Map(vec![array], None, vec![lambda.0], Box::new(lambda.1))
+            }
+            / "map" _ "(" _ args:array_list() _ ")" {?
+                let mut arrays = args;
+                match arrays.pop() {
+                    Some(BaseExpr::Var(fun_name)) if !arrays.is_empty() => {
+                        let (params, body) = function_lambda(fun_name, arrays.len());
+                        Ok(BaseExpr::Map(arrays, None, params, Box::new(body)))
+                    }
+                    _ => Err("lambda or function name"),
+                }
             }
             / "reduce" _ "(" _ array:base_expr() _ "," _ init_value:base_expr() _ "," _ lambda:lambda_expr_2() _ ")" {
                 BaseExpr::Reduce(Box::new(array), Box::new(init_value), lambda.0, lambda.1, Box::new(lambda.2))
             }
+            / "reduce" _ "(" _ array:base_expr() _ "," _ init_value:base_expr() _ "," _ fun_name:identifier() _ ")" {
+                let (params, body) = function_lambda(fun_name, 2);
+                BaseExpr::Reduce(Box::new(array), Box::new(init_value), params[0].clone(), params[1].clone(), Box::new(body))
+            }
+            / "scan" _ "(" _ array:base_expr() _ "," _ init_value:base_expr() _ "," _ lambda:lambda_expr_2() _ ")" {
+                BaseExpr::Scan(Box::new(array), Box::new(init_value), lambda.0, lambda.1, Box::new(lambda.2))
+            }
+            / "scan" _ "(" _ array:base_expr() _ "," _ init_value:base_expr() _ "," _ fun_name:identifier() _ ")" {
+                let (params, body) = function_lambda(fun_name, 2);
+                BaseExpr::Scan(Box::new(array), Box::new(init_value), params[0].clone(), params[1].clone(), Box::new(body))
+            }
             / name:identifier() _ "(" _ args:argument_list() _ ")" {
                 BaseExpr::Call(name, args)
             }
@@ -126,6 +251,10 @@ parser! {
                 (param1, param2, Expr_(vec![], body))
             }
 
+        rule size() -> Size
+            = n:number() { Size::Lit(n as usize) }
+            / name:identifier() { Size::Var(name) }
+
         rule number() -> i32
             = n:$(['0'..='9']+) {? n.parse().or(Err("number")) }
 
@@ -141,7 +270,8 @@ parser! {
             }
 
         rule reserved()
-            = "fn" / "let" / "in" / "map" / "reduce" / "new_array" / "true" / "false" / "i32" / "bool" / "array" / "=>" / "external"
+            = ("fn" / "let" / "in" / "mapi" / "map" / "reduce" / "scan" / "new_array" / "range" / "fill" / "for" / "do" / "var" / "while" / "if" / "then" / "else" / "struct" / "const" / "import" / "inline" / "noinline" / "true" / "false" / "i32" / "bool" / "array" / "=>" / "external" / "extern")
+              !['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
 
         rule _() = quiet!{ (whitespace_char() / line_comment())* }
         rule whitespace() = quiet!{ (whitespace_char() / line_comment())+ }
@@ -149,3 +279,11 @@ parser! {
         rule line_comment() = "//" (!"\n" [_])* "\n"?
     }
 }
+
+// `f` in lambda position is sugar for `(_arg0, ..., _argN) => f(_arg0, ..., _argN)`.
+// Alpha conversion renames the parameters and resolves `f` to the function.
+fn function_lambda(fun_name: Ident, arity: usize) -> (Vec<Ident>, Expr) {
+    let params: Vec<Ident> = (0..arity).map(|i| format!("_arg{}", i)).collect();
+    let args = params.iter().map(|param| BaseExpr::var(param)).collect();
+    (params, Expr_(vec![], BaseExpr::Call(fun_name, args)))
+}
@@ -0,0 +1,319 @@
+use crate::ast::*;
+use std::collections::HashMap;
+
+// Replaces multiplications by a constant, which go through a pipelined multiplier with
+// its go/done handshake, by shifts and additions, which are combinational:
+//
+//   x * 8   ~> x << 3
+//   x * 10  ~> (x << 3) + (x << 1)
+//   x * 7   ~> (x << 3) - x
+//   x * 27  ~> ((x << 5) - (x << 2)) - x
+//
+// The constant is written with digits 1, 0 and -1 (its non-adjacent form), which has the
+// fewest nonzero digits, and each nonzero digit becomes a shifted term. Constants needing
+// more than MAX_TERMS terms stay on the multiplier, as the chain of adders would be
+// slower and larger than it.
+const MAX_TERMS: usize = 3;
+
+struct StrengthReduction {
+    // Variables bound to a non-negative constant, with its type
+    consts: HashMap<Ident, (u32, Type)>,
+    // Products returned from a scope, bound to a fresh name
+    products: usize,
+}
+
+impl StrengthReduction {
+    fn expr(&mut self, expr: ANormalExpr) -> ANormalExpr {
+        let Expr_(lets, base) = expr;
+        let mut reduced = vec![];
+        for let_binding in lets {
+            reduced.extend(self.let_binding(let_binding));
+        }
+        let mut base = self.base_expr(base);
+        if let ANormalBaseExpr::Mul(left, right) = &base {
+            let name = format!("_product{}", self.products);
+            if let Some(lets) = self.multiply(&name, left, right) {
+                self.products += 1;
+                reduced.extend(lets);
+                base = ANormalBaseExpr::Var(name);
+            }
+        }
+        Expr_(reduced, base)
+    }
+
+    fn let_binding(&mut self, let_binding: ANormalLet) -> Vec<ANormalLet> {
+        match let_binding {
+            ANormalLet::BindLet(bind_let) => {
+                let value = self.base_expr(bind_let.value);
+                if let ANormalBaseExpr::Int(n) = value
+                    && n >= 0
+                {
+                    self.consts
+                        .insert(bind_let.name.clone(), (n as u32, bind_let.ty.clone()));
+                }
+                if let ANormalBaseExpr::Mul(left, right) = &value
+                    && let Some(lets) = self.multiply(&bind_let.name, left, right)
+                {
+                    return lets;
+                }
+                vec![ANormalLet::BindLet(BindLet_ { value, ..bind_let })]
+            }
+            ANormalLet::VarLet(var_let) => vec![ANormalLet::VarLet(BindLet_ {
+                value: self.base_expr(var_let.value),
+                ..var_let
+            })],
+            ANormalLet::NoBindLet(no_bind_let) => vec![ANormalLet::NoBindLet(NoBindLet_ {
+                value: self.base_expr(no_bind_let.value),
+            })],
+            ANormalLet::TupleLet(tuple_let) => vec![ANormalLet::TupleLet(TupleLet_ {
+                value: self.base_expr(tuple_let.value),
+                ..tuple_let
+            })],
+        }
+    }
+
+    // The bindings computing `name = left * right` without a multiplier, when one of the
+    // operands is a suitable constant. Both operands have the type of the product.
+    fn multiply(&self, name: &Ident, left: &Ident, right: &Ident) -> Option<Vec<ANormalLet>> {
+        let (x, (c, ty)) = match (self.consts.get(left), self.consts.get(right)) {
+            (None, Some(constant)) => (left, constant),
+            (Some(constant), None) => (right, constant),
+            _ => return None,
+        };
+        let c = *c;
+        // 0 and 1 are folded away
+        if c < 2 || !matches!(ty, Type::I(_)) {
+            return None;
+        }
+        let digits = signed_digits(c);
+        if digits.len() > MAX_TERMS {
+            return None;
+        }
+        let mut lets = vec![];
+        // The highest digit is positive
+        let ((high, _), rest) = digits.split_first().unwrap();
+        let value = match rest.split_last() {
+            None => {
+                let amount = shift_amount(&mut lets, name, ty, *high);
+                ANormalBaseExpr::BinOp(BinOp::Shl, x.clone(), amount)
+            }
+            Some((last, middle)) => {
+                let mut sum = shift(&mut lets, name, ty, x, *high);
+                for (i, digit) in middle.iter().enumerate() {
+                    let partial = format!("{}_sum{}", name, i);
+                    let value = add_term(&mut lets, name, ty, x, sum, *digit);
+                    lets.push(ANormalLet::BindLet(let_(&partial, ty.clone(), value)));
+                    sum = partial;
+                }
+                add_term(&mut lets, name, ty, x, sum, *last)
+            }
+        };
+        lets.push(ANormalLet::BindLet(let_(name, ty.clone(), value)));
+        Some(lets)
+    }
+
+    fn boxed_expr(&mut self, expr: ANormalExpr) -> Box<ANormalExpr> {
+        Box::new(self.expr(expr))
+    }
+
+    fn base_expr(&mut self, expr: ANormalBaseExpr) -> ANormalBaseExpr {
+        match expr {
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                ANormalBaseExpr::Map(arrays, index, params, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Reduce(array, init, acc, param, body) => {
+                ANormalBaseExpr::Reduce(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::Scan(array, init, acc, param, body) => {
+                ANormalBaseExpr::Scan(array, init, acc, param, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::For(counter, start, end, body) => {
+                ANormalBaseExpr::For(counter, start, end, self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::While(cond, body) => {
+                ANormalBaseExpr::While(self.boxed_expr(*cond), self.boxed_expr(*body))
+            }
+            ANormalBaseExpr::If(cond, then_branch, else_branch) => ANormalBaseExpr::If(
+                cond,
+                self.boxed_expr(*then_branch),
+                self.boxed_expr(*else_branch),
+            ),
+            expr => expr,
+        }
+    }
+}
+
+// The nonzero digits of `c` in non-adjacent form, as (position, negative), highest first
+fn signed_digits(c: u32) -> Vec<(u32, bool)> {
+    let mut digits = vec![];
+    let mut rest = c as u64;
+    let mut position = 0;
+    while rest != 0 {
+        if rest & 1 == 1 {
+            // ...11 is better written with a -1 and a carry
+            let negative = rest & 3 == 3;
+            digits.push((position, negative));
+            rest = if negative { rest + 1 } else { rest - 1 };
+        }
+        rest >>= 1;
+        position += 1;
+    }
+    digits.reverse();
+    digits
+}
+
+// Binds the amount of a shift, as wide as the shifted value
+fn shift_amount(lets: &mut Vec<ANormalLet>, name: &str, ty: &Type, shift: u32) -> Ident {
+    let amount = format!("{}_shift{}", name, shift);
+    lets.push(ANormalLet::BindLet(let_(
+        &amount,
+        ty.clone(),
+        ANormalBaseExpr::Int(shift as i32),
+    )));
+    amount
+}
+
+// Binds `x << shift`, or returns `x` itself for no shift
+fn shift(lets: &mut Vec<ANormalLet>, name: &str, ty: &Type, x: &Ident, shift: u32) -> Ident {
+    if shift == 0 {
+        return x.clone();
+    }
+    let amount = shift_amount(lets, name, ty, shift);
+    let shifted = format!("{}_shl{}", name, shift);
+    lets.push(ANormalLet::BindLet(let_(
+        &shifted,
+        ty.clone(),
+        ANormalBaseExpr::BinOp(BinOp::Shl, x.clone(), amount),
+    )));
+    shifted
+}
+
+// `sum + (x << position)`, or `sum - (x << position)` for a negative digit
+fn add_term(
+    lets: &mut Vec<ANormalLet>,
+    name: &str,
+    ty: &Type,
+    x: &Ident,
+    sum: Ident,
+    (position, negative): (u32, bool),
+) -> ANormalBaseExpr {
+    let term = shift(lets, name, ty, x, position);
+    if negative {
+        ANormalBaseExpr::BinOp(BinOp::Sub, sum, term)
+    } else {
+        ANormalBaseExpr::Add(sum, term)
+    }
+}
+
+pub fn reduce_strength(program: ANormalProgram) -> ANormalProgram {
+    program
+        .into_iter()
+        .map(|item| match item {
+            ANormalTopLevel::FunDef(fundef) => {
+                let mut reduction = StrengthReduction {
+                    consts: HashMap::new(),
+                    products: 0,
+                };
+                ANormalTopLevel::FunDef(FunDef_ {
+                    body: reduction.expr(fundef.body),
+                    ..fundef
+                })
+            }
+            item => item,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ANormalBaseExpr::{Add, BinOp as Op, Int, Mul, Var};
+
+    // The bindings replacing `p = x * c`, after the binding of `c`
+    fn reduce(c: i32) -> Vec<ANormalLet> {
+        let lets = vec![
+            ANormalLet::BindLet(let_("c", Type::i32(), Int(c))),
+            ANormalLet::BindLet(let_("p", Type::i32(), Mul("x".into(), "c".into()))),
+        ];
+        let mut reduction = StrengthReduction {
+            consts: HashMap::new(),
+            products: 0,
+        };
+        let Expr_(mut lets, _) = reduction.expr(Expr_(lets, Var("x".to_string())));
+        lets.remove(0);
+        lets
+    }
+
+    fn values(lets: &[ANormalLet]) -> Vec<&ANormalBaseExpr> {
+        lets.iter()
+            .map(|let_binding| match let_binding {
+                ANormalLet::BindLet(bind_let) => &bind_let.value,
+                _ => panic!("expected a binding"),
+            })
+            .collect()
+    }
+
+    // The value of `p` for the given `x`
+    fn evaluate(lets: &[ANormalLet], x: i64) -> i64 {
+        let mut env = HashMap::from([("x".to_string(), x)]);
+        for let_binding in lets {
+            let ANormalLet::BindLet(bind_let) = let_binding else {
+                panic!("expected a binding")
+            };
+            let value = match &bind_let.value {
+                Int(n) => *n as i64,
+                Add(left, right) => env[left] + env[right],
+                Op(BinOp::Sub, left, right) => env[left] - env[right],
+                Op(BinOp::Shl, left, right) => env[left] << env[right],
+                value => panic!("unexpected {:?}", value),
+            };
+            env.insert(bind_let.name.clone(), value);
+        }
+        env["p"]
+    }
+
+    #[test]
+    fn multiplies_by_a_power_of_two_with_a_single_shift() {
+        let lets = reduce(8);
+        let values = values(&lets);
+        assert_eq!(values.len(), 2);
+        assert!(matches!(values[0], Int(3)));
+        assert!(matches!(values[1], Op(BinOp::Shl, x, _) if x == "x"));
+    }
+
+    #[test]
+    fn writes_constants_with_signed_digits() {
+        assert_eq!(signed_digits(7), vec![(3, false), (0, true)]);
+        assert_eq!(signed_digits(27), vec![(5, false), (2, true), (0, true)]);
+        for c in 2..=64 {
+            let digits = signed_digits(c as u32);
+            assert!(digits.windows(2).all(|pair| pair[0].0 > pair[1].0 + 1));
+            let lets = reduce(c);
+            if digits.len() > MAX_TERMS {
+                assert!(matches!(values(&lets)[..], [Mul(..)]), "{}", c);
+            } else {
+                assert_eq!(evaluate(&lets, 5), 5 * c as i64, "{}", c);
+                assert_eq!(evaluate(&lets, 13), 13 * c as i64, "{}", c);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_multiplier_beyond_max_terms() {
+        // 27 = 32 - 4 - 1 and 45 = 64 - 16 - 4 + 1
+        assert!(
+            values(&reduce(27))
+                .iter()
+                .all(|value| !matches!(value, Mul(..)))
+        );
+        assert!(matches!(values(&reduce(45))[..], [Mul(..)]));
+    }
+
+    #[test]
+    fn leaves_negative_constants_and_zero_and_one() {
+        // Constant folding has already removed products by 0 and 1
+        for c in [-8, -3, 0, 1] {
+            assert!(matches!(values(&reduce(c))[..], [Mul(..)]), "{}", c);
+        }
+    }
+}
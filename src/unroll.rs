@@ -0,0 +1,312 @@
+use crate::ast::*;
+use crate::convert::{MAX_TREE_REDUCE_SIZE, is_associative};
+use crate::fusion::count_uses;
+use std::collections::{HashMap, HashSet};
+
+// Copies of a map body for the lanes of an unrolled loop. Every name bound inside the
+// body, and the lambda parameters, get a per-lane suffix so that the copies convert to
+// distinct cells; names from outside the lambda are shared by all lanes.
+struct LaneRenamer {
+    suffix: String,
+    bound: HashSet<Ident>,
+}
+
+impl LaneRenamer {
+    fn bind(&mut self, name: &Ident) -> Ident {
+        self.bound.insert(name.clone());
+        self.rename(name)
+    }
+
+    fn rename(&self, name: &Ident) -> Ident {
+        if self.bound.contains(name) {
+            format!("{}{}", name, self.suffix)
+        } else {
+            name.clone()
+        }
+    }
+
+    fn rename_all(&self, names: &[Ident]) -> Vec<Ident> {
+        names.iter().map(|name| self.rename(name)).collect()
+    }
+
+    fn expr(&mut self, expr: &ANormalExpr) -> ANormalExpr {
+        let Expr_(lets, base) = expr;
+        let lets = lets
+            .iter()
+            .map(|let_binding| self.let_binding(let_binding))
+            .collect();
+        Expr_(lets, self.base_expr(base))
+    }
+
+    fn let_binding(&mut self, let_binding: &ANormalLet) -> ANormalLet {
+        match let_binding {
+            ANormalLet::BindLet(bind_let) => {
+                let value = self.base_expr(&bind_let.value);
+                ANormalLet::BindLet(let_(&self.bind(&bind_let.name), bind_let.ty.clone(), value))
+            }
+            ANormalLet::VarLet(var_let) => {
+                let value = self.base_expr(&var_let.value);
+                ANormalLet::VarLet(let_(&self.bind(&var_let.name), var_let.ty.clone(), value))
+            }
+            ANormalLet::NoBindLet(no_bind_let) => ANormalLet::NoBindLet(NoBindLet_ {
+                value: self.base_expr(&no_bind_let.value),
+            }),
+            ANormalLet::TupleLet(tuple_let) => {
+                let value = self.base_expr(&tuple_let.value);
+                ANormalLet::TupleLet(TupleLet_ {
+                    names: tuple_let.names.iter().map(|name| self.bind(name)).collect(),
+                    ty: tuple_let.ty.clone(),
+                    value,
+                })
+            }
+        }
+    }
+
+    fn base_expr(&mut self, expr: &ANormalBaseExpr) -> ANormalBaseExpr {
+        match expr {
+            ANormalBaseExpr::Int(_)
+            | ANormalBaseExpr::Bool(_)
+            | ANormalBaseExpr::NewArray(..)
+            | ANormalBaseExpr::Range(_) => expr.clone(),
+            ANormalBaseExpr::Var(name) => ANormalBaseExpr::Var(self.rename(name)),
+            ANormalBaseExpr::Add(left, right) => {
+                ANormalBaseExpr::Add(self.rename(left), self.rename(right))
+            }
+            ANormalBaseExpr::Mul(left, right) => {
+                ANormalBaseExpr::Mul(self.rename(left), self.rename(right))
+            }
+            ANormalBaseExpr::BinOp(op, left, right) => {
+                ANormalBaseExpr::BinOp(*op, self.rename(left), self.rename(right))
+            }
+            ANormalBaseExpr::Fill(ty, size, value) => {
+                ANormalBaseExpr::Fill(ty.clone(), *size, self.rename(value))
+            }
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                let arrays = self.rename_all(arrays);
+                let index = index.as_ref().map(|index| self.bind(index));
+                let params = params.iter().map(|param| self.bind(param)).collect();
+                ANormalBaseExpr::Map(arrays, index, params, Box::new(self.expr(body)))
+            }
+            ANormalBaseExpr::Reduce(array, init, acc, param, body) => {
+                let (array, init) = (self.rename(array), self.rename(init));
+                let (acc, param) = (self.bind(acc), self.bind(param));
+                ANormalBaseExpr::Reduce(array, init, acc, param, Box::new(self.expr(body)))
+            }
+            ANormalBaseExpr::Scan(array, init, acc, param, body) => {
+                let (array, init) = (self.rename(array), self.rename(init));
+                let (acc, param) = (self.bind(acc), self.bind(param));
+                ANormalBaseExpr::Scan(array, init, acc, param, Box::new(self.expr(body)))
+            }
+            ANormalBaseExpr::Call(name, args) => {
+                ANormalBaseExpr::Call(name.clone(), self.rename_all(args))
+            }
+            ANormalBaseExpr::ArraySet(array, index, value) => ANormalBaseExpr::ArraySet(
+                self.rename(array),
+                Box::new(self.rename(index)),
+                Box::new(self.rename(value)),
+            ),
+            ANormalBaseExpr::ArrayGet(array, index) => {
+                ANormalBaseExpr::ArrayGet(self.rename(array), self.rename(index))
+            }
+            ANormalBaseExpr::Tuple(items) => ANormalBaseExpr::Tuple(self.rename_all(items)),
+            ANormalBaseExpr::For(counter, start, end, body) => {
+                let counter = self.bind(counter);
+                ANormalBaseExpr::For(counter, *start, *end, Box::new(self.expr(body)))
+            }
+            ANormalBaseExpr::Assign(name, value) => {
+                ANormalBaseExpr::Assign(self.rename(name), self.rename(value))
+            }
+            ANormalBaseExpr::Field(value, field) => {
+                ANormalBaseExpr::Field(self.rename(value), field.clone())
+            }
+            ANormalBaseExpr::Struct(name, fields) => ANormalBaseExpr::Struct(
+                name.clone(),
+                fields
+                    .iter()
+                    .map(|(field, value)| (field.clone(), self.rename(value)))
+                    .collect(),
+            ),
+            ANormalBaseExpr::While(cond, body) => {
+                ANormalBaseExpr::While(Box::new(self.expr(cond)), Box::new(self.expr(body)))
+            }
+            ANormalBaseExpr::If(cond, then_branch, else_branch) => ANormalBaseExpr::If(
+                self.rename(cond),
+                Box::new(self.expr(then_branch)),
+                Box::new(self.expr(else_branch)),
+            ),
+        }
+    }
+}
+
+// Returns the parameters, the index parameter and the body of the copy for `lane`
+pub fn lane_copy(
+    params: &[Ident],
+    index: &Option<Ident>,
+    body: &ANormalExpr,
+    lane: usize,
+) -> (Vec<Ident>, Option<Ident>, ANormalExpr) {
+    let mut renamer = LaneRenamer {
+        suffix: format!("_lane{}", lane),
+        bound: HashSet::new(),
+    };
+    let params = params.iter().map(|param| renamer.bind(param)).collect();
+    let index = index.as_ref().map(|index| renamer.bind(index));
+    let body = renamer.expr(body);
+    (params, index, body)
+}
+
+// Arrays produced by a map outside any lambda and only read by such maps, or by a reduce
+// lowered to a tree under `--tree-reduce`. The maps are unrolled into the same lanes, so
+// the array can be split into one memory per lane that the lanes read and write at the
+// same time, and the tree reads all of them at once; any other use indexes the array
+// element by element.
+pub fn bankable_arrays(body: &ANormalExpr, tree_reduce: bool) -> HashSet<Ident> {
+    let mut reads = BankedReads {
+        tree_reduce,
+        ..Default::default()
+    };
+    reads.expr(body);
+    let mut uses = HashMap::new();
+    count_uses(body, &mut uses);
+    reads
+        .produced
+        .into_iter()
+        .filter(|(name, size)| {
+            let map_reads = reads.map_reads.get(name).copied().unwrap_or(0);
+            let tree_reads = match reads.tree_reads.get(name) {
+                Some(tree_reads) if *size <= MAX_TREE_REDUCE_SIZE => *tree_reads,
+                _ => 0,
+            };
+            uses.get(name) == Some(&(map_reads + tree_reads))
+        })
+        .map(|(name, _)| name)
+        .collect()
+}
+
+#[derive(Default)]
+struct BankedReads {
+    tree_reduce: bool,
+    // Arrays produced by maps, with their size
+    produced: Vec<(Ident, usize)>,
+    map_reads: HashMap<Ident, usize>,
+    // Reads by an associative reduce, which is a tree if the array is small enough
+    tree_reads: HashMap<Ident, usize>,
+}
+
+impl BankedReads {
+    fn expr(&mut self, expr: &ANormalExpr) {
+        let Expr_(lets, base) = expr;
+        for let_binding in lets {
+            match let_binding {
+                ANormalLet::BindLet(bind_let) => {
+                    if let (ANormalBaseExpr::Map(..), Type::Array(_, size)) =
+                        (&bind_let.value, &bind_let.ty)
+                        && *size > 1
+                    {
+                        self.produced.push((bind_let.name.clone(), *size));
+                    }
+                    self.base_expr(&bind_let.value);
+                }
+                ANormalLet::VarLet(bind_let) => self.base_expr(&bind_let.value),
+                ANormalLet::NoBindLet(no_bind_let) => self.base_expr(&no_bind_let.value),
+                ANormalLet::TupleLet(tuple_let) => self.base_expr(&tuple_let.value),
+            }
+        }
+        self.base_expr(base);
+    }
+
+    // Lambda bodies are not visited, as the maps and reduces in them are not unrolled
+    fn base_expr(&mut self, expr: &ANormalBaseExpr) {
+        match expr {
+            ANormalBaseExpr::Map(arrays, ..) => {
+                for array in arrays {
+                    *self.map_reads.entry(array.clone()).or_default() += 1;
+                }
+            }
+            ANormalBaseExpr::Reduce(array, _, acc, param, body)
+                if self.tree_reduce && is_associative(acc, param, body) =>
+            {
+                *self.tree_reads.entry(array.clone()).or_default() += 1;
+            }
+            ANormalBaseExpr::For(_, _, _, body) => self.expr(body),
+            ANormalBaseExpr::While(cond, body) => {
+                self.expr(cond);
+                self.expr(body);
+            }
+            ANormalBaseExpr::If(_, then_branch, else_branch) => {
+                self.expr(then_branch);
+                self.expr(else_branch);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Names from outside a map body that it accesses as memories, assigns, or passes to a
+// call, which the lanes share if they run the body in par
+pub fn outer_state(body: &ANormalExpr) -> HashSet<Ident> {
+    let mut state = StateAccesses::default();
+    state.expr(body);
+    state.accessed.difference(&state.bound).cloned().collect()
+}
+
+#[derive(Default)]
+struct StateAccesses {
+    bound: HashSet<Ident>,
+    accessed: HashSet<Ident>,
+}
+
+impl StateAccesses {
+    fn expr(&mut self, expr: &ANormalExpr) {
+        let Expr_(lets, base) = expr;
+        for let_binding in lets {
+            match let_binding {
+                ANormalLet::BindLet(bind_let) | ANormalLet::VarLet(bind_let) => {
+                    self.bound.insert(bind_let.name.clone());
+                    self.base_expr(&bind_let.value);
+                }
+                ANormalLet::NoBindLet(no_bind_let) => self.base_expr(&no_bind_let.value),
+                ANormalLet::TupleLet(tuple_let) => {
+                    self.bound.extend(tuple_let.names.iter().cloned());
+                    self.base_expr(&tuple_let.value);
+                }
+            }
+        }
+        self.base_expr(base);
+    }
+
+    fn base_expr(&mut self, expr: &ANormalBaseExpr) {
+        match expr {
+            ANormalBaseExpr::ArrayGet(array, _)
+            | ANormalBaseExpr::ArraySet(array, ..)
+            | ANormalBaseExpr::Assign(array, _) => {
+                self.accessed.insert(array.clone());
+            }
+            ANormalBaseExpr::Call(_, args) => self.accessed.extend(args.iter().cloned()),
+            ANormalBaseExpr::Map(arrays, index, params, body) => {
+                self.accessed.extend(arrays.iter().cloned());
+                self.bound.extend(index.iter().chain(params).cloned());
+                self.expr(body);
+            }
+            ANormalBaseExpr::Reduce(array, _, acc, param, body)
+            | ANormalBaseExpr::Scan(array, _, acc, param, body) => {
+                self.accessed.insert(array.clone());
+                self.bound.extend([acc.clone(), param.clone()]);
+                self.expr(body);
+            }
+            ANormalBaseExpr::For(counter, _, _, body) => {
+                self.bound.insert(counter.clone());
+                self.expr(body);
+            }
+            ANormalBaseExpr::While(cond, body) => {
+                self.expr(cond);
+                self.expr(body);
+            }
+            ANormalBaseExpr::If(_, then_branch, else_branch) => {
+                self.expr(then_branch);
+                self.expr(else_branch);
+            }
+            _ => {}
+        }
+    }
+}